@@ -0,0 +1,200 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, ExprPath, Fields, GenericArgument, LitStr, PathArguments, Token, Type};
+
+// #[derive(FromRow)] generates `try_from_row(&Row) -> CoreResult<Self>` plus a mysql_common
+// FromRow impl whose from_row_opt never panics. It expands to the try_extract_* macros, so it
+// can only be used inside the my_own_tests crate.
+//
+// Struct attributes:
+//   #[from_row(table = "accounts")]   table name reported in errors (defaults to the struct name)
+// Field attributes:
+//   #[from_row(rename = "ID")]        column name (defaults to the field name)
+//   #[from_row(decimal)]              lenient Decimal decoding like extract_decimal!, but values that
+//                                     don't parse are errors instead of zero
+//   #[from_row(bool)]                 tinyint to bool, same as extract_bool!
+//   #[from_row(char)]                 first char of a string column, same as extract_char_opt!
+//   #[from_row(json)]                 string column deserialized with serde_json
+//   #[from_row(default)]              Default::default() when the column is NULL
+//   #[from_row(skip)]                 not a column, always Default::default()
+//   #[from_row(skip = "path")]        not a column, built by calling path()
+#[proc_macro_derive(FromRow, attributes(from_row))]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct FieldOptions {
+    rename: Option<String>,
+    decimal: bool,
+    bool: bool,
+    char: bool,
+    json: bool,
+    default: bool,
+    skip: bool,
+    skip_with: Option<ExprPath>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let table = parse_table(&input.attrs)?.unwrap_or_else(|| name.to_string());
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(name, "FromRow can only be derived for structs with named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(name, "FromRow can only be derived for structs")),
+    };
+
+    let mut initializers = Vec::with_capacity(fields.len());
+    for field in fields {
+        let ident = field.ident.as_ref().expect("named field");
+        let options = parse_field_options(&field.attrs)?;
+        let column = options.rename.clone().unwrap_or_else(|| ident.to_string());
+        let value = field_value(&field.ty, &options, &column, &table)?;
+        initializers.push(quote! { #ident: #value });
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            pub fn try_from_row(row: &mysql_common::row::Row) -> crate::utils::CoreResult<Self> {
+                Ok(#name {
+                    #(#initializers,)*
+                })
+            }
+        }
+
+        impl #impl_generics mysql_common::row::convert::FromRow for #name #ty_generics #where_clause {
+            fn from_row(row: mysql_common::row::Row) -> Self where Self: Sized {
+                Self::try_from_row(&row).unwrap_or_else(|e| panic!("{}", e))
+            }
+
+            fn from_row_opt(row: mysql_common::row::Row) -> Result<Self, mysql_common::row::convert::FromRowError> where Self: Sized {
+                Self::try_from_row(&row).map_err(|_| mysql_common::row::convert::FromRowError(row))
+            }
+        }
+    })
+}
+
+fn field_value(ty: &Type, options: &FieldOptions, column: &str, table: &str) -> syn::Result<TokenStream2> {
+    if options.skip {
+        return Ok(match &options.skip_with {
+            Some(path) => quote! { #path() },
+            None => quote! { ::std::default::Default::default() },
+        });
+    }
+
+    let kinds = [options.decimal, options.bool, options.char, options.json].iter().filter(|x| **x).count();
+    if kinds > 1 {
+        return Err(syn::Error::new_spanned(ty, "only one of decimal, bool, char or json can be used per field"));
+    }
+
+    let inner = option_inner(ty);
+    let nullable = inner.is_some();
+    let value_ty = inner.unwrap_or(ty);
+
+    // Reads the column as CoreResult<Option<T>> when the field is nullable or defaulted
+    let read_opt = if options.decimal {
+        quote! { crate::try_extract_decimal_opt!(row, #column, #table) }
+    } else if options.bool {
+        quote! { crate::try_extract_bool_opt!(row, #column, #table) }
+    } else if options.char {
+        quote! { crate::try_extract_char_opt!(row, #column, #table) }
+    } else if options.json {
+        quote! {
+            crate::try_extract_value!(row, #column, #table, Option<String>)
+                .and_then(|json| crate::datatypes::parse_json_field::<#value_ty>(json, #column, #table))
+        }
+    } else {
+        quote! { crate::try_extract_value!(row, #column, #table, Option<#value_ty>) }
+    };
+
+    if nullable {
+        return Ok(quote! { #read_opt? });
+    }
+    if options.default {
+        return Ok(quote! { #read_opt?.unwrap_or_default() });
+    }
+
+    Ok(if options.decimal {
+        quote! { crate::try_extract_decimal!(row, #column, #table)? }
+    } else if options.bool {
+        quote! { crate::try_extract_bool!(row, #column, #table)? }
+    } else if options.char || options.json {
+        quote! {
+            #read_opt?.ok_or_else(|| crate::datatypes::sql_field_error(
+                crate::datatypes::expect_sql_field(#column, #table),
+                #table
+            ))?
+        }
+    } else {
+        quote! { crate::try_extract_value!(row, #column, #table, #ty)? }
+    })
+}
+
+fn parse_table(attrs: &[Attribute]) -> syn::Result<Option<String>> {
+    let mut table = None;
+    for attr in attrs.iter().filter(|a| a.path().is_ident("from_row")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported from_row struct attribute"))
+            }
+        })?;
+    }
+    Ok(table)
+}
+
+fn parse_field_options(attrs: &[Attribute]) -> syn::Result<FieldOptions> {
+    let mut options = FieldOptions::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("from_row")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                options.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("decimal") {
+                options.decimal = true;
+            } else if meta.path.is_ident("bool") {
+                options.bool = true;
+            } else if meta.path.is_ident("char") {
+                options.char = true;
+            } else if meta.path.is_ident("json") {
+                options.json = true;
+            } else if meta.path.is_ident("default") {
+                options.default = true;
+            } else if meta.path.is_ident("skip") {
+                options.skip = true;
+                if meta.input.peek(Token![=]) {
+                    options.skip_with = Some(meta.value()?.parse::<LitStr>()?.parse::<ExprPath>()?);
+                }
+            } else {
+                return Err(meta.error("unsupported from_row field attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(options)
+}
+
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else { return None };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(GenericArgument::Type(inner)) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
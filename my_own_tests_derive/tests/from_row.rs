@@ -0,0 +1,7 @@
+// Pass cases are built and run against src/data/macros.rs and the stand-ins in tests/ui/support.rs
+#[test]
+fn from_row() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/pass/*.rs");
+    t.compile_fail("tests/ui/fail/*.rs");
+}
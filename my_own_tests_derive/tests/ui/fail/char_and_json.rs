@@ -0,0 +1,10 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+struct Parameter {
+    #[from_row(char)]
+    #[from_row(json)]
+    kind: char,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+struct Balance {
+    #[from_row(decimal, bool)]
+    amount: u64,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+enum Status {
+    Active,
+}
+
+fn main() {}
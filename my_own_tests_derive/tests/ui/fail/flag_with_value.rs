@@ -0,0 +1,9 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+struct Wallet {
+    #[from_row(default = "zero")]
+    charge_priority: i16,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+struct Account {
+    #[from_row(rename = 5)]
+    number: u64,
+}
+
+fn main() {}
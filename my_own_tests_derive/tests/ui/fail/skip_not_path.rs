@@ -0,0 +1,9 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+struct Account {
+    #[from_row(skip = "not a path")]
+    wallets: Vec<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+struct Account(u64);
+
+fn main() {}
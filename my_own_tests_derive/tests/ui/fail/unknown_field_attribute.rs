@@ -0,0 +1,9 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+struct Account {
+    #[from_row(nullable)]
+    number: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use my_own_tests_derive::FromRow;
+
+#[derive(FromRow)]
+#[from_row(name = "accounts")]
+struct Account {
+    number: u64,
+}
+
+fn main() {}
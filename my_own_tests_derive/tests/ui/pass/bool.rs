@@ -0,0 +1,29 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+#[derive(FromRow)]
+struct Block {
+    #[from_row(bool)]
+    denies_purchase: bool,
+    #[from_row(bool)]
+    denies_cash: Option<bool>,
+}
+
+fn main() {
+    let block = Block::try_from_row(&row(&[("denies_purchase", Value::Int(1)), ("denies_cash", Value::NULL)])).unwrap();
+    assert!(block.denies_purchase);
+    assert_eq!(block.denies_cash, None);
+
+    let block = Block::try_from_row(&row(&[("denies_purchase", Value::Int(0)), ("denies_cash", Value::Int(2))])).unwrap();
+    assert!(!block.denies_purchase);
+    assert_eq!(block.denies_cash, Some(true));
+
+    assert!(Block::try_from_row(&row(&[("denies_purchase", Value::NULL), ("denies_cash", Value::NULL)])).is_err());
+}
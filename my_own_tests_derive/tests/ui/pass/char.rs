@@ -0,0 +1,31 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+#[derive(FromRow)]
+struct Parameter {
+    #[from_row(char)]
+    kind: char,
+    #[from_row(char)]
+    flag: Option<char>,
+}
+
+fn main() {
+    let parameter = Parameter::try_from_row(&row(&[("kind", Value::Bytes(b"Decimal".to_vec())), ("flag", Value::NULL)])).unwrap();
+    assert_eq!(parameter.kind, 'D');
+    assert_eq!(parameter.flag, None);
+
+    let parameter = Parameter::try_from_row(&row(&[("kind", Value::Bytes(b"R".to_vec())), ("flag", Value::Bytes(b"Y".to_vec()))])).unwrap();
+    assert_eq!(parameter.kind, 'R');
+    assert_eq!(parameter.flag, Some('Y'));
+
+    // A required char can't come from NULL or an empty string
+    assert!(Parameter::try_from_row(&row(&[("kind", Value::NULL), ("flag", Value::NULL)])).is_err());
+    assert!(Parameter::try_from_row(&row(&[("kind", Value::Bytes(Vec::new())), ("flag", Value::NULL)])).is_err());
+}
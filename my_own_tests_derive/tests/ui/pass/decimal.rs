@@ -0,0 +1,48 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+use mysql_common::rust_decimal::Decimal;
+
+#[derive(FromRow)]
+struct Balance {
+    #[from_row(decimal)]
+    amount: Decimal,
+    #[from_row(decimal)]
+    limit: Option<Decimal>,
+    #[from_row(decimal, default)]
+    rate: Decimal,
+}
+
+fn main() {
+    let balance = Balance::try_from_row(&row(&[
+        ("amount", Value::Bytes(b"12.50".to_vec())),
+        ("limit", Value::NULL),
+        ("rate", Value::NULL),
+    ])).unwrap();
+    assert_eq!(balance.amount, Decimal::new(1250, 2));
+    assert_eq!(balance.limit, None);
+    assert_eq!(balance.rate, Decimal::ZERO);
+
+    // Values the Decimal FromValue impl rejects are parsed from their SQL text
+    let balance = Balance::try_from_row(&row(&[
+        ("amount", Value::Double(1.5)),
+        ("limit", Value::Bytes(b"100".to_vec())),
+        ("rate", Value::Bytes(b"0.025".to_vec())),
+    ])).unwrap();
+    assert_eq!(balance.amount, Decimal::new(15, 1));
+    assert_eq!(balance.limit, Some(Decimal::new(100, 0)));
+    assert_eq!(balance.rate, Decimal::new(25, 3));
+
+    assert!(Balance::try_from_row(&row(&[
+        ("amount", Value::Bytes(b"abc".to_vec())),
+        ("limit", Value::NULL),
+        ("rate", Value::NULL),
+    ])).is_err());
+}
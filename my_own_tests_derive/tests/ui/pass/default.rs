@@ -0,0 +1,30 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+#[derive(FromRow)]
+struct Wallet {
+    #[from_row(default)]
+    charge_priority: i16,
+    #[from_row(default, rename = "label")]
+    name: String,
+}
+
+fn main() {
+    let wallet = Wallet::try_from_row(&row(&[("charge_priority", Value::NULL), ("label", Value::NULL)])).unwrap();
+    assert_eq!(wallet.charge_priority, 0);
+    assert_eq!(wallet.name, "");
+
+    let wallet = Wallet::try_from_row(&row(&[("charge_priority", Value::Int(2)), ("label", Value::Bytes(b"main".to_vec()))])).unwrap();
+    assert_eq!(wallet.charge_priority, 2);
+    assert_eq!(wallet.name, "main");
+
+    // Defaults cover NULL, not a missing column
+    assert!(Wallet::try_from_row(&row(&[("charge_priority", Value::NULL)])).is_err());
+}
@@ -0,0 +1,36 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+use serde::Deserialize;
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct Parameters {
+    max: u32,
+}
+
+#[derive(FromRow)]
+struct Rule {
+    #[from_row(json)]
+    parameters: Parameters,
+    #[from_row(json)]
+    extra: Option<Vec<String>>,
+}
+
+fn main() {
+    let rule = Rule::try_from_row(&row(&[("parameters", Value::Bytes(br#"{"max": 3}"#.to_vec())), ("extra", Value::NULL)])).unwrap();
+    assert_eq!(rule.parameters, Parameters { max: 3 });
+    assert_eq!(rule.extra, None);
+
+    let rule = Rule::try_from_row(&row(&[("parameters", Value::Bytes(br#"{"max": 1}"#.to_vec())), ("extra", Value::Bytes(br#"["a"]"#.to_vec()))])).unwrap();
+    assert_eq!(rule.extra, Some(vec!["a".to_string()]));
+
+    assert!(Rule::try_from_row(&row(&[("parameters", Value::NULL), ("extra", Value::NULL)])).is_err());
+    assert!(Rule::try_from_row(&row(&[("parameters", Value::Bytes(b"{".to_vec())), ("extra", Value::NULL)])).is_err());
+}
@@ -0,0 +1,25 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+#[derive(FromRow)]
+struct Account {
+    #[from_row(rename = "ID")]
+    id: u64,
+    number: u64,
+}
+
+fn main() {
+    let account = Account::try_from_row(&row(&[("ID", Value::UInt(7)), ("number", Value::UInt(1001))])).unwrap();
+    assert_eq!(account.id, 7);
+    assert_eq!(account.number, 1001);
+
+    // The field name is not looked up once renamed
+    assert!(Account::try_from_row(&row(&[("id", Value::UInt(7)), ("number", Value::UInt(1001))])).is_err());
+}
@@ -0,0 +1,32 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+use std::collections::HashMap;
+
+#[derive(FromRow)]
+struct Account {
+    number: u64,
+    #[from_row(skip)]
+    parameters: Option<Vec<u32>>,
+    #[from_row(skip = "new_wallets")]
+    wallets: HashMap<u32, u32>,
+}
+
+fn new_wallets() -> HashMap<u32, u32> {
+    HashMap::with_capacity(12)
+}
+
+fn main() {
+    let account = Account::try_from_row(&row(&[("number", Value::UInt(1))])).unwrap();
+    assert_eq!(account.number, 1);
+    assert_eq!(account.parameters, None);
+    assert!(account.wallets.is_empty());
+    assert!(account.wallets.capacity() >= 12);
+}
@@ -0,0 +1,34 @@
+#[path = "../../../../src/data/macros.rs"]
+#[macro_use]
+mod macros;
+#[path = "../support.rs"]
+mod support;
+
+use my_own_tests_derive::FromRow;
+use mysql_common::value::Value;
+use support::{datatypes, row, utils};
+
+use mysql_common::row::convert::FromRow;
+
+#[derive(Debug, FromRow)]
+#[from_row(table = "accounts")]
+struct Account {
+    number: u64,
+}
+
+#[derive(Debug, FromRow)]
+struct Unnamed {
+    number: u64,
+}
+
+fn main() {
+    let error = Account::try_from_row(&row(&[("other", Value::UInt(1))])).unwrap_err();
+    assert!(error.contains("Expected field number AT accounts"), "{}", error);
+
+    let error = Unnamed::try_from_row(&row(&[("other", Value::UInt(1))])).unwrap_err();
+    assert!(error.contains("AT Unnamed"), "{}", error);
+
+    // from_row_opt hands the row back instead of panicking
+    assert!(Account::from_row_opt(row(&[("number", Value::Bytes(b"x".to_vec()))])).is_err());
+    assert_eq!(Account::from_row(row(&[("number", Value::UInt(3))])).number, 3);
+}
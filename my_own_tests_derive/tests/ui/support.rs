@@ -0,0 +1,40 @@
+// Stand-ins for the my_own_tests items the FromRow expansion and the try_extract_* macros use
+use std::sync::Arc;
+use mysql_common::constants::ColumnType;
+use mysql_common::packets::Column;
+use mysql_common::row::{new_row, Row};
+use mysql_common::value::Value;
+
+pub mod utils {
+    pub type CoreError = String;
+    pub type CoreResult<T> = Result<T, CoreError>;
+}
+
+pub mod datatypes {
+    use std::fmt::Display;
+    use serde::de::DeserializeOwned;
+    use super::utils::{CoreError, CoreResult};
+
+    pub fn expect_sql_field(field_name: &'static str, struct_name: &'static str) -> String {
+        format!("Expected field {} AT {}", field_name, struct_name)
+    }
+
+    pub fn sql_field_error<T: Display>(detail: T, struct_name: &'static str) -> CoreError {
+        format!("{} ({})", detail, struct_name)
+    }
+
+    pub fn parse_json_field<T: DeserializeOwned>(value: Option<String>, field_name: &'static str, struct_name: &'static str) -> CoreResult<Option<T>> {
+        value
+            .map(|json| serde_json::from_str(&json))
+            .transpose()
+            .map_err(|e| format!("{}: parsing {} from {}", e, field_name, struct_name))
+    }
+}
+
+pub fn row(columns: &[(&str, Value)]) -> Row {
+    let names: Vec<Column> = columns.iter()
+        .map(|(name, _)| Column::new(ColumnType::MYSQL_TYPE_VAR_STRING).with_name(name.as_bytes()))
+        .collect();
+    let values = columns.iter().map(|(_, value)| value.clone()).collect();
+    new_row(values, Arc::from(names))
+}
@@ -0,0 +1,5 @@
+-- Schema used by billing::charges. withdrawal_coefficient is the share of credit_amount
+-- available as cash (billing::availability), cash advance interest has its own rate
+
+ALTER TABLE accounts
+    ADD COLUMN cash_advance_coefficient FLOAT NOT NULL DEFAULT 0 AFTER withdrawal_coefficient;
@@ -0,0 +1,15 @@
+-- Schema used by authorization::idempotency
+
+-- Final decisions per transmission key (fields 32, 11, 7 and 41, missing ones stored as ''),
+-- replayed to retransmissions inside the configured window. Expired rows of a reused key are
+-- overwritten in place
+CREATE TABLE authorization_idempotency (
+    acquirer_id VARCHAR(11) NOT NULL,
+    stan CHAR(6) NOT NULL,
+    transmission_date_time CHAR(10) NOT NULL,
+    terminal_id VARCHAR(8) NOT NULL,
+    decision JSON NOT NULL,
+    created_at DATETIME NOT NULL,
+    PRIMARY KEY (acquirer_id, stan, transmission_date_time, terminal_id),
+    KEY authorization_idempotency_created_at (created_at)
+);
@@ -0,0 +1,23 @@
+-- Schema used by batch::ledger
+
+-- run_type: charges, statements. status: running, completed, failed, interrupted
+CREATE TABLE batch_runs (
+    ID BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+    run_type VARCHAR(32) NOT NULL,
+    business_date DATE NOT NULL,
+    products_ID SMALLINT UNSIGNED NOT NULL,
+    shard_index BIGINT UNSIGNED NOT NULL,
+    shard_count BIGINT UNSIGNED NOT NULL,
+    last_accounts_ID INT UNSIGNED NOT NULL DEFAULT 0,
+    status VARCHAR(16) NOT NULL,
+    PRIMARY KEY (ID),
+    UNIQUE KEY batch_runs_shard (run_type, business_date, products_ID, shard_index, shard_count)
+);
+
+-- One row once every shard of a date completed
+CREATE TABLE batch_run_completions (
+    run_type VARCHAR(32) NOT NULL,
+    business_date DATE NOT NULL,
+    completed_at DATETIME NOT NULL,
+    PRIMARY KEY (run_type, business_date)
+);
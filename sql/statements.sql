@@ -0,0 +1,48 @@
+-- Schema used by billing::statements. account_statements already exists with
+-- accounts_id and balances_date; the rest is added here.
+
+ALTER TABLE account_statements
+    ADD COLUMN ID BIGINT UNSIGNED NOT NULL AUTO_INCREMENT FIRST,
+    ADD PRIMARY KEY (ID),
+    ADD COLUMN cycle_open DATE NOT NULL,
+    ADD COLUMN opening_balance DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN purchases DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN cash_advances DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN fees DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN payments DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN refunds DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN charges DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN closing_balance DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN minimum_payment DECIMAL(20, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN payment_due DATE NOT NULL,
+    -- One statement per account and cycle close, persist_statement relies on it
+    ADD UNIQUE KEY account_statements_account_date (accounts_id, balances_date);
+
+-- line_kind: purchase, cash_advance, payment, refund, fee, revolving_interest,
+-- purchase_interest, cash_advance_interest. transactions_ID is NULL for interest lines
+CREATE TABLE account_statement_lines (
+    ID BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+    account_statements_ID BIGINT UNSIGNED NOT NULL,
+    line_kind VARCHAR(32) NOT NULL,
+    transactions_ID BIGINT UNSIGNED NULL,
+    posted_date DATE NOT NULL,
+    -- Signed, positive increases the balance owed
+    amount DECIMAL(20, 2) NOT NULL,
+    PRIMARY KEY (ID),
+    KEY account_statement_lines_statement (account_statements_ID),
+    CONSTRAINT account_statement_lines_statement_fk
+        FOREIGN KEY (account_statements_ID) REFERENCES account_statements (ID)
+);
+
+-- kind: purchase, cash_advance, payment, refund, fee. amount is always positive,
+-- the sign comes from the kind
+CREATE TABLE account_transactions (
+    ID BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+    accounts_ID INT UNSIGNED NOT NULL,
+    wallets_ID INT UNSIGNED NOT NULL,
+    posted_date DATE NOT NULL,
+    kind VARCHAR(32) NOT NULL,
+    amount DECIMAL(20, 2) NOT NULL,
+    PRIMARY KEY (ID),
+    KEY account_transactions_account_date (accounts_ID, posted_date)
+);
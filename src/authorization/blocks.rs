@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use mysql_async::Conn;
+use mysql_async::prelude::Queryable;
+use mysql_common::row::Row;
+use my_own_tests_derive::FromRow;
+use serde::Serialize;
+use crate::datatypes::response_codes::{ResponseCategory, ResponseCodes};
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::BlockIdType;
+use crate::utils::{CoreError, CoreResult};
+
+// Accounts with blocks_ID 0 are not blocked, the catalog doesn't need a row for it
+pub const NO_BLOCK: BlockIdType = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Operation {
+    Purchase,
+    Cash,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Purchase => "purchase",
+            Operation::Cash => "cash",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "purchase" => Some(Operation::Purchase),
+            "cash" => Some(Operation::Cash),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DeniedOperations {
+    pub purchase: bool,
+    pub cash: bool,
+    pub online: bool,
+}
+
+impl DeniedOperations {
+    pub fn all() -> Self {
+        DeniedOperations { purchase: true, cash: true, online: true }
+    }
+
+    // Online only narrows purchases/cash that would otherwise be allowed, e.g. a block denying
+    // online lets card-present purchases through
+    pub fn denies(&self, operation: Operation, online: bool) -> bool {
+        let by_operation = match operation {
+            Operation::Purchase => self.purchase,
+            Operation::Cash => self.cash,
+        };
+        by_operation || (online && self.online)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Block {
+    pub id: BlockIdType,
+    pub description: String,
+    pub denied: DeniedOperations,
+    pub response_code: ResponseCodes,
+}
+
+impl Block {
+    // None when the operation is allowed, otherwise the response code to decline with
+    pub fn check(&self, operation: Operation, online: bool) -> Option<ResponseCodes> {
+        if self.denied.denies(operation, online) { Some(self.response_code) } else { None }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "blocks")]
+struct BlockRow {
+    #[from_row(rename = "ID")]
+    id: BlockIdType,
+    #[from_row(default)]
+    description: String,
+    #[from_row(bool)]
+    denies_purchase: bool,
+    #[from_row(bool)]
+    denies_cash: bool,
+    #[from_row(bool)]
+    denies_online: bool,
+    #[from_row(bool)]
+    denies_all: bool,
+    response_code: u8,
+}
+
+impl BlockRow {
+    fn into_block(self) -> CoreResult<Block> {
+        let response_code = ResponseCodes::from_code(self.response_code)
+            .ok_or_else(|| CoreError::system_error(
+                format!("Block {} has unknown response code {}", self.id, self.response_code),
+                "authorization::blocks::BlockRow::into_block",
+                SystemErrorCodes::UnknownBlocksId(2)
+            ))?;
+        // An approval code would turn the block's decline into an approved response
+        if response_code.category() == ResponseCategory::Approved {
+            return Err(CoreError::system_error(
+                format!("Block {} declines with approval code {}", self.id, response_code),
+                "authorization::blocks::BlockRow::into_block",
+                SystemErrorCodes::UnknownBlocksId(3)
+            ));
+        }
+        let denied = if self.denies_all {
+            DeniedOperations::all()
+        } else {
+            DeniedOperations { purchase: self.denies_purchase, cash: self.denies_cash, online: self.denies_online }
+        };
+        Ok(Block { id: self.id, description: self.description, denied, response_code })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockCatalog {
+    blocks: HashMap<BlockIdType, Block>,
+}
+
+impl BlockCatalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, block: Block) {
+        self.blocks.insert(block.id, block);
+    }
+
+    pub fn get(&self, blocks_id: BlockIdType) -> CoreResult<&Block> {
+        self.blocks.get(&blocks_id)
+            .ok_or_else(|| CoreError::system_error(
+                format!("Unknown blocks_id {}", blocks_id),
+                "authorization::blocks::BlockCatalog::get",
+                SystemErrorCodes::UnknownBlocksId(1)
+            ))
+    }
+
+    // None when the operation is allowed for the block
+    pub fn check(&self, blocks_id: BlockIdType, operation: Operation, online: bool) -> CoreResult<Option<ResponseCodes>> {
+        if blocks_id == NO_BLOCK && !self.blocks.contains_key(&NO_BLOCK) {
+            return Ok(None);
+        }
+        Ok(self.get(blocks_id)?.check(operation, online))
+    }
+
+    pub fn check_account(&self, account: &Account, operation: Operation, online: bool) -> CoreResult<Option<ResponseCodes>> {
+        self.check(account.blocks_id(), operation, online)
+    }
+
+    pub async fn load_from_db(conn: &mut Conn) -> CoreResult<Self> {
+        let rows = conn.query::<Row, _>(
+            "SELECT ID, description, denies_purchase, denies_cash, denies_online, denies_all, response_code FROM blocks"
+        ).await.map_err(|e| CoreError::system_error(e, "authorization::blocks::BlockCatalog::load_from_db", SystemErrorCodes::DbQuery(17)))?;
+
+        let mut catalog = Self::new();
+        for row in rows.iter() {
+            catalog.insert(BlockRow::try_from_row(row)?.into_block()?);
+        }
+        Ok(catalog)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: BlockIdType, denies: (bool, bool, bool, bool), response_code: u8) -> BlockRow {
+        let (denies_purchase, denies_cash, denies_online, denies_all) = denies;
+        BlockRow { id, description: String::new(), denies_purchase, denies_cash, denies_online, denies_all, response_code }
+    }
+
+    #[test]
+    fn denied_operations_table() {
+        let cash_only = DeniedOperations { purchase: false, cash: true, online: false };
+        let online_only = DeniedOperations { purchase: false, cash: false, online: true };
+        // (denied, operation, online, expected)
+        let cases = [
+            (cash_only, Operation::Cash, false, true),
+            (cash_only, Operation::Purchase, false, false),
+            (cash_only, Operation::Purchase, true, false),
+            (online_only, Operation::Purchase, false, false),
+            (online_only, Operation::Purchase, true, true),
+            (online_only, Operation::Cash, true, true),
+            (DeniedOperations::default(), Operation::Cash, true, false),
+            (DeniedOperations::all(), Operation::Purchase, false, true),
+        ];
+        for (denied, operation, online, expected) in cases {
+            assert_eq!(denied.denies(operation, online), expected, "{:?} {:?} online={}", denied, operation, online);
+        }
+    }
+
+    #[test]
+    fn denies_all_overrides_the_individual_flags() {
+        let block = row(7, (false, false, false, true), ResponseCodes::StolenCard as u8).into_block().unwrap();
+        assert_eq!(block.denied, DeniedOperations::all());
+        assert_eq!(block.check(Operation::Purchase, false), Some(ResponseCodes::StolenCard));
+
+        let block = row(8, (true, false, false, false), ResponseCodes::RestrictedCard as u8).into_block().unwrap();
+        assert_eq!(block.check(Operation::Purchase, false), Some(ResponseCodes::RestrictedCard));
+        assert_eq!(block.check(Operation::Cash, true), None);
+    }
+
+    #[test]
+    fn block_rows_need_a_known_decline_code() {
+        let error = row(7, (true, true, true, true), 2).into_block().unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::UnknownBlocksId(2));
+
+        for code in [ResponseCodes::Approved, ResponseCodes::PartialApproval, ResponseCodes::NotDeclined] {
+            let error = row(7, (true, true, true, true), code as u8).into_block().unwrap_err();
+            assert_eq!(error.system_error, SystemErrorCodes::UnknownBlocksId(3), "{}", code);
+        }
+    }
+
+    #[test]
+    fn catalog_check_handles_no_block_and_unknown_ids() {
+        let mut catalog = BlockCatalog::new();
+        assert_eq!(catalog.check(NO_BLOCK, Operation::Cash, true).unwrap(), None);
+        assert_eq!(catalog.check(3, Operation::Cash, true).unwrap_err().system_error, SystemErrorCodes::UnknownBlocksId(1));
+
+        // A catalog row for NO_BLOCK is honoured
+        catalog.insert(Block { id: NO_BLOCK, description: String::new(), denied: DeniedOperations::all(), response_code: ResponseCodes::DoNotHonor });
+        catalog.insert(row(3, (false, true, false, false), ResponseCodes::RestrictedCard as u8).into_block().unwrap());
+        assert_eq!(catalog.check(NO_BLOCK, Operation::Purchase, false).unwrap(), Some(ResponseCodes::DoNotHonor));
+        assert_eq!(catalog.check(3, Operation::Cash, false).unwrap(), Some(ResponseCodes::RestrictedCard));
+        assert_eq!(catalog.check(3, Operation::Purchase, false).unwrap(), None);
+    }
+}
@@ -0,0 +1,492 @@
+use mysql_common::chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use mysql_common::rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use crate::authorization::blocks::{BlockCatalog, Operation};
+use crate::authorization::fraud::{FraudEngine, FraudOutcome, FraudRuleIdType, FraudTransaction, MccType};
+use crate::authorization::idempotency::{IdempotencyConfig, IdempotencyStore, IdempotentDecision, TransmissionKey};
+use crate::billing::waterfall::{wallets_by_priority, CurrencyConverter};
+use crate::data::repository::{AccountRepository, BalanceRepository, IdempotencyRepository};
+use crate::datatypes::response_codes::ResponseCodes;
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, BlockIdType, CurrenciesIdType};
+use crate::iso8583::requests::{IsoRequest, MessageClass};
+use crate::utils::{CoreError, CoreResult};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthorizationRequest {
+    pub accounts_id: AccountIdType,
+    pub amount: Decimal,
+    pub currencies_id: CurrenciesIdType,
+    pub operation: Operation,
+    pub online: bool,
+    pub mcc: MccType,
+    pub country: String,
+    pub local_datetime: NaiveDateTime,
+    // Any day of the card's expiry month, the card is valid through the end of that month
+    pub card_expiry: Option<NaiveDate>,
+    pub partial_approval_allowed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DecisionReason {
+    InvalidAmount,
+    AccountNotFound,
+    Blocked(BlockIdType),
+    CardExpired,
+    CurrencyNotHeld(CurrenciesIdType),
+    CashCountExceeded { count: u32, limit: u32 },
+    FraudDeclined,
+    FraudReferred,
+    // Amounts in the request currency
+    InsufficientCredit { available: Decimal },
+    CashLimitExceeded { available: Decimal },
+    PartiallyApproved { available: Decimal },
+    SystemError { error_code: SystemErrorCodes, detail: String },
+    DuplicateTransmission,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizationDecision {
+    pub response_code: ResponseCodes,
+    // In the request currency, zero unless approved or partially approved
+    pub approved_amount: Decimal,
+    pub currencies_id: CurrenciesIdType,
+    pub reasons: Vec<DecisionReason>,
+    pub fraud_rules: Vec<FraudRuleIdType>,
+}
+
+impl AuthorizationDecision {
+    pub fn is_approved(&self) -> bool {
+        matches!(self.response_code, ResponseCodes::Approved | ResponseCodes::PartialApproval)
+    }
+
+    fn declined(request: &AuthorizationRequest, response_code: ResponseCodes, reason: DecisionReason) -> Self {
+        AuthorizationDecision {
+            response_code,
+            approved_amount: Decimal::ZERO,
+            currencies_id: request.currencies_id,
+            reasons: vec![reason],
+            fraud_rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AuthorizationLimits {
+    // Cash withdrawals allowed per local calendar day, None for no limit
+    pub max_daily_cash_withdrawals: Option<u32>,
+    // How far back authorizations are loaded for velocity checks and fraud rules. Extended to the
+    // longest Velocity window of the account's fraud group when that is longer
+    pub history_minutes: i64,
+}
+
+impl Default for AuthorizationLimits {
+    fn default() -> Self {
+        AuthorizationLimits { max_daily_cash_withdrawals: None, history_minutes: 24 * 60 }
+    }
+}
+
+// Checks run in order and the first decline wins: account, block, expiry, wallet currency,
+// velocity, fraud group and finally available credit, so partial approvals only happen for
+// otherwise acceptable transactions. Credit is evaluated in the currency of the account's
+// primary wallet (lowest charge_priority), the currency credit_amount is expressed in
+pub struct Authorizer<R, C> {
+    repository: R,
+    blocks: BlockCatalog,
+    fraud: FraudEngine,
+    converter: C,
+    limits: AuthorizationLimits,
+    idempotency: IdempotencyStore,
+    // Network whose response code overrides apply to system errors, see response_mapping
+    network: Option<String>,
+}
+
+impl<R, C> Authorizer<R, C>
+where
+    R: AccountRepository + BalanceRepository + IdempotencyRepository,
+    C: CurrencyConverter,
+{
+    pub fn new(repository: R, blocks: BlockCatalog, fraud: FraudEngine, converter: C, limits: AuthorizationLimits) -> Self {
+        let idempotency = IdempotencyStore::new(&IdempotencyConfig::default());
+        Authorizer { repository, blocks, fraud, converter, limits, idempotency, network: None }
+    }
+
+    pub fn with_idempotency(mut self, config: &IdempotencyConfig) -> Self {
+        self.idempotency = IdempotencyStore::new(config);
+        self
+    }
+
+    pub fn with_network(mut self, network: &str) -> Self {
+        self.network = Some(network.to_string());
+        self
+    }
+
+    // Entry point for network requests: retransmissions of the same key inside the window get
+    // the first decision back instead of being authorized again
+    pub async fn authorize_transmission(&self, key: &TransmissionKey, request: &AuthorizationRequest, now: NaiveDateTime) -> CoreResult<IdempotentDecision> {
+        self.idempotency.authorize_once(&self.repository, key, now, || self.authorize(request)).await
+    }
+
+    // The PAN to account lookup and the currency's decimals are left to the caller. Reversals
+    // release an earlier authorization and must not be approved as a new debit, they're refused
+    // here until they get their own path
+    pub async fn authorize_iso(&self, request: &IsoRequest, accounts_id: AccountIdType, decimals: u32, now: NaiveDateTime) -> CoreResult<IdempotentDecision> {
+        if !matches!(request.class, MessageClass::Authorization | MessageClass::Financial) {
+            return Err(CoreError::system_error(
+                format!("{:?} messages ({}) are not authorized", request.class, request.mti),
+                "authorization::decision::Authorizer::authorize_iso",
+                SystemErrorCodes::UnknownOperation
+            ));
+        }
+        let authorization = request.to_authorization_request(accounts_id, decimals, now)?;
+        self.authorize_transmission(&TransmissionKey::from_request(request), &authorization, now).await
+    }
+
+    // System failures never escape: they become a decline with the error's response code
+    pub async fn authorize(&self, request: &AuthorizationRequest) -> AuthorizationDecision {
+        match self.try_authorize(request).await {
+            Ok(decision) => decision,
+            Err(e) => AuthorizationDecision::declined(
+                request,
+                match self.network.as_deref() {
+                    Some(network) => e.system_error.as_network_response_code(network),
+                    None => e.system_error.as_response_code(),
+                },
+                DecisionReason::SystemError { error_code: e.system_error, detail: e.detail }
+            ),
+        }
+    }
+
+    async fn try_authorize(&self, request: &AuthorizationRequest) -> CoreResult<AuthorizationDecision> {
+        if request.amount <= Decimal::ZERO {
+            return Ok(AuthorizationDecision::declined(request, ResponseCodes::InvalidAmount, DecisionReason::InvalidAmount));
+        }
+
+        let account = match self.repository.get_account(request.accounts_id).await? {
+            Some(account) => account,
+            None => return Ok(AuthorizationDecision::declined(
+                request,
+                ResponseCodes::InvalidNonexistentAccountSpecified,
+                DecisionReason::AccountNotFound
+            )),
+        };
+
+        if let Some(code) = self.blocks.check_account(&account, request.operation, request.online)? {
+            return Ok(AuthorizationDecision::declined(request, code, DecisionReason::Blocked(account.blocks_id())));
+        }
+
+        if is_expired(request.card_expiry, request.local_datetime.date()) {
+            return Ok(AuthorizationDecision::declined(request, ResponseCodes::ExpiredCard, DecisionReason::CardExpired));
+        }
+
+        let base_currencies_id = match primary_currency(&account) {
+            Some(currencies_id) if account.wallets().values().any(|w| w.currencies_id() == request.currencies_id) => currencies_id,
+            _ => return Ok(AuthorizationDecision::declined(
+                request,
+                ResponseCodes::TransactionNotPermittedToCardholder,
+                DecisionReason::CurrencyNotHeld(request.currencies_id)
+            )),
+        };
+
+        let today = request.local_datetime.date();
+        let history_minutes = self.limits.history_minutes.max(self.fraud.history_minutes(account.fraud_groups_id()));
+        let since = request.local_datetime - Duration::minutes(history_minutes);
+        let cash_limit = match request.operation {
+            Operation::Cash => self.limits.max_daily_cash_withdrawals,
+            Operation::Purchase => None,
+        };
+        // The cash count covers the whole local day even when history_minutes is shorter
+        let load_since = match cash_limit {
+            Some(_) => since.min(today.and_time(NaiveTime::MIN)),
+            None => since,
+        };
+        let loaded = self.repository.get_recent_authorizations(account.id(), load_since).await?;
+        let history = &loaded[loaded.partition_point(|t| t.local_datetime < since)..];
+
+        if let Some(limit) = cash_limit {
+            let count = loaded.iter()
+                .filter(|t| t.operation == Operation::Cash && t.local_datetime.date() == today)
+                .count() as u32;
+            if count >= limit {
+                return Ok(AuthorizationDecision::declined(
+                    request,
+                    ResponseCodes::ExceedsWithdrawalCountLimit,
+                    DecisionReason::CashCountExceeded { count, limit }
+                ));
+            }
+        }
+
+        let fraud_transaction = FraudTransaction {
+            amount: request.amount,
+            operation: request.operation,
+            online: request.online,
+            mcc: request.mcc,
+            country: request.country.clone(),
+            local_datetime: request.local_datetime,
+        };
+        let fraud = self.fraud.evaluate_account(&account, &fraud_transaction, history)?;
+        let fraud_decline = match fraud.outcome {
+            FraudOutcome::Allow => None,
+            FraudOutcome::Decline(code) => Some((code, DecisionReason::FraudDeclined)),
+            FraudOutcome::Refer => Some((ResponseCodes::ReferToCardIssuer, DecisionReason::FraudReferred)),
+        };
+        if let Some((code, reason)) = fraud_decline {
+            let mut decision = AuthorizationDecision::declined(request, code, reason);
+            decision.fraud_rules = fraud.matched_rules;
+            return Ok(decision);
+        }
+
+        let balances = self.repository.get_wallet_balances(account.id()).await?;
+        let availability = account.availability(base_currencies_id, &balances, &self.converter, today)?;
+        let available_base = match request.operation {
+            Operation::Purchase => availability.available_credit,
+            Operation::Cash => availability.available_cash,
+        };
+        let available = self.converter.convert(available_base, base_currencies_id, request.currencies_id, today)?
+            .round_dp_with_strategy(2, RoundingStrategy::ToZero);
+
+        let mut decision = AuthorizationDecision {
+            response_code: ResponseCodes::Approved,
+            approved_amount: request.amount,
+            currencies_id: request.currencies_id,
+            reasons: Vec::new(),
+            fraud_rules: fraud.matched_rules,
+        };
+        if request.amount > available {
+            match request.operation {
+                Operation::Purchase if request.partial_approval_allowed && available > Decimal::ZERO => {
+                    decision.response_code = ResponseCodes::PartialApproval;
+                    decision.approved_amount = available;
+                    decision.reasons.push(DecisionReason::PartiallyApproved { available });
+                },
+                Operation::Purchase => {
+                    decision.response_code = ResponseCodes::InsufficientFunds;
+                    decision.approved_amount = Decimal::ZERO;
+                    decision.reasons.push(DecisionReason::InsufficientCredit { available });
+                },
+                Operation::Cash => {
+                    decision.response_code = ResponseCodes::ExceedsWithdrawalAmountLimit;
+                    decision.approved_amount = Decimal::ZERO;
+                    decision.reasons.push(DecisionReason::CashLimitExceeded { available });
+                },
+            }
+        }
+        Ok(decision)
+    }
+}
+
+fn primary_currency(account: &Account) -> Option<CurrenciesIdType> {
+    wallets_by_priority(account).first().map(|wallet| wallet.currencies_id())
+}
+
+fn is_expired(card_expiry: Option<NaiveDate>, date: NaiveDate) -> bool {
+    match card_expiry {
+        Some(expiry) => (date.year(), date.month()) > (expiry.year(), expiry.month()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::blocks::{Block, DeniedOperations};
+    use crate::authorization::fraud::{FraudRule, RecentTransaction, RuleAction, RuleCondition};
+    use crate::billing::availability::WalletBalance;
+    use crate::billing::waterfall::SameCurrencyOnly;
+    use crate::data::repository::memory::InMemoryRepository;
+    use crate::datatypes::response_mapping::{set_response_code_mapping, ResponseCodeMapping};
+    use crate::datatypes::structs::Wallet;
+    use crate::iso8583::codec::IsoMessage;
+
+    const EUR: CurrenciesIdType = 978;
+    const USD: CurrenciesIdType = 840;
+    const STOLEN: BlockIdType = 5;
+
+    fn at(datetime: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    // One EUR wallet, 1000 credit with half of it available as cash
+    fn repository(blocks_id: BlockIdType, used: i64) -> InMemoryRepository {
+        let repository = InMemoryRepository::new();
+        let mut account = Account::new(1, 1, 1, blocks_id, 1, 0, Some(15), Decimal::new(1000, 0), 0.0, 0.0, 0.5, 0.0);
+        account.wallets_mut().insert(1, Wallet::new(1, EUR, 1));
+        repository.insert_account(account);
+        repository.insert_wallet_balance(1, 1, WalletBalance { posted: Decimal::new(used, 0), ..Default::default() });
+        repository
+    }
+
+    // Blocks STOLEN, declines anything above 500 and allows up to 2 cash withdrawals a day
+    // while only keeping an hour of history
+    fn authorizer(repository: InMemoryRepository) -> Authorizer<InMemoryRepository, SameCurrencyOnly> {
+        let mut blocks = BlockCatalog::new();
+        blocks.insert(Block { id: STOLEN, description: "stolen".to_string(), denied: DeniedOperations::all(), response_code: ResponseCodes::StolenCard });
+        let mut fraud = FraudEngine::new();
+        fraud.insert_group(1, vec![FraudRule { id: 9, condition: RuleCondition::AmountAbove(Decimal::new(500, 0)), action: RuleAction::Decline(ResponseCodes::DoNotHonor) }]);
+        let limits = AuthorizationLimits { max_daily_cash_withdrawals: Some(2), history_minutes: 60 };
+        Authorizer::new(repository, blocks, fraud, SameCurrencyOnly, limits)
+    }
+
+    fn request(operation: Operation, amount: i64, currencies_id: CurrenciesIdType, card_expiry: &str) -> AuthorizationRequest {
+        AuthorizationRequest {
+            accounts_id: 1,
+            amount: Decimal::new(amount, 0),
+            currencies_id,
+            operation,
+            online: false,
+            mcc: 5411,
+            country: "ESP".to_string(),
+            local_datetime: at("2024-03-15 18:00"),
+            card_expiry: NaiveDate::parse_from_str(card_expiry, "%Y-%m-%d").ok(),
+            partial_approval_allowed: true,
+        }
+    }
+
+    fn cash_at(repository: &InMemoryRepository, datetimes: &[&str]) {
+        for datetime in datetimes {
+            repository.insert_authorization(1, RecentTransaction { local_datetime: at(datetime), amount: Decimal::new(20, 0), operation: Operation::Cash });
+        }
+    }
+
+    #[tokio::test]
+    async fn block_is_checked_before_expiry() {
+        let decision = authorizer(repository(STOLEN, 0)).authorize(&request(Operation::Purchase, 10, EUR, "2024-02-01")).await;
+        assert_eq!(decision.response_code, ResponseCodes::StolenCard);
+        assert_eq!(decision.reasons, vec![DecisionReason::Blocked(STOLEN)]);
+    }
+
+    #[tokio::test]
+    async fn expiry_is_checked_before_wallet_currency() {
+        let decision = authorizer(repository(0, 0)).authorize(&request(Operation::Purchase, 10, USD, "2024-02-01")).await;
+        assert_eq!(decision.reasons, vec![DecisionReason::CardExpired]);
+
+        // The card is valid through the end of its expiry month
+        let decision = authorizer(repository(0, 0)).authorize(&request(Operation::Purchase, 10, EUR, "2024-03-01")).await;
+        assert_eq!(decision.response_code, ResponseCodes::Approved);
+    }
+
+    #[tokio::test]
+    async fn wallet_currency_is_checked_before_velocity() {
+        let repository = repository(0, 0);
+        cash_at(&repository, &["2024-03-15 17:30", "2024-03-15 17:45"]);
+        let decision = authorizer(repository).authorize(&request(Operation::Cash, 10, USD, "2025-01-01")).await;
+        assert_eq!(decision.reasons, vec![DecisionReason::CurrencyNotHeld(USD)]);
+    }
+
+    #[tokio::test]
+    async fn velocity_is_checked_before_fraud() {
+        let repository = repository(0, 0);
+        cash_at(&repository, &["2024-03-15 17:30", "2024-03-15 17:45"]);
+        let decision = authorizer(repository).authorize(&request(Operation::Cash, 600, EUR, "2025-01-01")).await;
+        assert_eq!(decision.response_code, ResponseCodes::ExceedsWithdrawalCountLimit);
+        assert_eq!(decision.reasons, vec![DecisionReason::CashCountExceeded { count: 2, limit: 2 }]);
+    }
+
+    #[tokio::test]
+    async fn fraud_is_checked_before_available_credit() {
+        let decision = authorizer(repository(0, 900)).authorize(&request(Operation::Purchase, 600, EUR, "2025-01-01")).await;
+        assert_eq!(decision.response_code, ResponseCodes::DoNotHonor);
+        assert_eq!(decision.reasons, vec![DecisionReason::FraudDeclined]);
+        assert_eq!(decision.fraud_rules, vec![9]);
+    }
+
+    #[tokio::test]
+    async fn available_credit_is_checked_last() {
+        let authorizer = authorizer(repository(0, 900));
+        let decision = authorizer.authorize(&request(Operation::Purchase, 300, EUR, "2025-01-01")).await;
+        assert_eq!(decision.response_code, ResponseCodes::PartialApproval);
+        assert_eq!(decision.approved_amount, Decimal::new(100, 0));
+
+        let decision = authorizer.authorize(&request(Operation::Cash, 300, EUR, "2025-01-01")).await;
+        assert_eq!(decision.reasons, vec![DecisionReason::CashLimitExceeded { available: Decimal::new(100, 0) }]);
+    }
+
+    #[tokio::test]
+    async fn cash_count_covers_the_whole_day_beyond_history_minutes() {
+        let repository = repository(0, 0);
+        cash_at(&repository, &["2024-03-14 23:50", "2024-03-15 08:00"]);
+        let authorizer = authorizer(repository);
+        assert!(authorizer.authorize(&request(Operation::Cash, 10, EUR, "2025-01-01")).await.is_approved());
+
+        authorizer.repository.insert_authorization(1, RecentTransaction { local_datetime: at("2024-03-15 09:00"), amount: Decimal::new(20, 0), operation: Operation::Cash });
+        let decision = authorizer.authorize(&request(Operation::Cash, 10, EUR, "2025-01-01")).await;
+        assert_eq!(decision.reasons, vec![DecisionReason::CashCountExceeded { count: 2, limit: 2 }]);
+    }
+
+    #[tokio::test]
+    async fn fraud_history_covers_the_longest_velocity_window() {
+        let repository = repository(0, 0);
+        cash_at(&repository, &["2024-03-15 15:30", "2024-03-15 16:00"]);
+        let mut authorizer = authorizer(repository);
+        let velocity = RuleCondition::Velocity { window_minutes: 180, max_count: Some(2), max_amount: None };
+        authorizer.fraud.insert_group(1, vec![FraudRule { id: 10, condition: velocity, action: RuleAction::Decline(ResponseCodes::DoNotHonor) }]);
+
+        let decision = authorizer.authorize(&request(Operation::Purchase, 10, EUR, "2025-01-01")).await;
+        assert_eq!(decision.reasons, vec![DecisionReason::FraudDeclined]);
+        assert_eq!(decision.fraud_rules, vec![10]);
+    }
+
+    #[tokio::test]
+    async fn system_errors_use_the_network_response_codes() {
+        let config = toml::from_str("[networks.authorizer_test]\nExchangeConfiguration = 12").unwrap();
+        set_response_code_mapping(ResponseCodeMapping::from_config(&config).unwrap());
+
+        // Converting the USD wallet's balance without exchange rates fails
+        let with_usd_wallet = || async {
+            let repository = repository(0, 0);
+            let mut account = repository.get_account(1).await.unwrap().unwrap();
+            account.wallets_mut().insert(2, Wallet::new(2, USD, 2));
+            repository.insert_account(account);
+            repository
+        };
+        let request = request(Operation::Purchase, 10, USD, "2025-01-01");
+
+        let decision = authorizer(with_usd_wallet().await).authorize(&request).await;
+        assert_eq!(decision.response_code, ResponseCodes::SystemError);
+        assert!(matches!(decision.reasons[..], [DecisionReason::SystemError { error_code: SystemErrorCodes::ExchangeConfiguration, .. }]));
+
+        let decision = authorizer(with_usd_wallet().await).with_network("authorizer_test").authorize(&request).await;
+        assert_eq!(decision.response_code, ResponseCodes::InvalidTransaction);
+    }
+
+    #[tokio::test]
+    async fn retransmissions_get_the_first_decision() {
+        let authorizer = authorizer(repository(0, 0));
+        let key = TransmissionKey { acquirer_id: "123456".to_string(), stan: "000001".to_string(), transmission_date_time: "0315180000".to_string(), terminal_id: String::new() };
+        let request = request(Operation::Purchase, 300, EUR, "2025-01-01");
+
+        let first = authorizer.authorize_transmission(&key, &request, at("2024-03-15 18:00")).await.unwrap();
+        authorizer.repository.insert_wallet_balance(1, 1, WalletBalance { posted: Decimal::new(1000, 0), ..Default::default() });
+        let second = authorizer.authorize_transmission(&key, &request, at("2024-03-15 18:01")).await.unwrap();
+
+        assert!(first.decision.is_approved() && !first.duplicate);
+        assert!(second.decision.is_approved() && second.duplicate);
+    }
+
+    #[tokio::test]
+    async fn reversals_are_not_authorized_as_debits() {
+        let mut message = IsoMessage::new("0400");
+        message.set(2, "4111111111111111");
+        message.set(3, "000000");
+        message.set(4, "000000001000");
+        message.set(7, "0315180000");
+        message.set(11, "000001");
+        message.set(12, "180000");
+        message.set(13, "0315");
+        message.set(49, "978");
+        message.set(90, "0".repeat(42));
+        let reversal = IsoRequest::parse(&message).unwrap();
+        assert_eq!(reversal.class, MessageClass::Reversal);
+
+        let authorizer = authorizer(repository(0, 0));
+        let error = authorizer.authorize_iso(&reversal, 1, 2, at("2024-03-15 18:00")).await.unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::UnknownOperation);
+
+        message.set_mti("0100");
+        message.remove(90);
+        let authorization = IsoRequest::parse(&message).unwrap();
+        let decision = authorizer.authorize_iso(&authorization, 1, 2, at("2024-03-15 18:00")).await.unwrap();
+        assert_eq!(decision.decision.approved_amount, Decimal::new(1000, 2));
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::{HashMap, HashSet};
+use mysql_async::Conn;
+use mysql_async::prelude::Queryable;
+use mysql_common::chrono::{Duration, NaiveDateTime, NaiveTime};
+use mysql_common::row::Row;
+use mysql_common::rust_decimal::Decimal;
+use my_own_tests_derive::FromRow;
+use serde::{Deserialize, Serialize};
+use crate::authorization::blocks::Operation;
+use crate::datatypes::response_codes::{ResponseCategory, ResponseCodes};
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::FraudGroupsId;
+use crate::utils::{CoreError, CoreResult};
+
+pub type FraudRuleIdType = u32;
+pub type MccType = u16;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FraudTransaction {
+    pub amount: Decimal,
+    pub operation: Operation,
+    pub online: bool,
+    pub mcc: MccType,
+    // ISO 3166 alpha-3, compared case-insensitively
+    pub country: String,
+    // Cardholder local time, used by time-of-day rules and as the end of velocity windows
+    pub local_datetime: NaiveDateTime,
+}
+
+// Earlier transactions of the account, only needed by velocity rules
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RecentTransaction {
+    pub local_datetime: NaiveDateTime,
+    pub amount: Decimal,
+    pub operation: Operation,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum RuleCondition {
+    AmountAbove(Decimal),
+    MccIn(HashSet<MccType>),
+    CountryIn(HashSet<String>),
+    // Wraps around midnight when start > end
+    TimeBetween { start: NaiveTime, end: NaiveTime },
+    // Matches when the transaction would take the window over either limit
+    Velocity { window_minutes: i64, max_count: Option<u32>, max_amount: Option<Decimal> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RuleAction {
+    // Whitelist: stops evaluation and lets the transaction through
+    Allow,
+    Decline(ResponseCodes),
+    Refer,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FraudRule {
+    pub id: FraudRuleIdType,
+    pub condition: RuleCondition,
+    pub action: RuleAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FraudOutcome {
+    Allow,
+    Decline(ResponseCodes),
+    Refer,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FraudEvaluation {
+    pub outcome: FraudOutcome,
+    // In evaluation order, for auditing
+    pub matched_rules: Vec<FraudRuleIdType>,
+}
+
+impl RuleCondition {
+    pub fn matches(&self, transaction: &FraudTransaction, history: &[RecentTransaction]) -> bool {
+        match self {
+            RuleCondition::AmountAbove(threshold) => transaction.amount > *threshold,
+            RuleCondition::MccIn(mccs) => mccs.contains(&transaction.mcc),
+            RuleCondition::CountryIn(countries) => countries.contains(&transaction.country.to_uppercase()),
+            RuleCondition::TimeBetween { start, end } => {
+                let time = transaction.local_datetime.time();
+                if start <= end { time >= *start && time < *end } else { time >= *start || time < *end }
+            },
+            RuleCondition::Velocity { window_minutes, max_count, max_amount } => {
+                let since = transaction.local_datetime - Duration::minutes(*window_minutes);
+                let (count, amount) = history.iter()
+                    .filter(|t| t.local_datetime > since && t.local_datetime <= transaction.local_datetime)
+                    .fold((1u32, transaction.amount), |(count, amount), t| (count + 1, amount + t.amount));
+                max_count.map(|max| count > max).unwrap_or(false)
+                    || max_amount.map(|max| amount > max).unwrap_or(false)
+            },
+        }
+    }
+}
+
+// Rules of each group run in position order. The first matching Allow or Decline rule ends the
+// evaluation; Refer rules are recorded and evaluation continues, so a later decline still wins
+#[derive(Debug, Clone, Default)]
+pub struct FraudEngine {
+    groups: HashMap<FraudGroupsId, Vec<FraudRule>>,
+}
+
+impl FraudEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces the group; rules must already be in evaluation order
+    pub fn insert_group(&mut self, fraud_groups_id: FraudGroupsId, rules: Vec<FraudRule>) {
+        self.groups.insert(fraud_groups_id, rules);
+    }
+
+    pub fn evaluate(&self, fraud_groups_id: FraudGroupsId, transaction: &FraudTransaction, history: &[RecentTransaction]) -> CoreResult<FraudEvaluation> {
+        let rules = self.groups.get(&fraud_groups_id)
+            .ok_or_else(|| CoreError::system_error(
+                format!("Unknown fraud group {}", fraud_groups_id),
+                "authorization::fraud::FraudEngine::evaluate",
+                SystemErrorCodes::InvalidFraudRuleGroup
+            ))?;
+
+        let mut evaluation = FraudEvaluation { outcome: FraudOutcome::Allow, matched_rules: Vec::new() };
+        for rule in rules.iter().filter(|rule| rule.condition.matches(transaction, history)) {
+            evaluation.matched_rules.push(rule.id);
+            match rule.action {
+                RuleAction::Allow => {
+                    evaluation.outcome = FraudOutcome::Allow;
+                    break;
+                },
+                RuleAction::Decline(code) => {
+                    evaluation.outcome = FraudOutcome::Decline(code);
+                    break;
+                },
+                RuleAction::Refer => evaluation.outcome = FraudOutcome::Refer,
+            }
+        }
+        Ok(evaluation)
+    }
+
+    // Longest Velocity window in the group, so callers load enough history for it
+    pub fn history_minutes(&self, fraud_groups_id: FraudGroupsId) -> i64 {
+        self.groups.get(&fraud_groups_id).into_iter().flatten()
+            .filter_map(|rule| match rule.condition {
+                RuleCondition::Velocity { window_minutes, .. } => Some(window_minutes),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn evaluate_account(&self, account: &Account, transaction: &FraudTransaction, history: &[RecentTransaction]) -> CoreResult<FraudEvaluation> {
+        self.evaluate(account.fraud_groups_id(), transaction, history)
+    }
+
+    // Every group in fraud_groups is loaded, including those without rules, so an account pointing
+    // at a missing group is reported instead of silently allowed
+    pub async fn load_from_db(conn: &mut Conn) -> CoreResult<Self> {
+        let mut engine = Self::new();
+
+        let groups = conn.query::<FraudGroupsId, _>("SELECT ID FROM fraud_groups")
+            .await
+            .map_err(|e| CoreError::system_error(e, "authorization::fraud::FraudEngine::load_from_db", SystemErrorCodes::DbQuery(18)))?;
+        for fraud_groups_id in groups {
+            engine.groups.insert(fraud_groups_id, Vec::new());
+        }
+
+        let rows = conn.query::<Row, _>(
+            "SELECT ID, fraud_groups_ID, rule_type, parameters, action, response_code FROM fraud_rules \
+            WHERE active = 1 ORDER BY fraud_groups_ID, position, ID"
+        ).await.map_err(|e| CoreError::system_error(e, "authorization::fraud::FraudEngine::load_from_db", SystemErrorCodes::DbQuery(19)))?;
+
+        for row in rows.iter() {
+            let rule_row = FraudRuleRow::try_from_row(row)?;
+            let fraud_groups_id = rule_row.fraud_groups_id;
+            let rule = rule_row.into_rule()?;
+            engine.groups.get_mut(&fraud_groups_id)
+                .ok_or_else(|| rule_error(format!("Rule {} belongs to unknown fraud group {}", rule.id, fraud_groups_id)))?
+                .push(rule);
+        }
+
+        Ok(engine)
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "fraud_rules")]
+struct FraudRuleRow {
+    #[from_row(rename = "ID")]
+    id: FraudRuleIdType,
+    #[from_row(rename = "fraud_groups_ID")]
+    fraud_groups_id: FraudGroupsId,
+    rule_type: String,
+    #[from_row(json)]
+    parameters: serde_json::Value,
+    action: String,
+    response_code: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct AmountParameters {
+    above: Decimal,
+}
+
+#[derive(Deserialize)]
+struct MccParameters {
+    mcc: Vec<MccType>,
+}
+
+#[derive(Deserialize)]
+struct CountryParameters {
+    countries: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TimeOfDayParameters {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+#[derive(Deserialize)]
+struct VelocityParameters {
+    window_minutes: i64,
+    max_count: Option<u32>,
+    max_amount: Option<Decimal>,
+}
+
+impl FraudRuleRow {
+    fn into_rule(self) -> CoreResult<FraudRule> {
+        let condition = match self.rule_type.as_str() {
+            "amount" => RuleCondition::AmountAbove(self.parameters::<AmountParameters>()?.above),
+            "mcc" => RuleCondition::MccIn(self.parameters::<MccParameters>()?.mcc.into_iter().collect()),
+            "country" => RuleCondition::CountryIn(
+                self.parameters::<CountryParameters>()?.countries.iter().map(|c| c.to_uppercase()).collect()
+            ),
+            "time_of_day" => {
+                let p = self.parameters::<TimeOfDayParameters>()?;
+                RuleCondition::TimeBetween { start: p.start, end: p.end }
+            },
+            "velocity" => {
+                let p = self.parameters::<VelocityParameters>()?;
+                if p.window_minutes <= 0 || (p.max_count.is_none() && p.max_amount.is_none()) {
+                    return Err(rule_error(format!("Rule {} needs a positive window and max_count or max_amount", self.id)));
+                }
+                RuleCondition::Velocity { window_minutes: p.window_minutes, max_count: p.max_count, max_amount: p.max_amount }
+            },
+            other => return Err(rule_error(format!("Rule {} has unknown rule_type {}", self.id, other))),
+        };
+
+        let action = match self.action.as_str() {
+            "allow" => RuleAction::Allow,
+            "refer" => RuleAction::Refer,
+            "decline" => RuleAction::Decline(
+                self.response_code
+                    .and_then(ResponseCodes::from_code)
+                    // An approval code would make the decline an approved response
+                    .filter(|code| code.category() != ResponseCategory::Approved)
+                    .ok_or_else(|| rule_error(format!("Decline rule {} has no valid decline response_code", self.id)))?
+            ),
+            other => return Err(rule_error(format!("Rule {} has unknown action {}", self.id, other))),
+        };
+
+        Ok(FraudRule { id: self.id, condition, action })
+    }
+
+    fn parameters<T: for<'de> Deserialize<'de>>(&self) -> CoreResult<T> {
+        serde_json::from_value(self.parameters.clone())
+            .map_err(|e| rule_error(format!("Rule {} {} parameters: {}", self.id, self.rule_type, e)))
+    }
+}
+
+fn rule_error(detail: String) -> CoreError {
+    CoreError::system_error(detail, "authorization::fraud", SystemErrorCodes::InvalidFraudRuleGroup)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn at(datetime: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn transaction(amount: i64, local_datetime: &str) -> FraudTransaction {
+        FraudTransaction {
+            amount: Decimal::new(amount, 0),
+            operation: Operation::Purchase,
+            online: false,
+            mcc: 5411,
+            country: "esp".to_string(),
+            local_datetime: at(local_datetime),
+        }
+    }
+
+    fn recent(amount: i64, local_datetime: &str) -> RecentTransaction {
+        RecentTransaction { local_datetime: at(local_datetime), amount: Decimal::new(amount, 0), operation: Operation::Purchase }
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn rule(id: FraudRuleIdType, condition: RuleCondition, action: RuleAction) -> FraudRule {
+        FraudRule { id, condition, action }
+    }
+
+    fn row(rule_type: &str, parameters: serde_json::Value, action: &str, response_code: Option<u8>) -> FraudRuleRow {
+        FraudRuleRow { id: 1, fraud_groups_id: 1, rule_type: rule_type.to_string(), parameters, action: action.to_string(), response_code }
+    }
+
+    #[test]
+    fn simple_conditions_match() {
+        let purchase = transaction(100, "2024-03-15 12:00");
+        assert!(RuleCondition::AmountAbove(Decimal::new(99, 0)).matches(&purchase, &[]));
+        assert!(!RuleCondition::AmountAbove(Decimal::new(100, 0)).matches(&purchase, &[]));
+        assert!(RuleCondition::MccIn([5411, 6011].into()).matches(&purchase, &[]));
+        assert!(!RuleCondition::MccIn([6011].into()).matches(&purchase, &[]));
+        assert!(RuleCondition::CountryIn(["ESP".to_string()].into()).matches(&purchase, &[]));
+        assert!(!RuleCondition::CountryIn(["PRT".to_string()].into()).matches(&purchase, &[]));
+    }
+
+    #[test]
+    fn history_minutes_is_the_longest_velocity_window() {
+        let mut engine = FraudEngine::new();
+        engine.insert_group(1, vec![
+            rule(1, RuleCondition::Velocity { window_minutes: 60, max_count: Some(3), max_amount: None }, RuleAction::Refer),
+            rule(2, RuleCondition::AmountAbove(Decimal::new(500, 0)), RuleAction::Refer),
+            rule(3, RuleCondition::Velocity { window_minutes: 1440, max_count: None, max_amount: Some(Decimal::new(900, 0)) }, RuleAction::Refer),
+        ]);
+        engine.insert_group(2, vec![rule(4, RuleCondition::AmountAbove(Decimal::new(500, 0)), RuleAction::Refer)]);
+
+        assert_eq!(engine.history_minutes(1), 1440);
+        assert_eq!(engine.history_minutes(2), 0);
+        assert_eq!(engine.history_minutes(3), 0);
+    }
+
+    #[test]
+    fn time_between_wraps_midnight() {
+        let night = RuleCondition::TimeBetween { start: time(22, 0), end: time(6, 0) };
+        let day = RuleCondition::TimeBetween { start: time(9, 0), end: time(17, 0) };
+        // (time, night, day)
+        let cases = [("23:30", true, false), ("02:00", true, false), ("06:00", false, false), ("22:00", true, false), ("09:00", false, true), ("17:00", false, false)];
+        for (clock, in_night, in_day) in cases {
+            let purchase = transaction(10, &format!("2024-03-15 {}", clock));
+            assert_eq!(night.matches(&purchase, &[]), in_night, "night {}", clock);
+            assert_eq!(day.matches(&purchase, &[]), in_day, "day {}", clock);
+        }
+    }
+
+    #[test]
+    fn velocity_counts_the_current_transaction() {
+        let history = [recent(50, "2024-03-15 10:00"), recent(50, "2024-03-15 11:30"), recent(50, "2024-03-15 11:50")];
+        let purchase = transaction(60, "2024-03-15 12:00");
+
+        // Two in the last hour plus this one
+        let by_count = |max_count| RuleCondition::Velocity { window_minutes: 60, max_count: Some(max_count), max_amount: None };
+        assert!(by_count(2).matches(&purchase, &history));
+        assert!(!by_count(3).matches(&purchase, &history));
+        assert!(!by_count(1).matches(&purchase, &[]));
+
+        // 50 + 50 + 60 in the last hour
+        let by_amount = |max_amount| RuleCondition::Velocity { window_minutes: 60, max_count: None, max_amount: Some(Decimal::new(max_amount, 0)) };
+        assert!(by_amount(159).matches(&purchase, &history));
+        assert!(!by_amount(160).matches(&purchase, &history));
+
+        let longer = RuleCondition::Velocity { window_minutes: 180, max_count: Some(3), max_amount: None };
+        assert!(longer.matches(&purchase, &history));
+    }
+
+    #[test]
+    fn evaluate_refers_and_continues_until_a_decline_or_allow() {
+        let mut engine = FraudEngine::new();
+        engine.insert_group(1, vec![
+            rule(1, RuleCondition::AmountAbove(Decimal::new(50, 0)), RuleAction::Refer),
+            rule(2, RuleCondition::MccIn([6011].into()), RuleAction::Allow),
+            rule(3, RuleCondition::AmountAbove(Decimal::new(500, 0)), RuleAction::Decline(ResponseCodes::DoNotHonor)),
+            rule(4, RuleCondition::AmountAbove(Decimal::new(900, 0)), RuleAction::Decline(ResponseCodes::RestrictedCard)),
+        ]);
+
+        let refer = engine.evaluate(1, &transaction(100, "2024-03-15 12:00"), &[]).unwrap();
+        assert_eq!((refer.outcome, refer.matched_rules), (FraudOutcome::Refer, vec![1]));
+
+        let decline = engine.evaluate(1, &transaction(1000, "2024-03-15 12:00"), &[]).unwrap();
+        assert_eq!((decline.outcome, decline.matched_rules), (FraudOutcome::Decline(ResponseCodes::DoNotHonor), vec![1, 3]));
+
+        let mut whitelisted = transaction(1000, "2024-03-15 12:00");
+        whitelisted.mcc = 6011;
+        let allow = engine.evaluate(1, &whitelisted, &[]).unwrap();
+        assert_eq!((allow.outcome, allow.matched_rules), (FraudOutcome::Allow, vec![1, 2]));
+
+        let clean = engine.evaluate(1, &transaction(10, "2024-03-15 12:00"), &[]).unwrap();
+        assert_eq!((clean.outcome, clean.matched_rules), (FraudOutcome::Allow, vec![]));
+    }
+
+    #[test]
+    fn unknown_group_is_an_error() {
+        let error = FraudEngine::new().evaluate(9, &transaction(10, "2024-03-15 12:00"), &[]).unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::InvalidFraudRuleGroup);
+    }
+
+    #[test]
+    fn rows_become_rules() {
+        let velocity = row("velocity", json!({ "window_minutes": 60, "max_count": 3 }), "decline", Some(ResponseCodes::DoNotHonor as u8)).into_rule().unwrap();
+        assert_eq!(velocity.condition, RuleCondition::Velocity { window_minutes: 60, max_count: Some(3), max_amount: None });
+        assert_eq!(velocity.action, RuleAction::Decline(ResponseCodes::DoNotHonor));
+
+        let country = row("country", json!({ "countries": ["esp", "Prt"] }), "refer", None).into_rule().unwrap();
+        assert_eq!(country.condition, RuleCondition::CountryIn(["ESP".to_string(), "PRT".to_string()].into()));
+
+        let night = row("time_of_day", json!({ "start": "22:00:00", "end": "06:00:00" }), "allow", None).into_rule().unwrap();
+        assert_eq!(night.condition, RuleCondition::TimeBetween { start: time(22, 0), end: time(6, 0) });
+    }
+
+    #[test]
+    fn invalid_rows_are_rejected() {
+        let decline = |code: Option<u8>| row("amount", json!({ "above": 100 }), "decline", code);
+        let invalid = [
+            row("amount", json!({ "above": 100 }), "block", None),
+            row("weather", json!({}), "refer", None),
+            row("amount", json!({ "below": 100 }), "refer", None),
+            row("mcc", json!({ "mcc": "5411" }), "refer", None),
+            row("velocity", json!({ "window_minutes": 60 }), "refer", None),
+            row("velocity", json!({ "window_minutes": 0, "max_count": 3 }), "refer", None),
+            decline(None),
+            decline(Some(2)),
+            decline(Some(ResponseCodes::Approved as u8)),
+            decline(Some(ResponseCodes::PartialApproval as u8)),
+        ];
+        for row in invalid {
+            let description = format!("{} {} {} {:?}", row.rule_type, row.parameters, row.action, row.response_code);
+            assert_eq!(row.into_rule().unwrap_err().system_error, SystemErrorCodes::InvalidFraudRuleGroup, "{}", description);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use mysql_common::chrono::{Duration, NaiveDateTime};
+use mysql_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use crate::authorization::decision::{AuthorizationDecision, DecisionReason};
+use crate::data::repository::IdempotencyRepository;
+use crate::datatypes::response_codes::{ResponseCategory, ResponseCodes};
+use crate::iso8583::requests::IsoRequest;
+use crate::utils::CoreResult;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    // How long a decision is replayed for its transmission key
+    pub window_secs: u64,
+    // false declines retransmissions with DuplicateTransmissionDetected instead of replaying
+    pub replay: bool,
+    // Size of the in-process cache; expired entries are pruned first, then the oldest live ones
+    pub max_cached: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig { window_secs: 300, replay: true, max_cached: 100_000 }
+    }
+}
+
+// Fields 32, 11, 7 and 41. Missing acquirer or terminal IDs are kept as empty strings
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct TransmissionKey {
+    pub acquirer_id: String,
+    pub stan: String,
+    pub transmission_date_time: String,
+    pub terminal_id: String,
+}
+
+impl TransmissionKey {
+    pub fn from_request(request: &IsoRequest) -> Self {
+        TransmissionKey {
+            acquirer_id: request.acquirer_id.clone().unwrap_or_default(),
+            stan: request.stan.clone(),
+            transmission_date_time: request.transmission_date_time.clone(),
+            terminal_id: request.terminal_id.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IdempotentDecision {
+    pub decision: AuthorizationDecision,
+    // true when an earlier transmission of the same key was already decided
+    pub duplicate: bool,
+}
+
+// Decisions are looked up in the in-process cache first, then in the repository, and only
+// authorized when neither has one inside the window. Concurrent transmissions of the same key
+// are serialized, so a retransmission arriving mid-flight waits for the original's decision.
+// Retryable and system declines are never kept, a retransmission gets authorized again
+pub struct IdempotencyStore {
+    window: Duration,
+    replay: bool,
+    max_cached: usize,
+    cache: Mutex<HashMap<TransmissionKey, (NaiveDateTime, AuthorizationDecision)>>,
+    in_flight: Mutex<HashMap<TransmissionKey, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(config: &IdempotencyConfig) -> Self {
+        IdempotencyStore {
+            window: Duration::seconds(config.window_secs as i64),
+            replay: config.replay,
+            max_cached: config.max_cached,
+            cache: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // `authorize` only runs for first transmissions. Its decision is stored even when the
+    // repository write fails, the cache still covers retransmissions reaching this process
+    pub async fn authorize_once<R, F, Fut>(&self, repository: &R, key: &TransmissionKey, now: NaiveDateTime, authorize: F) -> CoreResult<IdempotentDecision>
+    where
+        R: IdempotencyRepository,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = AuthorizationDecision>,
+    {
+        let lock = self.in_flight.lock().unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = lock.lock().await;
+        let result = self.decide(repository, key, now, authorize).await;
+        drop(guard);
+
+        // Map plus this task: nobody else is waiting on the key
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            in_flight.remove(key);
+        }
+        result
+    }
+
+    async fn decide<R, F, Fut>(&self, repository: &R, key: &TransmissionKey, now: NaiveDateTime, authorize: F) -> CoreResult<IdempotentDecision>
+    where
+        R: IdempotencyRepository,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = AuthorizationDecision>,
+    {
+        let since = now - self.window;
+        if let Some(decision) = self.cached(key, since) {
+            return Ok(self.duplicate(decision));
+        }
+        // Cached with the row's own timestamp, replaying must not extend the window
+        if let Some((created_at, decision)) = repository.get_decision(key, since).await? {
+            self.cache(key, created_at, decision.clone(), since);
+            return Ok(self.duplicate(decision));
+        }
+
+        let decision = authorize().await;
+        if !is_final(&decision) {
+            return Ok(IdempotentDecision { decision, duplicate: false });
+        }
+        let stored = repository.insert_decision(key, &decision, now, since).await;
+        if let Ok(false) = stored {
+            // Another process recorded the key first, its decision is the one the acquirer gets
+            if let Some((created_at, original)) = repository.get_decision(key, since).await? {
+                self.cache(key, created_at, original.clone(), since);
+                return Ok(self.duplicate(original));
+            }
+        }
+        self.cache(key, now, decision.clone(), since);
+        Ok(IdempotentDecision { decision, duplicate: false })
+    }
+
+    fn cached(&self, key: &TransmissionKey, since: NaiveDateTime) -> Option<AuthorizationDecision> {
+        self.cache.lock().unwrap()
+            .get(key)
+            .filter(|(at, _)| *at >= since)
+            .map(|(_, decision)| decision.clone())
+    }
+
+    fn cache(&self, key: &TransmissionKey, at: NaiveDateTime, decision: AuthorizationDecision, since: NaiveDateTime) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.max_cached {
+            cache.retain(|_, (cached_at, _)| *cached_at >= since);
+        }
+        // Still full with live entries: the oldest go, the repository still has them
+        while !cache.is_empty() && cache.len() >= self.max_cached {
+            let oldest = cache.iter()
+                .min_by_key(|(_, (cached_at, _))| *cached_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(key.clone(), (at, decision));
+    }
+
+    fn duplicate(&self, original: AuthorizationDecision) -> IdempotentDecision {
+        let decision = match self.replay {
+            true => original,
+            false => AuthorizationDecision {
+                response_code: ResponseCodes::DuplicateTransmissionDetected,
+                approved_amount: Decimal::ZERO,
+                currencies_id: original.currencies_id,
+                reasons: vec![DecisionReason::DuplicateTransmission],
+                fraud_rules: Vec::new(),
+            },
+        };
+        IdempotentDecision { decision, duplicate: true }
+    }
+}
+
+// Retryable and system declines depend on a transient state, replaying them would keep
+// declining a retransmission that could now succeed
+fn is_final(decision: &AuthorizationDecision) -> bool {
+    !matches!(decision.response_code.category(), ResponseCategory::Retryable | ResponseCategory::System)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use mysql_common::chrono::NaiveDate;
+    use super::*;
+    use crate::data::repository::memory::InMemoryRepository;
+
+    fn key(stan: &str) -> TransmissionKey {
+        TransmissionKey {
+            acquirer_id: "123456".to_string(),
+            stan: stan.to_string(),
+            transmission_date_time: "0315120000".to_string(),
+            terminal_id: "TERM0001".to_string(),
+        }
+    }
+
+    fn at(seconds: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, 15).unwrap().and_hms_opt(12, 0, 0).unwrap() + Duration::seconds(seconds as i64)
+    }
+
+    fn decision(response_code: ResponseCodes) -> AuthorizationDecision {
+        AuthorizationDecision {
+            response_code,
+            approved_amount: Decimal::ZERO,
+            currencies_id: 978,
+            reasons: Vec::new(),
+            fraud_rules: Vec::new(),
+        }
+    }
+
+    fn store(replay: bool) -> IdempotencyStore {
+        IdempotencyStore::new(&IdempotencyConfig { window_secs: 300, replay, max_cached: 10 })
+    }
+
+    // Counts the calls and answers with `response_code`
+    async fn authorize(calls: &AtomicUsize, response_code: ResponseCodes) -> AuthorizationDecision {
+        calls.fetch_add(1, Ordering::SeqCst);
+        tokio::task::yield_now().await;
+        decision(response_code)
+    }
+
+    #[tokio::test]
+    async fn retransmission_replays_the_first_decision() {
+        let repository = InMemoryRepository::new();
+        let store = store(true);
+        let calls = AtomicUsize::new(0);
+
+        let first = store.authorize_once(&repository, &key("000001"), at(0), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+        let second = store.authorize_once(&repository, &key("000001"), at(10), || authorize(&calls, ResponseCodes::InsufficientFunds)).await.unwrap();
+
+        assert!(!first.duplicate);
+        assert!(second.duplicate);
+        assert_eq!(second.decision.response_code, ResponseCodes::Approved);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(repository.get_decision(&key("000001"), at(0)).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn retransmission_is_declined_without_replay() {
+        let repository = InMemoryRepository::new();
+        let store = store(false);
+        let calls = AtomicUsize::new(0);
+
+        store.authorize_once(&repository, &key("000001"), at(0), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+        let second = store.authorize_once(&repository, &key("000001"), at(10), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+
+        assert_eq!(second.decision.response_code, ResponseCodes::DuplicateTransmissionDetected);
+        assert_eq!(second.decision.reasons, vec![DecisionReason::DuplicateTransmission]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retryable_and_system_declines_are_not_kept() {
+        let repository = InMemoryRepository::new();
+        let store = store(true);
+        let calls = AtomicUsize::new(0);
+
+        for response_code in [ResponseCodes::SystemError, ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative] {
+            let first = store.authorize_once(&repository, &key("000001"), at(0), || authorize(&calls, response_code)).await.unwrap();
+            assert_eq!(first.decision.response_code, response_code);
+        }
+        assert!(repository.get_decision(&key("000001"), at(0)).await.unwrap().is_none());
+
+        let retry = store.authorize_once(&repository, &key("000001"), at(10), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+        assert!(!retry.duplicate);
+        assert_eq!(retry.decision.response_code, ResponseCodes::Approved);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn key_is_authorized_again_after_the_window() {
+        let repository = InMemoryRepository::new();
+        let store = store(true);
+        let calls = AtomicUsize::new(0);
+
+        store.authorize_once(&repository, &key("000001"), at(0), || authorize(&calls, ResponseCodes::InsufficientFunds)).await.unwrap();
+        let later = store.authorize_once(&repository, &key("000001"), at(301), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+
+        assert!(!later.duplicate);
+        assert_eq!(later.decision.response_code, ResponseCodes::Approved);
+        assert_eq!(repository.get_decision(&key("000001"), at(1)).await.unwrap().unwrap().1.response_code, ResponseCodes::Approved);
+    }
+
+    #[tokio::test]
+    async fn decision_recorded_by_another_process_is_replayed() {
+        let repository = InMemoryRepository::new();
+        repository.insert_decision(&key("000001"), &decision(ResponseCodes::DoNotHonor), at(0), at(0)).await.unwrap();
+        let calls = AtomicUsize::new(0);
+
+        let replayed = store(true).authorize_once(&repository, &key("000001"), at(5), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+        assert!(replayed.duplicate);
+        assert_eq!(replayed.decision.response_code, ResponseCodes::DoNotHonor);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_transmissions_are_authorized_once() {
+        let repository = InMemoryRepository::new();
+        let store = store(true);
+        let calls = AtomicUsize::new(0);
+
+        let same = key("000001");
+        let (first, second) = tokio::join!(
+            store.authorize_once(&repository, &same, at(0), || authorize(&calls, ResponseCodes::Approved)),
+            store.authorize_once(&repository, &same, at(0), || authorize(&calls, ResponseCodes::Approved)),
+        );
+        let other = store.authorize_once(&repository, &key("000002"), at(0), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+
+        assert_eq!([first.unwrap().duplicate, second.unwrap().duplicate, other.duplicate], [false, true, false]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(store.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn replayed_decisions_keep_their_original_window() {
+        let repository = InMemoryRepository::new();
+        repository.insert_decision(&key("000001"), &decision(ResponseCodes::DoNotHonor), at(0), at(0)).await.unwrap();
+        let store = store(true);
+        let calls = AtomicUsize::new(0);
+
+        let replayed = store.authorize_once(&repository, &key("000001"), at(290), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+        assert!(replayed.duplicate);
+
+        let expired = store.authorize_once(&repository, &key("000001"), at(400), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+        assert!(!expired.duplicate);
+        assert_eq!(expired.decision.response_code, ResponseCodes::Approved);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_is_bounded_while_entries_are_live() {
+        let repository = InMemoryRepository::new();
+        let store = store(true);
+        let calls = AtomicUsize::new(0);
+
+        for stan in 0..25 {
+            let stan = format!("{:06}", stan);
+            store.authorize_once(&repository, &key(&stan), at(stan.parse().unwrap()), || authorize(&calls, ResponseCodes::Approved)).await.unwrap();
+        }
+        let cache = store.cache.lock().unwrap();
+        assert_eq!(cache.len(), 10);
+        assert!(cache.contains_key(&key("000024")));
+        assert!(!cache.contains_key(&key("000000")));
+    }
+}
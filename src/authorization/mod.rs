@@ -0,0 +1,4 @@
+pub mod blocks;
+pub mod decision;
+pub mod fraud;
+pub mod idempotency;
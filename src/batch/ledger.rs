@@ -0,0 +1,233 @@
+use mysql_async::Conn;
+use mysql_async::prelude::Queryable;
+use mysql_common::chrono::NaiveDate;
+use mysql_common::row::Row;
+use my_own_tests_derive::FromRow;
+use serde::Serialize;
+use crate::batch::runner::BatchStep;
+use crate::data::charges_data::Shard;
+use crate::data::transaction::{tx_query_error, with_transaction, TransactionOptions};
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, ProductIdType};
+use crate::utils::{CoreError, CoreResult};
+
+// One row per (run_type, business_date, products_ID, shard_index, shard_count), enforced by a
+// unique key on batch_runs. last_accounts_ID is the keyset checkpoint a crashed run resumes from.
+// Whether a whole date is done lives in batch_run_completions, keyed on (run_type, business_date)
+// only, so changing a product's shard count doesn't reopen it. Schema in sql/batch_runs.sql
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RunStatus {
+    Running,
+    Completed,
+    Failed,
+    Interrupted,
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Completed => "completed",
+            RunStatus::Failed => "failed",
+            RunStatus::Interrupted => "interrupted",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "running" => Some(RunStatus::Running),
+            "completed" => Some(RunStatus::Completed),
+            "failed" => Some(RunStatus::Failed),
+            "interrupted" => Some(RunStatus::Interrupted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct RunKey {
+    pub run_type: BatchStep,
+    pub business_date: NaiveDate,
+    pub products_id: ProductIdType,
+    pub shard: Shard,
+}
+
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "batch_runs")]
+pub struct BatchRun {
+    #[from_row(rename = "ID")]
+    id: u64,
+    run_type: String,
+    business_date: NaiveDate,
+    #[from_row(rename = "products_ID")]
+    products_id: ProductIdType,
+    shard_index: u64,
+    shard_count: u64,
+    #[from_row(rename = "last_accounts_ID")]
+    last_accounts_id: AccountIdType,
+    status: String,
+}
+
+impl BatchRun {
+    // A fresh running row, batch_runs builds them from rows instead
+    pub fn new(id: u64, key: RunKey) -> Self {
+        BatchRun {
+            id,
+            run_type: key.run_type.as_str().to_string(),
+            business_date: key.business_date,
+            products_id: key.products_id,
+            shard_index: key.shard.index,
+            shard_count: key.shard.count,
+            last_accounts_id: 0,
+            status: RunStatus::Running.as_str().to_string(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn business_date(&self) -> NaiveDate {
+        self.business_date
+    }
+
+    pub fn products_id(&self) -> ProductIdType {
+        self.products_id
+    }
+
+    pub fn shard(&self) -> Shard {
+        Shard { index: self.shard_index, count: self.shard_count }
+    }
+
+    pub fn last_accounts_id(&self) -> AccountIdType {
+        self.last_accounts_id
+    }
+
+    // Unknown values are treated as failed so the shard is resumed rather than skipped
+    pub fn status(&self) -> RunStatus {
+        RunStatus::from_code(&self.status).unwrap_or(RunStatus::Failed)
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.status() == RunStatus::Completed
+    }
+
+    pub(crate) fn set_status(&mut self, status: RunStatus) {
+        self.status = status.as_str().to_string();
+    }
+
+    pub(crate) fn set_last_accounts_id(&mut self, last_accounts_id: AccountIdType) {
+        self.last_accounts_id = last_accounts_id;
+    }
+}
+
+const SELECT_RUN: &str = "SELECT ID, run_type, business_date, products_ID, shard_index, shard_count, last_accounts_ID, status \
+    FROM batch_runs WHERE run_type = ? AND business_date = ? AND products_ID = ? AND shard_index = ? AND shard_count = ?";
+
+pub async fn get_runs(conn: &mut Conn, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<Vec<BatchRun>> {
+    conn.exec::<Row, _, _>(
+        "SELECT ID, run_type, business_date, products_ID, shard_index, shard_count, last_accounts_ID, status \
+        FROM batch_runs WHERE run_type = ? AND business_date = ? ORDER BY products_ID, shard_index",
+        (run_type.as_str(), business_date)
+    ).await
+        .map_err(|e| CoreError::system_error(e, "batch::ledger::get_runs", SystemErrorCodes::DbQuery(13)))?
+        .iter()
+        .map(BatchRun::try_from_row)
+        .collect()
+}
+
+// The row is locked FOR UPDATE while it's claimed, so two processes starting the same shard
+// serialize here
+pub async fn start_run(key: RunKey, force: bool) -> CoreResult<BatchRun> {
+    with_transaction(&TransactionOptions::default(), move |tx| Box::pin(async move {
+        let params = (key.run_type.as_str(), key.business_date, key.products_id, key.shard.index, key.shard.count);
+        let existing = tx.exec_first::<Row, _, _>(format!("{} FOR UPDATE", SELECT_RUN), params)
+            .await
+            .map_err(|e| tx_query_error(e, "batch::ledger::start_run"))?
+            .map(|row| BatchRun::try_from_row(&row))
+            .transpose()?;
+
+        match existing {
+            Some(run) if run.is_completed() && !force => return Ok(run),
+            Some(run) => {
+                let last_accounts_id = if run.is_completed() { 0 } else { run.last_accounts_id };
+                tx.exec_drop(
+                    "UPDATE batch_runs SET status = ?, last_accounts_ID = ? WHERE ID = ?",
+                    (RunStatus::Running.as_str(), last_accounts_id, run.id)
+                ).await.map_err(|e| tx_query_error(e, "batch::ledger::start_run"))?;
+            },
+            None => {
+                tx.exec_drop(
+                    "INSERT INTO batch_runs \
+                    (run_type, business_date, products_ID, shard_index, shard_count, last_accounts_ID, status) \
+                    VALUES (?, ?, ?, ?, ?, 0, ?)",
+                    (key.run_type.as_str(), key.business_date, key.products_id, key.shard.index, key.shard.count, RunStatus::Running.as_str())
+                ).await.map_err(|e| tx_query_error(e, "batch::ledger::start_run"))?;
+            },
+        }
+
+        tx.exec_first::<Row, _, _>(SELECT_RUN, params)
+            .await
+            .map_err(|e| tx_query_error(e, "batch::ledger::start_run"))?
+            .ok_or_else(|| CoreError::system_error(
+                format!("batch_runs row for {:?} vanished", key),
+                "batch::ledger::start_run",
+                SystemErrorCodes::NoInsertId(2)
+            ))
+            .and_then(|row| BatchRun::try_from_row(&row))
+    })).await
+}
+
+// Moves the checkpoint forward. The update only matches the checkpoint we last wrote, so a second
+// process working the same shard is detected instead of both silently interleaving
+pub async fn checkpoint(conn: &mut Conn, run: &mut BatchRun, last_accounts_id: AccountIdType) -> CoreResult<()> {
+    conn.exec_drop(
+        "UPDATE batch_runs SET last_accounts_ID = ? WHERE ID = ? AND last_accounts_ID = ? AND status = ?",
+        (last_accounts_id, run.id, run.last_accounts_id, RunStatus::Running.as_str())
+    ).await.map_err(|e| CoreError::system_error(e, "batch::ledger::checkpoint", SystemErrorCodes::DbQuery(14)))?;
+
+    if conn.affected_rows() == 0 && last_accounts_id != run.last_accounts_id {
+        return Err(CoreError::system_error(
+            format!("Checkpoint of batch run {} is no longer {}", run.id, run.last_accounts_id),
+            "batch::ledger::checkpoint",
+            SystemErrorCodes::LastLogIdChanged
+        ));
+    }
+    run.set_last_accounts_id(last_accounts_id);
+    Ok(())
+}
+
+pub async fn finish_run(conn: &mut Conn, run: &mut BatchRun, status: RunStatus) -> CoreResult<()> {
+    conn.exec_drop(
+        "UPDATE batch_runs SET status = ? WHERE ID = ?",
+        (status.as_str(), run.id)
+    ).await.map_err(|e| CoreError::system_error(e, "batch::ledger::finish_run", SystemErrorCodes::DbQuery(25)))?;
+    run.set_status(status);
+    Ok(())
+}
+
+pub async fn is_date_completed(conn: &mut Conn, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<bool> {
+    conn.exec_first::<u8, _, _>(
+        "SELECT 1 FROM batch_run_completions WHERE run_type = ? AND business_date = ?",
+        (run_type.as_str(), business_date)
+    ).await
+        .map(|found| found.is_some())
+        .map_err(|e| CoreError::system_error(e, "batch::ledger::is_date_completed", SystemErrorCodes::DbQuery(26)))
+}
+
+pub async fn complete_date(conn: &mut Conn, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<()> {
+    conn.exec_drop(
+        "INSERT INTO batch_run_completions (run_type, business_date, completed_at) VALUES (?, ?, NOW()) \
+        ON DUPLICATE KEY UPDATE completed_at = VALUES(completed_at)",
+        (run_type.as_str(), business_date)
+    ).await.map_err(|e| CoreError::system_error(e, "batch::ledger::complete_date", SystemErrorCodes::DbQuery(27)))
+}
+
+// Forced reruns clear the marker so an interrupted rerun doesn't leave the date looking done
+pub async fn reopen_date(conn: &mut Conn, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<()> {
+    conn.exec_drop(
+        "DELETE FROM batch_run_completions WHERE run_type = ? AND business_date = ?",
+        (run_type.as_str(), business_date)
+    ).await.map_err(|e| CoreError::system_error(e, "batch::ledger::reopen_date", SystemErrorCodes::DbQuery(28)))
+}
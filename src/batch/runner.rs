@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use mysql_common::chrono::NaiveDate;
+use serde::Serialize;
+use tokio::sync::{RwLock, Semaphore};
+use crate::batch::ledger::{BatchRun, RunKey, RunStatus};
+use crate::config::Config;
+use crate::data::charges_data::{AccountChargesCursor, AccountFilter, Shard};
+use crate::data::queries::{AccountChargesData, FullQuery};
+use crate::data::repository::{AccountRepository, BatchRunRepository, ProductConfigRepository, StatementRepository};
+use crate::datatypes::system_datatypes::{AccountIdType, ProductIdType};
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::shutdown;
+use crate::utils::{CoreError, CoreResult};
+
+// Work done for a single account by a batch step. Errors are counted per shard and don't stop it
+pub trait AccountProcessor: Send + Sync + 'static {
+    fn process(&self, data: &AccountChargesData) -> impl Future<Output = CoreResult<()>> + Send;
+}
+
+// Each step reads its parallelism from the matching processN key of
+// products_statements_configurations.processes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum BatchStep {
+    Charges,
+    Statements,
+}
+
+impl BatchStep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatchStep::Charges => "charges",
+            BatchStep::Statements => "statements",
+        }
+    }
+
+    pub fn process_number(&self) -> u8 {
+        match self {
+            BatchStep::Charges => 2,
+            BatchStep::Statements => 3,
+        }
+    }
+
+    // Products without a configuration row or a processes entry run as a single shard
+    pub fn shard_count(&self, configuration: Option<&FullQuery>) -> u64 {
+        configuration
+            .and_then(|configuration| configuration.processes())
+            .and_then(|processes| processes.count_for(self.process_number()))
+            .unwrap_or(1)
+            .max(1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ShardStatus {
+    Pending,
+    Running,
+    Completed,
+    Interrupted,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardProgress {
+    pub products_id: ProductIdType,
+    pub shard: Shard,
+    pub processed: u64,
+    pub failed: u64,
+    pub last_accounts_id: AccountIdType,
+    pub status: ShardStatus,
+}
+
+type ProgressMap = Arc<RwLock<BTreeMap<(ProductIdType, u64), ShardProgress>>>;
+
+// Splits every product into shards and runs them as concurrent tokio tasks, each one with its
+// own keyset cursor over the shared repository. At most `max_live_shards` run at once, the rest
+// wait as Pending, so the shards never ask the pool for more connections than it can hand out
+#[derive(Clone)]
+pub struct BatchRunner {
+    page_size: usize,
+    live_shards: Arc<Semaphore>,
+    progress: ProgressMap,
+}
+
+impl BatchRunner {
+    pub fn new(page_size: usize, max_live_shards: usize) -> Self {
+        BatchRunner {
+            page_size,
+            live_shards: Arc::new(Semaphore::new(max_live_shards.max(1))),
+            progress: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    // Half the pool, the other half stays free for everything else running in the process
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.batch.page_size, config.db.pool_max / 2)
+    }
+
+    // Snapshot of every shard, safe to call while a run is in progress
+    pub async fn progress(&self) -> Vec<ShardProgress> {
+        self.progress.read().await.values().cloned().collect()
+    }
+
+    // Every shard is tracked in the batch_runs ledger for `business_date`: unfinished shards resume
+    // from their checkpoint and completed ones are skipped. Once every shard completes the date is
+    // marked done, and rerunning it is refused unless `force`, which starts every shard over
+    pub async fn run<R, P>(&self, repository: Arc<R>, step: BatchStep, business_date: NaiveDate, force: bool, processor: Arc<P>) -> CoreResult<Vec<ShardProgress>>
+    where
+        R: ProductConfigRepository + AccountRepository + StatementRepository + BatchRunRepository + Send + Sync + 'static,
+        P: AccountProcessor,
+    {
+        let configurations: HashMap<ProductIdType, FullQuery> = repository.get_product_configurations().await?
+            .into_iter()
+            .map(|configuration| (configuration.products_id(), configuration))
+            .collect();
+        // Products without a configuration row still run, as a single shard
+        let mut products = repository.get_account_products().await?;
+        products.extend(configurations.keys().copied());
+        products.sort_unstable();
+        products.dedup();
+        if force {
+            repository.reopen_date(step, business_date).await?;
+        } else if repository.is_date_completed(step, business_date).await? {
+            return Err(CoreError::system_error(
+                format!("{:?} run for {} already completed", step, business_date),
+                "batch::runner::BatchRunner::run",
+                SystemErrorCodes::RequestError
+            ));
+        }
+        self.progress.write().await.clear();
+
+        let mut handles = Vec::new();
+        for products_id in products {
+            let count = step.shard_count(configurations.get(&products_id));
+            for index in 0..count {
+                let filter = AccountFilter {
+                    products_id: Some(products_id),
+                    shard: Some(Shard { index, count }),
+                };
+                self.progress.write().await.insert(
+                    (products_id, index),
+                    ShardProgress {
+                        products_id,
+                        shard: Shard { index, count },
+                        processed: 0,
+                        failed: 0,
+                        last_accounts_id: 0,
+                        status: ShardStatus::Pending,
+                    }
+                );
+                let key = RunKey { run_type: step, business_date, products_id, shard: Shard { index, count } };
+                handles.push(tokio::spawn(run_shard(
+                    repository.clone(),
+                    self.live_shards.clone(),
+                    self.progress.clone(),
+                    key,
+                    force,
+                    filter,
+                    self.page_size,
+                    processor.clone(),
+                )));
+            }
+        }
+
+        for handle in handles {
+            if let Err(e) = handle.await {
+                println!("Batch shard task for {:?} did not finish: {}", step, e);
+            }
+        }
+
+        let progress = self.progress().await;
+        if !progress.is_empty() && progress.iter().all(|shard| shard.status == ShardStatus::Completed) {
+            repository.complete_date(step, business_date).await?;
+        }
+        Ok(progress)
+    }
+}
+
+async fn run_shard<R, P>(repository: Arc<R>, live_shards: Arc<Semaphore>, progress: ProgressMap, key: RunKey, force: bool, filter: AccountFilter, page_size: usize, processor: Arc<P>)
+where
+    R: AccountRepository + StatementRepository + BatchRunRepository,
+    P: AccountProcessor,
+{
+    let step = key.run_type;
+    let progress_key = (key.products_id, key.shard.index);
+
+    let _permit = match live_shards.acquire_owned().await {
+        Ok(permit) => permit,
+        Err(_) => return set_status(&progress, progress_key, ShardStatus::Interrupted).await,
+    };
+    let _guard = match shutdown::begin_work(format!("{:?} product {} shard {}/{}", step, key.products_id, key.shard.index, key.shard.count)) {
+        Some(guard) => guard,
+        None => return set_status(&progress, progress_key, ShardStatus::Interrupted).await,
+    };
+    let mut run = match repository.start_run(key, force).await {
+        Ok(run) if run.is_completed() => return set_status(&progress, progress_key, ShardStatus::Completed).await,
+        Ok(run) => run,
+        Err(e) => return set_status(&progress, progress_key, ShardStatus::Failed(e.to_string())).await,
+    };
+    if let Some(entry) = progress.write().await.get_mut(&progress_key) {
+        entry.last_accounts_id = run.last_accounts_id();
+        entry.status = ShardStatus::Running;
+    }
+
+    let repository = repository.as_ref();
+    let mut cursor = AccountChargesCursor::starting_after(run.last_accounts_id(), page_size).with_filter(filter);
+    loop {
+        if shutdown::is_shutting_down() {
+            return finish_shard(repository, &progress, progress_key, &mut run, ShardStatus::Interrupted).await;
+        }
+        let batch = match cursor.next_batch(repository).await {
+            Ok(Some(batch)) => batch,
+            Ok(None) => return finish_shard(repository, &progress, progress_key, &mut run, ShardStatus::Completed).await,
+            Err(e) => return finish_shard(repository, &progress, progress_key, &mut run, ShardStatus::Failed(e.to_string())).await,
+        };
+
+        let (mut processed, mut failed) = (0, 0);
+        for data in batch.iter() {
+            match processor.process(data).await {
+                Ok(()) => processed += 1,
+                Err(e) => {
+                    failed += 1;
+                    println!("{:?} failed for account {}: {}", step, data.account().id(), e);
+                }
+            }
+        }
+
+        // Someone else moved our checkpoint, stop before both runs process the same accounts. The
+        // row is marked failed so the other process stops at its next checkpoint too, and the
+        // next run resumes from whatever checkpoint was stored last
+        if let Err(e) = repository.checkpoint(&mut run, cursor.last_id()).await {
+            return finish_shard(repository, &progress, progress_key, &mut run, ShardStatus::Failed(e.to_string())).await;
+        }
+
+        // Progress is published once per page to keep lock traffic low
+        if let Some(entry) = progress.write().await.get_mut(&progress_key) {
+            entry.processed += processed;
+            entry.failed += failed;
+            entry.last_accounts_id = cursor.last_id();
+        }
+    }
+}
+
+// Records the final status in the ledger
+async fn finish_shard<R: BatchRunRepository>(repository: &R, progress: &ProgressMap, key: (ProductIdType, u64), run: &mut BatchRun, status: ShardStatus) {
+    let run_status = match status {
+        ShardStatus::Completed => RunStatus::Completed,
+        ShardStatus::Interrupted => RunStatus::Interrupted,
+        _ => RunStatus::Failed,
+    };
+    if let Err(e) = repository.finish_run(run, run_status).await {
+        println!("Failed to record batch run {} as {}: {}", run.id(), run_status.as_str(), e);
+    }
+    set_status(progress, key, status).await;
+}
+
+async fn set_status(progress: &ProgressMap, key: (ProductIdType, u64), status: ShardStatus) {
+    if let Some(entry) = progress.write().await.get_mut(&key) {
+        entry.status = status;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use super::*;
+    use crate::data::repository::InMemoryRepository;
+    use crate::datatypes::structs::Account;
+    use mysql_common::rust_decimal::Decimal;
+
+    #[derive(Default)]
+    struct Recorder {
+        seen: Mutex<Vec<AccountIdType>>,
+    }
+
+    impl AccountProcessor for Recorder {
+        async fn process(&self, data: &AccountChargesData) -> CoreResult<()> {
+            self.seen.lock().unwrap().push(data.account().id());
+            Ok(())
+        }
+    }
+
+    fn configuration(products_id: ProductIdType, statements_shards: u64) -> FullQuery {
+        serde_json::from_value(serde_json::json!({
+            "products_id": products_id,
+            "pids_json": null,
+            "processes_json": { "process2": 1, "process3": statements_shards, "process4": 1, "process5": 1 },
+            "created_at": "",
+            "updated_at": "",
+        })).unwrap()
+    }
+
+    fn repository() -> Arc<InMemoryRepository> {
+        let repository = InMemoryRepository::new();
+        for (id, products_id) in [(1, 1), (2, 1), (3, 1), (4, 2), (5, 2)] {
+            repository.insert_account(Account::new(id, id, products_id, 0, 0, 0, None, Decimal::ZERO, 0.0, 0.0, 0.0, 0.0));
+        }
+        repository.insert_configuration(configuration(1, 3));
+        Arc::new(repository)
+    }
+
+    fn business_date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 15).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unconfigured_products_run_as_one_shard() {
+        let repository = repository();
+        let processor = Arc::new(Recorder::default());
+        let progress = BatchRunner::new(2, 1)
+            .run(repository.clone(), BatchStep::Statements, business_date(), false, processor.clone())
+            .await
+            .unwrap();
+
+        let shards: Vec<(ProductIdType, u64, u64)> = progress.iter().map(|p| (p.products_id, p.shard.index, p.shard.count)).collect();
+        assert_eq!(shards, vec![(1, 0, 3), (1, 1, 3), (1, 2, 3), (2, 0, 1)]);
+        assert!(progress.iter().all(|p| p.status == ShardStatus::Completed));
+
+        let mut seen = processor.seen.lock().unwrap().clone();
+        seen.sort_unstable();
+        assert_eq!(seen, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn completed_date_is_refused_unless_forced() {
+        let repository = repository();
+        let runner = BatchRunner::new(10, 2);
+        runner.run(repository.clone(), BatchStep::Statements, business_date(), false, Arc::new(Recorder::default())).await.unwrap();
+
+        // Changing the shard count doesn't reopen the date
+        repository.insert_configuration(configuration(1, 2));
+        let refused = runner.run(repository.clone(), BatchStep::Statements, business_date(), false, Arc::new(Recorder::default())).await;
+        assert_eq!(refused.unwrap_err().system_error, SystemErrorCodes::RequestError);
+
+        let processor = Arc::new(Recorder::default());
+        runner.run(repository.clone(), BatchStep::Statements, business_date(), true, processor.clone()).await.unwrap();
+        assert_eq!(processor.seen.lock().unwrap().len(), 5);
+    }
+}
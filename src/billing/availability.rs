@@ -0,0 +1,258 @@
+use std::collections::{BTreeMap, HashMap};
+use mysql_common::chrono::NaiveDate;
+use mysql_common::rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use crate::billing::charges::coefficient_to_decimal;
+use crate::billing::waterfall::{wallets_by_priority, CurrencyConverter};
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_datatypes::{CurrenciesIdType, WalletIdType};
+use crate::utils::CoreResult;
+
+// Amounts owed per wallet in the wallet's currency; negative values are credit balances.
+// The cash_* amounts are the part of posted/pending that came from cash withdrawals
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct WalletBalance {
+    pub posted: Decimal,
+    pub pending: Decimal,
+    pub cash_posted: Decimal,
+    pub cash_pending: Decimal,
+}
+
+// Wallets share the account's credit line, so available_credit and available_cash are the account's
+// headroom expressed in the wallet's currency
+#[derive(Debug, Clone, Serialize)]
+pub struct WalletAvailability {
+    pub wallets_id: WalletIdType,
+    pub currencies_id: CurrenciesIdType,
+    pub posted: Decimal,
+    pub pending: Decimal,
+    pub cash_posted: Decimal,
+    pub cash_pending: Decimal,
+    // posted + pending converted to the base currency
+    pub used: Decimal,
+    pub available_credit: Decimal,
+    pub available_cash: Decimal,
+}
+
+// Balances of the wallets held in currencies_id, summed in that currency, and the account's
+// headroom converted to it. The base currency is always listed, even without a wallet
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CurrencyAvailability {
+    pub currencies_id: CurrenciesIdType,
+    pub posted: Decimal,
+    pub pending: Decimal,
+    pub cash_posted: Decimal,
+    pub cash_pending: Decimal,
+    pub available_credit: Decimal,
+    pub available_cash: Decimal,
+}
+
+// Totals are in base_currencies_id, the currency credit_amount is expressed in
+#[derive(Debug, Clone, Serialize)]
+pub struct Availability {
+    pub base_currencies_id: CurrenciesIdType,
+    pub credit_amount: Decimal,
+    pub used: Decimal,
+    pub available_credit: Decimal,
+    pub cash_limit: Decimal,
+    pub cash_used: Decimal,
+    pub available_cash: Decimal,
+    pub wallets: Vec<WalletAvailability>,
+    pub currencies: Vec<CurrencyAvailability>,
+}
+
+impl Account {
+    // available credit = credit_amount - (posted + pending) across all wallets
+    // cash limit       = credit_amount * withdrawal_coefficient, never above the available credit
+    pub fn availability<C: CurrencyConverter>(
+        &self,
+        base_currencies_id: CurrenciesIdType,
+        balances: &HashMap<WalletIdType, WalletBalance>,
+        converter: &C,
+        date: NaiveDate,
+    ) -> CoreResult<Availability> {
+        let mut wallets = Vec::with_capacity(self.wallets().len());
+        let mut currencies = BTreeMap::new();
+        let mut used = Decimal::ZERO;
+        let mut cash_used = Decimal::ZERO;
+
+        for wallet in wallets_by_priority(self) {
+            let balance = balances.get(&wallet.id()).copied().unwrap_or_default();
+            let wallet_used = converter.convert(balance.posted + balance.pending, wallet.currencies_id(), base_currencies_id, date)?;
+            used += wallet_used;
+            cash_used += converter.convert(balance.cash_posted + balance.cash_pending, wallet.currencies_id(), base_currencies_id, date)?;
+
+            let currency = currencies.entry(wallet.currencies_id())
+                .or_insert_with(|| CurrencyAvailability { currencies_id: wallet.currencies_id(), ..Default::default() });
+            currency.posted += balance.posted;
+            currency.pending += balance.pending;
+            currency.cash_posted += balance.cash_posted;
+            currency.cash_pending += balance.cash_pending;
+
+            wallets.push(WalletAvailability {
+                wallets_id: wallet.id(),
+                currencies_id: wallet.currencies_id(),
+                posted: balance.posted,
+                pending: balance.pending,
+                cash_posted: balance.cash_posted,
+                cash_pending: balance.cash_pending,
+                used: wallet_used,
+                available_credit: Decimal::ZERO,
+                available_cash: Decimal::ZERO,
+            });
+        }
+
+        let credit_amount = self.credit_amount();
+        let available_credit = (credit_amount - used).max(Decimal::ZERO);
+        let cash_limit = (credit_amount * coefficient_to_decimal(self.withdrawal_coefficient())?)
+            .round_dp_with_strategy(converter.decimals(base_currencies_id), RoundingStrategy::MidpointAwayFromZero);
+        let available_cash = (cash_limit - cash_used).min(available_credit).max(Decimal::ZERO);
+
+        currencies.entry(base_currencies_id)
+            .or_insert_with(|| CurrencyAvailability { currencies_id: base_currencies_id, ..Default::default() });
+        for currency in currencies.values_mut() {
+            currency.available_credit = converter.convert(available_credit, base_currencies_id, currency.currencies_id, date)?;
+            currency.available_cash = converter.convert(available_cash, base_currencies_id, currency.currencies_id, date)?;
+        }
+        for wallet in wallets.iter_mut() {
+            let currency = &currencies[&wallet.currencies_id];
+            wallet.available_credit = currency.available_credit;
+            wallet.available_cash = currency.available_cash;
+        }
+
+        Ok(Availability {
+            base_currencies_id,
+            credit_amount,
+            used,
+            available_credit,
+            cash_limit,
+            cash_used,
+            available_cash,
+            wallets,
+            currencies: currencies.into_values().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::billing::waterfall::SameCurrencyOnly;
+    use crate::datatypes::structs::Wallet;
+    use crate::datatypes::system_codes::SystemErrorCodes;
+    use crate::exchange::ExchangeStore;
+
+    // 1 unit of currency 2 is worth 2 units of currency 1
+    struct Doubling;
+
+    impl CurrencyConverter for Doubling {
+        fn convert(&self, amount: Decimal, from: CurrenciesIdType, to: CurrenciesIdType, date: NaiveDate) -> CoreResult<Decimal> {
+            match (from, to) {
+                (2, 1) => Ok(amount * Decimal::TWO),
+                (1, 2) => Ok(amount / Decimal::TWO),
+                _ => SameCurrencyOnly.convert(amount, from, to, date),
+            }
+        }
+    }
+
+    // (wallets_id, currencies_id, charge_priority)
+    fn account(credit_amount: Decimal, withdrawal_coefficient: f32, wallets: &[(WalletIdType, CurrenciesIdType, i16)]) -> Account {
+        let mut account = Account::new(7, 7, 1, 0, 0, 0, Some(15), credit_amount, 0.0, 0.0, withdrawal_coefficient, 0.0);
+        for (id, currencies_id, priority) in wallets {
+            account.wallets_mut().insert(*id, Wallet::new(*id, *currencies_id, *priority));
+        }
+        account
+    }
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 15).unwrap()
+    }
+
+    // (wallets_id, posted, pending, cash_posted, cash_pending)
+    fn balances(amounts: &[(WalletIdType, i64, i64, i64, i64)]) -> HashMap<WalletIdType, WalletBalance> {
+        amounts.iter().map(|(id, posted, pending, cash_posted, cash_pending)| (*id, WalletBalance {
+            posted: Decimal::new(*posted, 0),
+            pending: Decimal::new(*pending, 0),
+            cash_posted: Decimal::new(*cash_posted, 0),
+            cash_pending: Decimal::new(*cash_pending, 0),
+        })).collect()
+    }
+
+    fn figures(availability: &Availability) -> (Decimal, Decimal, Decimal, Decimal, Decimal) {
+        (availability.used, availability.available_credit, availability.cash_limit, availability.cash_used, availability.available_cash)
+    }
+
+    #[test]
+    fn figures_are_broken_down_per_wallet_and_currency() {
+        let account = account(Decimal::new(1000, 0), 0.3, &[(10, 1, 1), (11, 1, 2), (12, 2, 3)]);
+        let balances = balances(&[(10, 100, 50, 40, 0), (11, 50, 0, 0, 0), (12, 100, 25, 0, 10)]);
+        let availability = account.availability(1, &balances, &Doubling, date()).unwrap();
+
+        assert_eq!(figures(&availability), (
+            Decimal::new(450, 0), Decimal::new(550, 0), Decimal::new(300, 0), Decimal::new(60, 0), Decimal::new(240, 0)
+        ));
+
+        let wallets: Vec<_> = availability.wallets.iter()
+            .map(|w| (w.wallets_id, w.used, w.available_credit, w.available_cash))
+            .collect();
+        assert_eq!(wallets, vec![
+            (10, Decimal::new(150, 0), Decimal::new(550, 0), Decimal::new(240, 0)),
+            (11, Decimal::new(50, 0), Decimal::new(550, 0), Decimal::new(240, 0)),
+            (12, Decimal::new(250, 0), Decimal::new(275, 0), Decimal::new(120, 0)),
+        ]);
+
+        let currencies: Vec<_> = availability.currencies.iter()
+            .map(|c| (c.currencies_id, c.posted, c.pending, c.cash_posted, c.cash_pending, c.available_credit, c.available_cash))
+            .collect();
+        assert_eq!(currencies, vec![
+            (1, Decimal::new(150, 0), Decimal::new(50, 0), Decimal::new(40, 0), Decimal::ZERO, Decimal::new(550, 0), Decimal::new(240, 0)),
+            (2, Decimal::new(100, 0), Decimal::new(25, 0), Decimal::ZERO, Decimal::new(10, 0), Decimal::new(275, 0), Decimal::new(120, 0)),
+        ]);
+    }
+
+    #[test]
+    fn available_cash_never_exceeds_available_credit() {
+        let account = account(Decimal::new(1000, 0), 0.5, &[(10, 1, 1)]);
+
+        let near_limit = account.availability(1, &balances(&[(10, 900, 0, 0, 0)]), &SameCurrencyOnly, date()).unwrap();
+        assert_eq!((near_limit.available_credit, near_limit.available_cash), (Decimal::new(100, 0), Decimal::new(100, 0)));
+
+        let over_limit = account.availability(1, &balances(&[(10, 1100, 100, 600, 0)]), &SameCurrencyOnly, date()).unwrap();
+        assert_eq!((over_limit.available_credit, over_limit.available_cash), (Decimal::ZERO, Decimal::ZERO));
+    }
+
+    #[test]
+    fn base_currency_is_listed_without_a_wallet_in_it() {
+        let account = account(Decimal::new(1000, 0), 0.5, &[(12, 2, 1)]);
+        let availability = account.availability(1, &HashMap::new(), &Doubling, date()).unwrap();
+
+        assert_eq!(availability.used, Decimal::ZERO);
+        let currencies: Vec<_> = availability.currencies.iter()
+            .map(|c| (c.currencies_id, c.posted, c.available_credit, c.available_cash))
+            .collect();
+        assert_eq!(currencies, vec![
+            (1, Decimal::ZERO, Decimal::new(1000, 0), Decimal::new(500, 0)),
+            (2, Decimal::ZERO, Decimal::new(500, 0), Decimal::new(250, 0)),
+        ]);
+    }
+
+    #[test]
+    fn cash_limit_is_rounded_to_the_base_currency_precision() {
+        // 100.01 * 0.333 = 33.30333
+        let account = account(Decimal::new(10001, 2), 0.333, &[(10, 1, 1)]);
+        let availability = account.availability(1, &HashMap::new(), &SameCurrencyOnly, date()).unwrap();
+        assert_eq!(availability.cash_limit, Decimal::new(3330, 2));
+
+        let mut store = ExchangeStore::new();
+        store.set_decimals(1, 0);
+        let availability = account.availability(1, &HashMap::new(), &store, date()).unwrap();
+        assert_eq!(availability.cash_limit, Decimal::new(33, 0));
+    }
+
+    #[test]
+    fn foreign_wallets_need_a_converter() {
+        let account = account(Decimal::new(1000, 0), 0.5, &[(10, 1, 1), (12, 2, 2)]);
+        let error = account.availability(1, &balances(&[(12, 10, 0, 0, 0)]), &SameCurrencyOnly, date()).unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::ExchangeConfiguration);
+    }
+}
@@ -0,0 +1,156 @@
+use mysql_common::chrono::{Datelike, Duration, NaiveDate};
+use serde::Serialize;
+use crate::data::queries::AccountStatements;
+use crate::datatypes::structs::Account;
+
+// Both ends inclusive: a cycle opens the day after the previous close
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Cycle {
+    pub open: NaiveDate,
+    pub close: NaiveDate,
+}
+
+// `payment_due` and `grace_period_end` refer to the previous (last closed) cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CycleDates {
+    pub current: Cycle,
+    pub previous: Cycle,
+    pub payment_due: NaiveDate,
+    pub grace_period_end: NaiveDate,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StatementCalendar {
+    pub payment_due_days: i64,
+    pub grace_period_days: i64,
+}
+
+impl Default for StatementCalendar {
+    fn default() -> Self {
+        StatementCalendar { payment_due_days: 20, grace_period_days: 25 }
+    }
+}
+
+impl StatementCalendar {
+    pub fn new(payment_due_days: i64, grace_period_days: i64) -> Self {
+        StatementCalendar { payment_due_days, grace_period_days }
+    }
+
+    pub fn payment_due_for(&self, close: NaiveDate) -> NaiveDate {
+        close + Duration::days(self.payment_due_days)
+    }
+
+    pub fn grace_period_end_for(&self, close: NaiveDate) -> NaiveDate {
+        close + Duration::days(self.grace_period_days)
+    }
+
+    // None when the account has no credit line (statement_day is None).
+    // statement_day 29-31 closes on the last day of shorter months. When the last statement
+    // was closed on a different day than the calendar says (late run, day change) its
+    // balances_date wins, and if statements were missed the previous cycle stretches back to it
+    pub fn cycle_dates(&self, account: &Account, reference: NaiveDate, last_statement: Option<&AccountStatements>) -> Option<CycleDates> {
+        let statement_day = account.statement_day()?;
+        self.cycle_dates_for_day(statement_day, reference, last_statement.map(|s| s.balances_date()))
+    }
+
+    pub fn cycle_dates_for_day(&self, statement_day: u8, reference: NaiveDate, last_balances_date: Option<NaiveDate>) -> Option<CycleDates> {
+        let statement_day = statement_day.clamp(1, 31) as u32;
+
+        let mut current_close = close_date(reference.year(), reference.month(), statement_day)?;
+        if reference > current_close {
+            let (year, month) = next_month(reference.year(), reference.month());
+            current_close = close_date(year, month, statement_day)?;
+        }
+
+        let mut previous_close = close_before(current_close, statement_day)?;
+        let mut previous_open = close_before(previous_close, statement_day)? + Duration::days(1);
+
+        if let Some(last) = last_balances_date.filter(|last| *last < reference) {
+            if last > previous_close && last < current_close {
+                previous_close = last;
+            } else if last < previous_close {
+                previous_open = last + Duration::days(1);
+            }
+        }
+
+        Some(CycleDates {
+            current: Cycle { open: previous_close + Duration::days(1), close: current_close },
+            previous: Cycle { open: previous_open, close: previous_close },
+            payment_due: self.payment_due_for(previous_close),
+            grace_period_end: self.grace_period_end_for(previous_close),
+        })
+    }
+}
+
+pub fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let (next_year, next_month) = next_month(year, month);
+    let first_of_next = NaiveDate::from_ymd_opt(next_year, next_month, 1)?;
+    Some((first_of_next - Duration::days(1)).day())
+}
+
+fn close_date(year: i32, month: u32, statement_day: u32) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(year, month, statement_day.min(days_in_month(year, month)?))
+}
+
+// Close date of the month before the one `close` falls in
+fn close_before(close: NaiveDate, statement_day: u32) -> Option<NaiveDate> {
+    let (year, month) = if close.month() == 1 { (close.year() - 1, 12) } else { (close.year(), close.month() - 1) };
+    close_date(year, month, statement_day)
+}
+
+fn next_month(year: i32, month: u32) -> (i32, u32) {
+    if month == 12 { (year + 1, 1) } else { (year, month + 1) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    fn cycle(open: &str, close: &str) -> Cycle {
+        Cycle { open: d(open), close: d(close) }
+    }
+
+    #[test]
+    fn cycle_dates_table() {
+        // (case, statement_day, reference, last balances_date, current, previous)
+        let cases = [
+            ("day 31 closes on feb 28", 31, "2023-02-01", None, cycle("2023-02-01", "2023-02-28"), cycle("2023-01-01", "2023-01-31")),
+            ("day 31 closes on feb 29 in a leap year", 31, "2024-02-01", None, cycle("2024-02-01", "2024-02-29"), cycle("2024-01-01", "2024-01-31")),
+            ("day 31 after a short february", 31, "2023-03-01", None, cycle("2023-03-01", "2023-03-31"), cycle("2023-02-01", "2023-02-28")),
+            ("day 30 in february", 30, "2023-02-15", None, cycle("2023-01-31", "2023-02-28"), cycle("2022-12-31", "2023-01-30")),
+            ("day 30 after february", 30, "2023-03-01", None, cycle("2023-03-01", "2023-03-30"), cycle("2023-01-31", "2023-02-28")),
+            ("december rolls over into january", 15, "2023-12-20", None, cycle("2023-12-16", "2024-01-15"), cycle("2023-11-16", "2023-12-15")),
+            ("january looks back into december", 10, "2024-01-05", None, cycle("2023-12-11", "2024-01-10"), cycle("2023-11-11", "2023-12-10")),
+            ("reference on the close day", 15, "2024-03-15", None, cycle("2024-02-16", "2024-03-15"), cycle("2024-01-16", "2024-02-15")),
+            ("late close anchors the previous cycle", 15, "2024-03-10", Some("2024-02-17"), cycle("2024-02-18", "2024-03-15"), cycle("2024-01-16", "2024-02-17")),
+            ("missed statements stretch the previous cycle", 15, "2024-03-10", Some("2023-12-15"), cycle("2024-02-16", "2024-03-15"), cycle("2023-12-16", "2024-02-15")),
+            ("statement after the reference is ignored", 15, "2024-03-10", Some("2024-03-12"), cycle("2024-02-16", "2024-03-15"), cycle("2024-01-16", "2024-02-15")),
+        ];
+
+        let calendar = StatementCalendar::default();
+        for (case, statement_day, reference, last, current, previous) in cases {
+            let dates = calendar.cycle_dates_for_day(statement_day, d(reference), last.map(d)).unwrap();
+            assert_eq!(dates.current, current, "{}", case);
+            assert_eq!(dates.previous, previous, "{}", case);
+        }
+    }
+
+    #[test]
+    fn payment_dates_follow_the_anchored_close() {
+        let dates = StatementCalendar::new(20, 25).cycle_dates_for_day(15, d("2024-03-10"), Some(d("2024-02-17"))).unwrap();
+        assert_eq!(dates.payment_due, d("2024-03-08"));
+        assert_eq!(dates.grace_period_end, d("2024-03-13"));
+    }
+
+    #[test]
+    fn days_in_month_handles_leap_years_and_december() {
+        assert_eq!(days_in_month(2023, 2), Some(28));
+        assert_eq!(days_in_month(2024, 2), Some(29));
+        assert_eq!(days_in_month(2023, 12), Some(31));
+        assert_eq!(days_in_month(2023, 4), Some(30));
+    }
+}
@@ -0,0 +1,196 @@
+use mysql_common::chrono::NaiveDate;
+use mysql_common::rust_decimal::{Decimal, RoundingStrategy};
+use mysql_common::rust_decimal::prelude::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use crate::billing::calendar::{Cycle, StatementCalendar};
+use crate::data::queries::AccountChargesData;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::AccountIdType;
+use crate::utils::{CoreError, CoreResult};
+
+const CHARGES_DECIMALS: u32 = 2;
+
+// Cycle activity the engine needs but that doesn't live on Account; amounts in the account currency.
+// `payments` only counts what was received up to the previous cycle's grace_period_end.
+// `refunds` reverse purchases, so they reduce the purchase base and never the revolving one
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct CycleBalances {
+    pub previous_statement_balance: Decimal,
+    pub payments: Decimal,
+    pub purchases: Decimal,
+    pub refunds: Decimal,
+    pub cash_advances: Decimal,
+}
+
+// What the interest was charged on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChargeKind {
+    Revolving,
+    Purchase,
+    CashAdvance,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChargeItem {
+    pub kind: ChargeKind,
+    pub base: Decimal,
+    pub rate: Decimal,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChargesResult {
+    pub accounts_id: AccountIdType,
+    pub cycle: Cycle,
+    pub grace_period_applied: bool,
+    pub items: Vec<ChargeItem>,
+    pub total: Decimal,
+}
+
+// Coefficients are per-cycle rates:
+//   future_balance_coefficient  on the unpaid previous statement balance (revolving)
+//   grace_period_coefficient    on new purchases, only when the previous balance wasn't paid in full
+//   cash_advance_coefficient    on cash advances, which never get a grace period
+// Accounts without a credit line (no statement_day or credit_amount <= 0) produce no charges
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChargesEngine {
+    calendar: StatementCalendar,
+}
+
+impl ChargesEngine {
+    pub fn new(calendar: StatementCalendar) -> Self {
+        ChargesEngine { calendar }
+    }
+
+    pub fn compute(&self, data: &AccountChargesData, reference: NaiveDate, balances: &CycleBalances) -> CoreResult<Option<ChargesResult>> {
+        let account = data.account();
+        if account.credit_amount() <= Decimal::ZERO {
+            return Ok(None);
+        }
+        let cycle_dates = match self.calendar.cycle_dates(account, reference, data.account_statement()) {
+            Some(cycle_dates) => cycle_dates,
+            None => return Ok(None),
+        };
+
+        let revolving_base = (balances.previous_statement_balance - balances.payments).max(Decimal::ZERO);
+        let grace_period_applied = revolving_base.is_zero();
+
+        let mut items = Vec::with_capacity(3);
+        push_charge(&mut items, ChargeKind::Revolving, revolving_base, account.future_balance_coefficient())?;
+        if !grace_period_applied {
+            push_charge(&mut items, ChargeKind::Purchase, balances.purchases - balances.refunds, account.grace_period_coefficient())?;
+        }
+        push_charge(&mut items, ChargeKind::CashAdvance, balances.cash_advances, account.cash_advance_coefficient())?;
+
+        let total = items.iter().map(|item| item.amount).sum();
+
+        Ok(Some(ChargesResult {
+            accounts_id: account.id(),
+            cycle: cycle_dates.current,
+            grace_period_applied,
+            items,
+            total,
+        }))
+    }
+}
+
+pub fn coefficient_to_decimal(coefficient: f32) -> CoreResult<Decimal> {
+    Decimal::from_f32(coefficient)
+        .ok_or_else(|| CoreError::system_error(
+            format!("Cannot convert coefficient {} to decimal", coefficient),
+            "billing::charges::coefficient_to_decimal",
+            SystemErrorCodes::DecimalToF64
+        ))
+}
+
+fn push_charge(items: &mut Vec<ChargeItem>, kind: ChargeKind, base: Decimal, coefficient: f32) -> CoreResult<()> {
+    if base <= Decimal::ZERO {
+        return Ok(());
+    }
+    let rate = coefficient_to_decimal(coefficient)?;
+    let amount = (base * rate).round_dp_with_strategy(CHARGES_DECIMALS, RoundingStrategy::MidpointAwayFromZero);
+    if !amount.is_zero() {
+        items.push(ChargeItem { kind, base, rate, amount });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::queries::AccountStatements;
+    use crate::datatypes::structs::Account;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn data(credit_amount: i64, grace_period_coefficient: f32) -> AccountChargesData {
+        let account = Account::new(1, 1, 1, 0, 0, 0, Some(15), Decimal::new(credit_amount, 0), 0.02, grace_period_coefficient, 0.5, 0.03);
+        AccountChargesData::new(account, Some(AccountStatements::new(1, date(2024, 2, 15))))
+    }
+
+    fn balances(previous_statement_balance: i64, payments: i64, purchases: i64, refunds: i64, cash_advances: i64) -> CycleBalances {
+        CycleBalances {
+            previous_statement_balance: Decimal::new(previous_statement_balance, 0),
+            payments: Decimal::new(payments, 0),
+            purchases: Decimal::new(purchases, 0),
+            refunds: Decimal::new(refunds, 0),
+            cash_advances: Decimal::new(cash_advances, 0),
+        }
+    }
+
+    fn items(result: &ChargesResult) -> Vec<(ChargeKind, Decimal)> {
+        result.items.iter().map(|item| (item.kind, item.amount)).collect()
+    }
+
+    #[test]
+    fn paying_the_previous_balance_keeps_the_grace_period() {
+        let result = ChargesEngine::default().compute(&data(1000, 0.01), date(2024, 3, 15), &balances(200, 200, 100, 0, 50)).unwrap().unwrap();
+
+        assert!(result.grace_period_applied);
+        assert_eq!(items(&result), vec![(ChargeKind::CashAdvance, Decimal::new(150, 2))]);
+        assert_eq!(result.total, Decimal::new(150, 2));
+    }
+
+    #[test]
+    fn an_unpaid_balance_loses_the_grace_period() {
+        let result = ChargesEngine::default().compute(&data(1000, 0.01), date(2024, 3, 15), &balances(200, 50, 100, 0, 50)).unwrap().unwrap();
+
+        assert!(!result.grace_period_applied);
+        assert_eq!(items(&result), vec![
+            (ChargeKind::Revolving, Decimal::new(300, 2)),
+            (ChargeKind::Purchase, Decimal::new(100, 2)),
+            (ChargeKind::CashAdvance, Decimal::new(150, 2)),
+        ]);
+        assert_eq!(result.total, Decimal::new(550, 2));
+    }
+
+    #[test]
+    fn refunds_larger_than_purchases_charge_nothing_on_purchases() {
+        let result = ChargesEngine::default().compute(&data(1000, 0.01), date(2024, 3, 15), &balances(200, 50, 100, 120, 0)).unwrap().unwrap();
+
+        assert_eq!(items(&result), vec![(ChargeKind::Revolving, Decimal::new(300, 2))]);
+    }
+
+    #[test]
+    fn accounts_without_a_credit_line_have_no_charges() {
+        let result = ChargesEngine::default().compute(&data(0, 0.01), date(2024, 3, 15), &balances(200, 0, 100, 0, 50)).unwrap();
+
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn amounts_are_rounded_to_cents() {
+        // 33.30 * 0.015 = 0.4995
+        let mut cycle = balances(200, 0, 0, 0, 0);
+        cycle.previous_statement_balance = Decimal::new(3330, 2);
+        cycle.purchases = Decimal::new(3330, 2);
+        let result = ChargesEngine::default().compute(&data(1000, 0.015), date(2024, 3, 15), &cycle).unwrap().unwrap();
+
+        assert_eq!(items(&result), vec![
+            (ChargeKind::Revolving, Decimal::new(67, 2)),
+            (ChargeKind::Purchase, Decimal::new(50, 2)),
+        ]);
+    }
+}
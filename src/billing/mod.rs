@@ -0,0 +1,5 @@
+pub mod availability;
+pub mod calendar;
+pub mod charges;
+pub mod statements;
+pub mod waterfall;
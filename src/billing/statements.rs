@@ -0,0 +1,494 @@
+use std::sync::Arc;
+use mysql_async::{Params, Transaction, Value};
+use mysql_async::prelude::Queryable;
+use mysql_common::chrono::NaiveDate;
+use mysql_common::row::Row;
+use mysql_common::rust_decimal::{Decimal, RoundingStrategy};
+use my_own_tests_derive::FromRow;
+use serde::Serialize;
+use crate::batch::runner::AccountProcessor;
+use crate::billing::calendar::{Cycle, StatementCalendar};
+use crate::billing::charges::{ChargeKind, ChargesEngine, CycleBalances};
+use crate::data::charges_data::AccountChargesCursor;
+use crate::data::queries::AccountChargesData;
+use crate::data::repository::{AccountRepository, StatementRepository};
+use crate::data::transaction::{is_duplicate_key, tx_query_error, tx_query_error_as, with_transaction, TransactionOptions};
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, WalletIdType};
+use crate::utils::shutdown;
+use crate::utils::{CoreError, CoreResult};
+
+const STATEMENT_DECIMALS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TransactionKind {
+    Purchase,
+    CashAdvance,
+    Payment,
+    Refund,
+    Fee,
+}
+
+impl TransactionKind {
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "purchase" => Some(TransactionKind::Purchase),
+            "cash_advance" => Some(TransactionKind::CashAdvance),
+            "payment" => Some(TransactionKind::Payment),
+            "refund" => Some(TransactionKind::Refund),
+            "fee" => Some(TransactionKind::Fee),
+            _ => None,
+        }
+    }
+
+    // Payments and refunds reduce what the cardholder owes
+    pub fn is_credit(&self) -> bool {
+        matches!(self, TransactionKind::Payment | TransactionKind::Refund)
+    }
+}
+
+// Amounts are always positive, the sign comes from the kind
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "account_transactions")]
+pub struct StatementTransaction {
+    #[from_row(rename = "ID")]
+    pub id: u64,
+    #[from_row(rename = "wallets_ID")]
+    pub wallets_id: WalletIdType,
+    pub posted_date: NaiveDate,
+    pub kind: String,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum StatementLineKind {
+    Transaction(TransactionKind),
+    Charge(ChargeKind),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementLine {
+    pub kind: StatementLineKind,
+    pub transactions_id: Option<u64>,
+    pub posted_date: NaiveDate,
+    // Signed: positive increases the balance owed
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Statement {
+    pub accounts_id: AccountIdType,
+    pub cycle_open: NaiveDate,
+    pub balances_date: NaiveDate,
+    pub opening_balance: Decimal,
+    pub purchases: Decimal,
+    pub cash_advances: Decimal,
+    pub fees: Decimal,
+    pub payments: Decimal,
+    pub refunds: Decimal,
+    pub charges: Decimal,
+    pub closing_balance: Decimal,
+    pub minimum_payment: Decimal,
+    pub payment_due: NaiveDate,
+    pub lines: Vec<StatementLine>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StatementJobSummary {
+    pub created: u64,
+    pub already_existing: u64,
+    pub not_due: u64,
+    pub failed: u64,
+    pub last_accounts_id: AccountIdType,
+}
+
+// minimum payment = closing balance * minimum_payment_rate, at least minimum_payment_floor,
+// never more than the closing balance
+#[derive(Debug, Clone, Copy)]
+pub struct StatementBuilder {
+    calendar: StatementCalendar,
+    charges: ChargesEngine,
+    minimum_payment_rate: Decimal,
+    minimum_payment_floor: Decimal,
+}
+
+impl Default for StatementBuilder {
+    fn default() -> Self {
+        Self::new(StatementCalendar::default(), Decimal::new(5, 2), Decimal::new(10, 0))
+    }
+}
+
+impl StatementBuilder {
+    pub fn new(calendar: StatementCalendar, minimum_payment_rate: Decimal, minimum_payment_floor: Decimal) -> Self {
+        StatementBuilder {
+            calendar,
+            charges: ChargesEngine::new(calendar),
+            minimum_payment_rate,
+            minimum_payment_floor,
+        }
+    }
+
+    // Closes the cycle ending on `close_date`. None when the account has no credit line or its
+    // cycle doesn't close that day
+    pub fn build(
+        &self,
+        data: &AccountChargesData,
+        close_date: NaiveDate,
+        opening_balance: Decimal,
+        transactions: &[StatementTransaction],
+    ) -> CoreResult<Option<Statement>> {
+        let cycle_dates = match self.calendar.cycle_dates(data.account(), close_date, data.account_statement()) {
+            Some(cycle_dates) if cycle_dates.current.close == close_date => cycle_dates,
+            _ => return Ok(None),
+        };
+        let cycle = cycle_dates.current;
+
+        let mut lines = Vec::with_capacity(transactions.len() + 3);
+        let (mut purchases, mut cash_advances, mut fees) = (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO);
+        let (mut payments, mut refunds) = (Decimal::ZERO, Decimal::ZERO);
+        // Late payments still lower the closing balance, but not the revolving interest base
+        let mut on_time_payments = Decimal::ZERO;
+        for transaction in transactions {
+            let kind = TransactionKind::from_code(&transaction.kind)
+                .ok_or_else(|| CoreError::system_error(
+                    format!("Unknown kind {} for transaction {}", transaction.kind, transaction.id),
+                    "billing::statements::StatementBuilder::build",
+                    SystemErrorCodes::UnknownOperation
+                ))?;
+            match kind {
+                TransactionKind::Purchase => purchases += transaction.amount,
+                TransactionKind::CashAdvance => cash_advances += transaction.amount,
+                TransactionKind::Fee => fees += transaction.amount,
+                TransactionKind::Payment => {
+                    payments += transaction.amount;
+                    if transaction.posted_date <= cycle_dates.grace_period_end {
+                        on_time_payments += transaction.amount;
+                    }
+                },
+                TransactionKind::Refund => refunds += transaction.amount,
+            }
+            lines.push(StatementLine {
+                kind: StatementLineKind::Transaction(kind),
+                transactions_id: Some(transaction.id),
+                posted_date: transaction.posted_date,
+                amount: if kind.is_credit() { -transaction.amount } else { transaction.amount },
+            });
+        }
+
+        let balances = CycleBalances {
+            previous_statement_balance: opening_balance,
+            payments: on_time_payments,
+            purchases,
+            refunds,
+            cash_advances,
+        };
+        let charges = self.charges.compute(data, close_date, &balances)?;
+        let charges_total = charges.as_ref().map(|c| c.total).unwrap_or_default();
+        for item in charges.iter().flat_map(|c| c.items.iter()) {
+            lines.push(StatementLine {
+                kind: StatementLineKind::Charge(item.kind),
+                transactions_id: None,
+                posted_date: close_date,
+                amount: item.amount,
+            });
+        }
+
+        let closing_balance = opening_balance + purchases + cash_advances + fees - payments - refunds + charges_total;
+
+        Ok(Some(Statement {
+            accounts_id: data.account().id(),
+            cycle_open: cycle.open,
+            balances_date: close_date,
+            opening_balance,
+            purchases,
+            cash_advances,
+            fees,
+            payments,
+            refunds,
+            charges: charges_total,
+            closing_balance,
+            minimum_payment: self.minimum_payment(closing_balance),
+            payment_due: self.calendar.payment_due_for(close_date),
+            lines,
+        }))
+    }
+
+    fn minimum_payment(&self, closing_balance: Decimal) -> Decimal {
+        if closing_balance <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+        (closing_balance * self.minimum_payment_rate)
+            .round_dp_with_strategy(STATEMENT_DECIMALS, RoundingStrategy::AwayFromZero)
+            .max(self.minimum_payment_floor)
+            .min(closing_balance)
+    }
+}
+
+// Closing balance of the last statement before `close_date`, zero for a first statement
+pub async fn get_opening_balance(tx: &mut Transaction<'_>, accounts_id: AccountIdType, close_date: NaiveDate) -> CoreResult<Decimal> {
+    tx.exec_first::<Option<Decimal>, _, _>(
+        "SELECT closing_balance FROM account_statements \
+        WHERE accounts_id = ? AND balances_date < ? ORDER BY balances_date DESC, ID DESC LIMIT 1 LOCK IN SHARE MODE",
+        (accounts_id, close_date)
+    ).await
+        .map(|balance| balance.flatten().unwrap_or_default())
+        .map_err(|e| tx_query_error_as(e, "billing::statements::get_opening_balance", SystemErrorCodes::DbQuery(11)))
+}
+
+pub async fn get_cycle_transactions(tx: &mut Transaction<'_>, accounts_id: AccountIdType, open: NaiveDate, close: NaiveDate) -> CoreResult<Vec<StatementTransaction>> {
+    tx.exec::<Row, _, _>(
+        "SELECT ID, wallets_ID, posted_date, kind, amount FROM account_transactions \
+        WHERE accounts_ID = ? AND posted_date BETWEEN ? AND ? ORDER BY posted_date, ID LOCK IN SHARE MODE",
+        (accounts_id, open, close)
+    ).await
+        .map_err(|e| tx_query_error_as(e, "billing::statements::get_cycle_transactions", SystemErrorCodes::DbQuery(12)))?
+        .iter()
+        .map(StatementTransaction::try_from_row)
+        .collect()
+}
+
+async fn statement_exists(tx: &mut Transaction<'_>, accounts_id: AccountIdType, balances_date: NaiveDate) -> CoreResult<bool> {
+    tx.exec_first::<u64, _, _>(
+        "SELECT ID FROM account_statements WHERE accounts_id = ? AND balances_date = ? FOR UPDATE",
+        (accounts_id, balances_date)
+    ).await
+        .map(|id| id.is_some())
+        .map_err(|e| tx_query_error_as(e, "billing::statements::statement_exists", SystemErrorCodes::DbQuery(24)))
+}
+
+// Writes header and lines inside the caller's transaction. account_statements has a unique key
+// on (accounts_id, balances_date) (see sql/statements.sql), a duplicate means the cycle was
+// already closed and returns false. Any other error is reported
+pub async fn persist_statement(tx: &mut Transaction<'_>, statement: &Statement) -> CoreResult<bool> {
+    let inserted = tx.exec_drop(
+        "INSERT INTO account_statements \
+        (accounts_id, balances_date, cycle_open, opening_balance, purchases, cash_advances, fees, payments, \
+        refunds, charges, closing_balance, minimum_payment, payment_due) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        Params::Positional(vec![
+            Value::from(statement.accounts_id),
+            Value::from(statement.balances_date),
+            Value::from(statement.cycle_open),
+            Value::from(statement.opening_balance),
+            Value::from(statement.purchases),
+            Value::from(statement.cash_advances),
+            Value::from(statement.fees),
+            Value::from(statement.payments),
+            Value::from(statement.refunds),
+            Value::from(statement.charges),
+            Value::from(statement.closing_balance),
+            Value::from(statement.minimum_payment),
+            Value::from(statement.payment_due),
+        ])
+    ).await;
+    match inserted {
+        Ok(()) => {},
+        Err(e) if is_duplicate_key(&e) => return Ok(false),
+        Err(e) => return Err(tx_query_error(e, "billing::statements::persist_statement")),
+    }
+
+    let statements_id = tx.last_insert_id()
+        .ok_or_else(|| CoreError::system_error(
+            format!("No insert id for statement of account {} at {}", statement.accounts_id, statement.balances_date),
+            "billing::statements::persist_statement",
+            SystemErrorCodes::NoInsertId(1)
+        ))?;
+
+    tx.exec_batch(
+        "INSERT INTO account_statement_lines \
+        (account_statements_ID, line_kind, transactions_ID, posted_date, amount) VALUES (?, ?, ?, ?, ?)",
+        statement.lines.iter().map(|line| Params::Positional(vec![
+            Value::from(statements_id),
+            Value::from(line_kind_code(&line.kind)),
+            Value::from(line.transactions_id),
+            Value::from(line.posted_date),
+            Value::from(line.amount),
+        ]))
+    ).await.map_err(|e| tx_query_error(e, "billing::statements::persist_statement"))?;
+
+    Ok(true)
+}
+
+// MySQL side of StatementRepository::close_statement. The existing-statement check, the opening
+// balance, the cycle's transactions and the insert share one transaction, so the statement
+// matches what was read
+pub async fn close_cycle<F>(accounts_id: AccountIdType, cycle: Cycle, build: F) -> CoreResult<Option<bool>>
+where
+    F: Fn(Decimal, &[StatementTransaction]) -> CoreResult<Option<Statement>> + Send + Sync + 'static,
+{
+    let build = Arc::new(build);
+    with_transaction(&TransactionOptions::default(), move |tx| {
+        let build = build.clone();
+        Box::pin(async move {
+            if statement_exists(tx, accounts_id, cycle.close).await? {
+                return Ok(Some(false));
+            }
+            let opening_balance = get_opening_balance(tx, accounts_id, cycle.close).await?;
+            let transactions = get_cycle_transactions(tx, accounts_id, cycle.open, cycle.close).await?;
+
+            match build(opening_balance, &transactions)? {
+                Some(statement) => persist_statement(tx, &statement).await.map(Some),
+                None => Ok(None),
+            }
+        })
+    }).await
+}
+
+// Closes every account whose cycle ends on `close_date`
+pub async fn run_statement_job<R>(repository: &R, builder: &StatementBuilder, close_date: NaiveDate, batch_size: usize) -> CoreResult<StatementJobSummary>
+where
+    R: AccountRepository + StatementRepository,
+{
+    let mut summary = StatementJobSummary::default();
+    let mut cursor = AccountChargesCursor::new(batch_size);
+
+    while let Some(batch) = cursor.next_batch(repository).await? {
+        for data in batch {
+            let _guard = match shutdown::begin_work(format!("statement for account {}", data.account().id())) {
+                Some(guard) => guard,
+                None => return Ok(summary),
+            };
+            summary.last_accounts_id = data.account().id();
+            match close_account_statement(repository, builder, &data, close_date).await {
+                Ok(Some(true)) => summary.created += 1,
+                Ok(Some(false)) => summary.already_existing += 1,
+                Ok(None) => summary.not_due += 1,
+                Err(e) => {
+                    summary.failed += 1;
+                    println!("Failed to close statement for account {}: {}", data.account().id(), e);
+                }
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+// Statement step for BatchRunner, closes every account whose cycle ends on `close_date`
+#[derive(Debug)]
+pub struct StatementProcessor<R> {
+    pub repository: Arc<R>,
+    pub builder: StatementBuilder,
+    pub close_date: NaiveDate,
+}
+
+impl<R: StatementRepository + Send + Sync + 'static> AccountProcessor for StatementProcessor<R> {
+    async fn process(&self, data: &AccountChargesData) -> CoreResult<()> {
+        close_account_statement(self.repository.as_ref(), &self.builder, data, self.close_date).await.map(|_| ())
+    }
+}
+
+// Some(created) when the account's cycle closes on `close_date`
+pub async fn close_account_statement<R: StatementRepository>(repository: &R, builder: &StatementBuilder, data: &AccountChargesData, close_date: NaiveDate) -> CoreResult<Option<bool>> {
+    let cycle = match builder.calendar.cycle_dates(data.account(), close_date, data.account_statement()) {
+        Some(cycle_dates) if cycle_dates.current.close == close_date => cycle_dates.current,
+        _ => return Ok(None),
+    };
+    let builder = *builder;
+    let data = data.clone();
+    repository.close_statement(data.account().id(), cycle, move |opening_balance, transactions| {
+        builder.build(&data, close_date, opening_balance, transactions)
+    }).await
+}
+
+fn line_kind_code(kind: &StatementLineKind) -> &'static str {
+    match kind {
+        StatementLineKind::Transaction(TransactionKind::Purchase) => "purchase",
+        StatementLineKind::Transaction(TransactionKind::CashAdvance) => "cash_advance",
+        StatementLineKind::Transaction(TransactionKind::Payment) => "payment",
+        StatementLineKind::Transaction(TransactionKind::Refund) => "refund",
+        StatementLineKind::Transaction(TransactionKind::Fee) => "fee",
+        StatementLineKind::Charge(ChargeKind::Revolving) => "revolving_interest",
+        StatementLineKind::Charge(ChargeKind::Purchase) => "purchase_interest",
+        StatementLineKind::Charge(ChargeKind::CashAdvance) => "cash_advance_interest",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::queries::AccountStatements;
+    use crate::datatypes::structs::Account;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    // Closes on the 15th, last statement 2024-02-15 so its grace period ends 2024-03-11
+    fn data() -> AccountChargesData {
+        let account = Account::new(1, 1, 1, 0, 0, 0, Some(15), Decimal::new(1000, 0), 0.02, 0.01, 0.5, 0.03);
+        AccountChargesData::new(account, Some(AccountStatements::new(1, date(2024, 2, 15))))
+    }
+
+    fn transaction(id: u64, kind: &str, posted_date: NaiveDate, amount: i64) -> StatementTransaction {
+        StatementTransaction { id, wallets_id: 1, posted_date, kind: kind.to_string(), amount: Decimal::new(amount, 0) }
+    }
+
+    fn transactions(payment_date: NaiveDate) -> Vec<StatementTransaction> {
+        vec![
+            transaction(1, "payment", payment_date, 200),
+            transaction(2, "purchase", date(2024, 2, 20), 100),
+            transaction(3, "refund", date(2024, 2, 25), 30),
+            transaction(4, "cash_advance", date(2024, 3, 2), 50),
+            transaction(5, "fee", date(2024, 3, 5), 5),
+        ]
+    }
+
+    fn charge_lines(statement: &Statement) -> Vec<(StatementLineKind, Decimal)> {
+        statement.lines.iter()
+            .filter(|line| line.transactions_id.is_none())
+            .map(|line| (line.kind, line.amount))
+            .collect()
+    }
+
+    #[test]
+    fn build_totals_the_cycle_and_keeps_the_grace_period() {
+        let close = date(2024, 3, 15);
+        let statement = StatementBuilder::default().build(&data(), close, Decimal::new(200, 0), &transactions(date(2024, 3, 1))).unwrap().unwrap();
+
+        assert_eq!(statement.cycle_open, date(2024, 2, 16));
+        assert_eq!((statement.purchases, statement.refunds, statement.payments), (Decimal::new(100, 0), Decimal::new(30, 0), Decimal::new(200, 0)));
+        assert_eq!(statement.charges, Decimal::new(150, 2));
+        assert_eq!(statement.closing_balance, Decimal::new(12650, 2));
+        assert_eq!(statement.minimum_payment, Decimal::new(10, 0));
+        assert_eq!(statement.payment_due, date(2024, 4, 4));
+        assert_eq!(charge_lines(&statement), vec![(StatementLineKind::Charge(ChargeKind::CashAdvance), Decimal::new(150, 2))]);
+
+        // Credits are negative lines
+        let amounts: Vec<Decimal> = statement.lines.iter().take(5).map(|line| line.amount).collect();
+        assert_eq!(amounts, [-200, 100, -30, 50, 5].map(|amount| Decimal::new(amount, 0)));
+    }
+
+    #[test]
+    fn late_payment_does_not_keep_the_grace_period() {
+        let close = date(2024, 3, 15);
+        let statement = StatementBuilder::default().build(&data(), close, Decimal::new(200, 0), &transactions(date(2024, 3, 13))).unwrap().unwrap();
+
+        assert_eq!(statement.payments, Decimal::new(200, 0));
+        assert_eq!(charge_lines(&statement), vec![
+            (StatementLineKind::Charge(ChargeKind::Revolving), Decimal::new(400, 2)),
+            (StatementLineKind::Charge(ChargeKind::Purchase), Decimal::new(70, 2)),
+            (StatementLineKind::Charge(ChargeKind::CashAdvance), Decimal::new(150, 2)),
+        ]);
+        assert_eq!(statement.closing_balance, Decimal::new(13120, 2));
+    }
+
+    #[test]
+    fn build_skips_days_that_do_not_close_the_cycle() {
+        let statement = StatementBuilder::default().build(&data(), date(2024, 3, 14), Decimal::ZERO, &[]).unwrap();
+        assert!(statement.is_none());
+
+        let unknown = [transaction(1, "chargeback", date(2024, 3, 1), 10)];
+        let error = StatementBuilder::default().build(&data(), date(2024, 3, 15), Decimal::ZERO, &unknown).unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::UnknownOperation);
+    }
+
+    #[test]
+    fn minimum_payment_applies_floor_and_cap() {
+        let builder = StatementBuilder::default();
+        let cases = [(100_000, 5_000), (33_333, 1_667), (10_000, 1_000), (600, 600), (0, 0), (-500, 0)];
+        for (closing, expected) in cases {
+            assert_eq!(builder.minimum_payment(Decimal::new(closing, 2)), Decimal::new(expected, 2), "{}", closing);
+        }
+    }
+}
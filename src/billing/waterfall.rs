@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use mysql_common::chrono::NaiveDate;
+use mysql_common::rust_decimal::Decimal;
+use serde::Serialize;
+use crate::datatypes::structs::{Account, Wallet};
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{CurrenciesIdType, WalletIdType};
+use crate::utils::{CoreError, CoreResult};
+
+// `date` picks the rates, so past business dates convert at their own rates
+pub trait CurrencyConverter {
+    fn convert(&self, amount: Decimal, from: CurrenciesIdType, to: CurrenciesIdType, date: NaiveDate) -> CoreResult<Decimal>;
+
+    // Precision amounts in `currencies_id` are rounded to
+    fn decimals(&self, _currencies_id: CurrenciesIdType) -> u32 {
+        2
+    }
+}
+
+// For callers without exchange rates: only same-currency wallets can be used
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SameCurrencyOnly;
+
+impl CurrencyConverter for SameCurrencyOnly {
+    fn convert(&self, amount: Decimal, from: CurrenciesIdType, to: CurrenciesIdType, _date: NaiveDate) -> CoreResult<Decimal> {
+        if from == to {
+            Ok(amount)
+        } else {
+            Err(CoreError::system_error(
+                format!("No exchange configured from {} to {}", from, to),
+                "billing::waterfall::SameCurrencyOnly::convert",
+                SystemErrorCodes::ExchangeConfiguration
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WalletAllocation {
+    pub wallets_id: WalletIdType,
+    pub currencies_id: CurrenciesIdType,
+    // In the wallet's currency
+    pub amount: Decimal,
+    // Portion of the original amount, in its currency
+    pub allocated: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DebitAllocation {
+    pub currencies_id: CurrenciesIdType,
+    pub requested: Decimal,
+    pub allocations: Vec<WalletAllocation>,
+    pub shortfall: Decimal,
+}
+
+impl DebitAllocation {
+    pub fn is_covered(&self) -> bool {
+        self.shortfall.is_zero()
+    }
+}
+
+// Splits a debit across the account wallets in charge_priority order (lowest first), converting
+// from each wallet's currency when it differs. `available` is the spendable amount per wallet in
+// its own currency; wallets missing from it are treated as empty. Whatever can't be covered is
+// returned as shortfall. The amount must be positive and the account must hold a wallet in the
+// debit currency
+pub fn allocate_debit<C: CurrencyConverter>(
+    account: &Account,
+    amount: Decimal,
+    currencies_id: CurrenciesIdType,
+    available: &HashMap<WalletIdType, Decimal>,
+    converter: &C,
+    date: NaiveDate,
+) -> CoreResult<DebitAllocation> {
+    if amount <= Decimal::ZERO {
+        return Err(CoreError::system_error(
+            format!("Debit amount {} for account {} must be positive", amount, account.id()),
+            "billing::waterfall::allocate_debit",
+            SystemErrorCodes::BadFormat
+        ));
+    }
+    if !account.wallets().values().any(|w| w.currencies_id() == currencies_id) {
+        return Err(CoreError::system_error(
+            format!("Account {} has no wallet in debit currency {}", account.id(), currencies_id),
+            "billing::waterfall::allocate_debit",
+            SystemErrorCodes::MissingDebitCurrency(1)
+        ));
+    }
+
+    let mut remaining = amount;
+    let mut allocations = Vec::new();
+    for wallet in wallets_by_priority(account) {
+        if remaining <= Decimal::ZERO { break }
+
+        let wallet_available = available.get(&wallet.id()).copied().unwrap_or_default();
+        if wallet_available <= Decimal::ZERO { continue }
+
+        let (allocated, wallet_amount) = if wallet.currencies_id() == currencies_id {
+            let allocated = remaining.min(wallet_available);
+            (allocated, allocated)
+        } else {
+            let available_converted = converter.convert(wallet_available, wallet.currencies_id(), currencies_id, date)?;
+            if available_converted <= remaining {
+                (available_converted, wallet_available)
+            } else {
+                (remaining, converter.convert(remaining, currencies_id, wallet.currencies_id(), date)?.min(wallet_available))
+            }
+        };
+
+        if allocated <= Decimal::ZERO { continue }
+        remaining -= allocated;
+        allocations.push(WalletAllocation {
+            wallets_id: wallet.id(),
+            currencies_id: wallet.currencies_id(),
+            amount: wallet_amount,
+            allocated,
+        });
+    }
+
+    Ok(DebitAllocation {
+        currencies_id,
+        requested: amount,
+        allocations,
+        shortfall: remaining.max(Decimal::ZERO),
+    })
+}
+
+// Credits (refunds, payments) go entirely to the highest priority wallet in their currency
+pub fn allocate_credit(account: &Account, amount: Decimal, currencies_id: CurrenciesIdType) -> CoreResult<WalletAllocation> {
+    wallets_by_priority(account)
+        .into_iter()
+        .find(|w| w.currencies_id() == currencies_id)
+        .map(|wallet| WalletAllocation {
+            wallets_id: wallet.id(),
+            currencies_id,
+            amount,
+            allocated: amount,
+        })
+        .ok_or_else(|| CoreError::system_error(
+            format!("Account {} has no wallet in credit currency {}", account.id(), currencies_id),
+            "billing::waterfall::allocate_credit",
+            SystemErrorCodes::MissingCreditCurrency(1)
+        ))
+}
+
+pub fn wallets_by_priority(account: &Account) -> Vec<&Wallet> {
+    let mut wallets: Vec<&Wallet> = account.wallets().values().collect();
+    wallets.sort_by_key(|w| (w.charge_priority(), w.id()));
+    wallets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 unit of currency 2 is worth 2 units of currency 1
+    struct Doubling;
+
+    impl CurrencyConverter for Doubling {
+        fn convert(&self, amount: Decimal, from: CurrenciesIdType, to: CurrenciesIdType, date: NaiveDate) -> CoreResult<Decimal> {
+            match (from, to) {
+                (2, 1) => Ok(amount * Decimal::TWO),
+                (1, 2) => Ok(amount / Decimal::TWO),
+                _ => SameCurrencyOnly.convert(amount, from, to, date),
+            }
+        }
+    }
+
+    // (wallets_id, currencies_id, charge_priority)
+    fn account(wallets: &[(WalletIdType, CurrenciesIdType, i16)]) -> Account {
+        let mut account = Account::new(7, 7, 1, 0, 0, 0, Some(15), Decimal::new(1000, 0), 0.0, 0.0, 0.0, 0.0);
+        for (id, currencies_id, priority) in wallets {
+            account.wallets_mut().insert(*id, Wallet::new(*id, *currencies_id, *priority));
+        }
+        account
+    }
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 15).unwrap()
+    }
+
+    fn available(amounts: &[(WalletIdType, i64)]) -> HashMap<WalletIdType, Decimal> {
+        amounts.iter().map(|(id, amount)| (*id, Decimal::new(*amount, 0))).collect()
+    }
+
+    fn allocated(allocation: &DebitAllocation) -> Vec<(WalletIdType, Decimal, Decimal)> {
+        allocation.allocations.iter().map(|a| (a.wallets_id, a.amount, a.allocated)).collect()
+    }
+
+    #[test]
+    fn debit_follows_charge_priority() {
+        let account = account(&[(10, 1, 3), (11, 1, 1), (12, 1, 2)]);
+        let allocation = allocate_debit(&account, Decimal::new(70, 0), 1, &available(&[(10, 100), (11, 30), (12, 25)]), &SameCurrencyOnly, date()).unwrap();
+
+        assert_eq!(allocated(&allocation), vec![
+            (11, Decimal::new(30, 0), Decimal::new(30, 0)),
+            (12, Decimal::new(25, 0), Decimal::new(25, 0)),
+            (10, Decimal::new(15, 0), Decimal::new(15, 0)),
+        ]);
+        assert!(allocation.is_covered());
+    }
+
+    #[test]
+    fn debit_converts_other_currency_wallets() {
+        let account = account(&[(10, 1, 1), (11, 2, 2)]);
+        let allocation = allocate_debit(&account, Decimal::new(50, 0), 1, &available(&[(10, 20), (11, 100)]), &Doubling, date()).unwrap();
+
+        assert_eq!(allocated(&allocation), vec![
+            (10, Decimal::new(20, 0), Decimal::new(20, 0)),
+            (11, Decimal::new(15, 0), Decimal::new(30, 0)),
+        ]);
+        assert!(allocation.is_covered());
+    }
+
+    #[test]
+    fn uncovered_debit_reports_shortfall() {
+        let account = account(&[(10, 1, 1), (11, 1, 2), (12, 1, 3)]);
+        let allocation = allocate_debit(&account, Decimal::new(100, 0), 1, &available(&[(10, 30), (11, -5)]), &SameCurrencyOnly, date()).unwrap();
+
+        assert_eq!(allocated(&allocation), vec![(10, Decimal::new(30, 0), Decimal::new(30, 0))]);
+        assert_eq!(allocation.shortfall, Decimal::new(70, 0));
+        assert!(!allocation.is_covered());
+    }
+
+    #[test]
+    fn debit_without_converter_fails_on_foreign_wallet() {
+        let account = account(&[(10, 1, 1), (11, 2, 2)]);
+        let error = allocate_debit(&account, Decimal::new(50, 0), 1, &available(&[(10, 20), (11, 100)]), &SameCurrencyOnly, date()).unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::ExchangeConfiguration);
+    }
+
+    #[test]
+    fn debit_rejects_zero_and_negative_amounts() {
+        let account = account(&[(10, 1, 1)]);
+        for amount in [Decimal::ZERO, Decimal::new(-1, 0)] {
+            let error = allocate_debit(&account, amount, 1, &available(&[(10, 20)]), &SameCurrencyOnly, date()).unwrap_err();
+            assert_eq!(error.system_error, SystemErrorCodes::BadFormat);
+        }
+    }
+
+    #[test]
+    fn missing_currency_wallets_are_errors() {
+        let account = account(&[(10, 2, 1)]);
+        let debit = allocate_debit(&account, Decimal::new(5, 0), 1, &available(&[(10, 20)]), &Doubling, date()).unwrap_err();
+        assert_eq!(debit.system_error, SystemErrorCodes::MissingDebitCurrency(1));
+
+        let credit = allocate_credit(&account, Decimal::new(5, 0), 1).unwrap_err();
+        assert_eq!(credit.system_error, SystemErrorCodes::MissingCreditCurrency(1));
+    }
+
+    #[test]
+    fn credit_goes_to_highest_priority_wallet_in_its_currency() {
+        let account = account(&[(10, 1, 3), (11, 2, 1), (12, 1, 2)]);
+        let allocation = allocate_credit(&account, Decimal::new(40, 0), 1).unwrap();
+        assert_eq!((allocation.wallets_id, allocation.amount), (12, Decimal::new(40, 0)));
+    }
+}
@@ -0,0 +1,354 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use mysql_async::{Opts, OptsBuilder, PoolConstraints, PoolOpts, SslOpts};
+use serde::Deserialize;
+use crate::authorization::idempotency::IdempotencyConfig;
+use crate::datatypes::response_mapping::ResponseCodesConfig;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::{CoreError, CoreResult};
+
+const CONFIG_FILE_ENV: &str = "PROCESSOR_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "processor.toml";
+const DB_ENV_PREFIX: &str = "PROCESSOR_DB_";
+const DB_ARG_PREFIX: &str = "--db-";
+
+// Configuration is layered: defaults < TOML file < PROCESSOR_DB_* env vars < --db-* CLI args
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub db: DbConfig,
+    pub shutdown: ShutdownConfig,
+    pub batch: BatchConfig,
+    pub response_codes: ResponseCodesConfig,
+    pub idempotency: IdempotencyConfig,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    pub page_size: usize,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub drain_timeout_secs: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct DbConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub database: String,
+    pub pool_min: usize,
+    pub pool_max: usize,
+    pub conn_ttl_secs: Option<u64>,
+    pub timezone: Option<String>,
+    pub connect_initial_backoff_ms: u64,
+    pub connect_max_backoff_ms: u64,
+    pub connect_deadline_secs: u64,
+    pub health_check_interval_secs: u64,
+    pub health_check_failures_before_reset: u32,
+    pub tls: TlsConfig,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub ca_file: Option<PathBuf>,
+    pub accept_invalid_certs: bool,
+    pub skip_domain_validation: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { drain_timeout_secs: 30 }
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig { page_size: 500 }
+    }
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            host: "127.0.0.1".to_string(),
+            port: 3306,
+            user: "root".to_string(),
+            password: None,
+            password_file: None,
+            database: "processor".to_string(),
+            pool_min: 10,
+            pool_max: 100,
+            conn_ttl_secs: None,
+            timezone: None,
+            connect_initial_backoff_ms: 250,
+            connect_max_backoff_ms: 10_000,
+            connect_deadline_secs: 60,
+            health_check_interval_secs: 15,
+            health_check_failures_before_reset: 3,
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> CoreResult<Self> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Self::load_from(&args)
+    }
+
+    pub fn load_from(args: &[String]) -> CoreResult<Self> {
+        Self::load_with(args, std::env::vars().collect())
+    }
+
+    // Unknown PROCESSOR_DB_* env vars are reported and skipped, other deployments may share the
+    // prefix. Unknown --db-* arguments are typos and fail the load
+    fn load_with(args: &[String], env: Vec<(String, String)>) -> CoreResult<Self> {
+        let mut config = match config_file_path(args, &env) {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+
+        for (key, value) in env.iter() {
+            if let Some(db_key) = key.strip_prefix(DB_ENV_PREFIX) {
+                if !config.db.set(&db_key.to_lowercase(), value)? {
+                    println!("Ignoring unknown db config env var {}", key);
+                }
+            }
+        }
+
+        for (key, value) in cli_pairs(args) {
+            if let Some(db_key) = key.strip_prefix(DB_ARG_PREFIX) {
+                if !config.db.set(&db_key.replace('-', "_"), &value)? {
+                    return Err(CoreError::system_error(
+                        format!("Unknown db config argument {}", key),
+                        "config::Config::load_from",
+                        SystemErrorCodes::BadFormat
+                    ));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> CoreResult<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| CoreError::system_error(
+                format!("{}: reading {}", e, path.display()),
+                "config::Config::from_file",
+                SystemErrorCodes::StringParse(1)
+            ))?;
+        toml::from_str(&content)
+            .map_err(|e| CoreError::system_error(
+                format!("{}: parsing {}", e, path.display()),
+                "config::Config::from_file",
+                SystemErrorCodes::StringParse(1)
+            ))
+    }
+}
+
+impl DbConfig {
+    // Applies a single override coming from an env var or a CLI argument, false when the key
+    // is unknown. Invalid values for known keys are errors
+    pub fn set(&mut self, key: &str, value: &str) -> CoreResult<bool> {
+        match key {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = parse_value(key, value)?,
+            "user" => self.user = value.to_string(),
+            "password" => self.password = Some(value.to_string()),
+            "password_file" => self.password_file = Some(PathBuf::from(value)),
+            "database" | "name" => self.database = value.to_string(),
+            "pool_min" => self.pool_min = parse_value(key, value)?,
+            "pool_max" => self.pool_max = parse_value(key, value)?,
+            "conn_ttl_secs" => self.conn_ttl_secs = Some(parse_value(key, value)?),
+            "timezone" => self.timezone = Some(value.to_string()),
+            "connect_initial_backoff_ms" => self.connect_initial_backoff_ms = parse_value(key, value)?,
+            "connect_max_backoff_ms" => self.connect_max_backoff_ms = parse_value(key, value)?,
+            "connect_deadline_secs" => self.connect_deadline_secs = parse_value(key, value)?,
+            "health_check_interval_secs" => self.health_check_interval_secs = parse_value(key, value)?,
+            "health_check_failures_before_reset" => self.health_check_failures_before_reset = parse_value(key, value)?,
+            "tls" | "tls_enabled" => self.tls.enabled = parse_value(key, value)?,
+            "tls_ca_file" => self.tls.ca_file = Some(PathBuf::from(value)),
+            "tls_accept_invalid_certs" => self.tls.accept_invalid_certs = parse_value(key, value)?,
+            "tls_skip_domain_validation" => self.tls.skip_domain_validation = parse_value(key, value)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // password_file wins over password so secrets never have to live in the TOML file or env
+    pub fn resolve_password(&self) -> CoreResult<Option<String>> {
+        match &self.password_file {
+            Some(path) => std::fs::read_to_string(path)
+                .map(|p| Some(p.trim_end_matches(['\r', '\n']).to_string()))
+                .map_err(|e| CoreError::system_error(
+                    format!("{}: reading password file {}", e, path.display()),
+                    "config::DbConfig::resolve_password",
+                    SystemErrorCodes::StringParse(2)
+                )),
+            None => Ok(self.password.clone()),
+        }
+    }
+
+    pub fn opts(&self) -> CoreResult<Opts> {
+        let constraints = PoolConstraints::new(self.pool_min, self.pool_max)
+            .ok_or_else(|| CoreError::system_error(
+                format!("Invalid pool constraints min {} max {}", self.pool_min, self.pool_max),
+                "config::DbConfig::opts",
+                SystemErrorCodes::BadFormat
+            ))?;
+
+        let mut pool_opts = PoolOpts::default().with_constraints(constraints);
+        if let Some(ttl) = self.conn_ttl_secs {
+            pool_opts = pool_opts.with_inactive_connection_ttl(Duration::from_secs(ttl));
+        }
+
+        let mut builder = OptsBuilder::default()
+            .ip_or_hostname(self.host.clone())
+            .tcp_port(self.port)
+            .user(Some(self.user.clone()))
+            .pass(self.resolve_password()?)
+            .db_name(Some(self.database.clone()))
+            .conn_ttl(self.conn_ttl_secs.map(Duration::from_secs))
+            .pool_opts(pool_opts);
+
+        if self.tls.enabled {
+            builder = builder.ssl_opts(Some(
+                SslOpts::default()
+                    .with_root_cert_path(self.tls.ca_file.clone())
+                    .with_danger_accept_invalid_certs(self.tls.accept_invalid_certs)
+                    .with_danger_skip_domain_validation(self.tls.skip_domain_validation)
+            ));
+        }
+
+        if let Some(timezone) = &self.timezone {
+            builder = builder.init(vec![format!("SET time_zone = '{}'", timezone.replace('\'', "''"))]);
+        }
+
+        Ok(builder.into())
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> CoreResult<T> {
+    value.parse::<T>()
+        .map_err(|_| CoreError::system_error(
+            format!("Invalid value {} for db config key {}", value, key),
+            "config::parse_value",
+            SystemErrorCodes::BadFormat
+        ))
+}
+
+fn config_file_path(args: &[String], env: &[(String, String)]) -> Option<PathBuf> {
+    cli_pairs(args)
+        .into_iter()
+        .find(|(key, _)| key == "--config")
+        .map(|(_, value)| PathBuf::from(value))
+        .or_else(|| env.iter().find(|(key, _)| key == CONFIG_FILE_ENV).map(|(_, value)| PathBuf::from(value)))
+        .or_else(|| {
+            let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+            if default.exists() { Some(default) } else { None }
+        })
+}
+
+// Accepts both `--key=value` and `--key value`
+fn cli_pairs(args: &[String]) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") { continue }
+        match arg.split_once('=') {
+            Some((key, value)) => pairs.push((key.to_string(), value.to_string())),
+            None => {
+                if let Some(value) = iter.next_if(|v| !v.starts_with("--")) {
+                    pairs.push((arg.clone(), value.clone()));
+                }
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn env(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("processor_config_{}_{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_without_file_env_or_args() {
+        let config = Config::load_with(&[], Vec::new()).unwrap();
+        assert_eq!(config.db.host, "127.0.0.1");
+        assert_eq!(config.db.pool_max, 100);
+        assert_eq!(config.batch.page_size, 500);
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let path = config_file("file", "[db]\nhost = \"db.file\"\npool_max = 20\n");
+        let config = Config::load_with(&args(&["--config", path.to_str().unwrap()]), Vec::new()).unwrap();
+        assert_eq!(config.db.host, "db.file");
+        assert_eq!(config.db.pool_max, 20);
+        assert_eq!(config.db.port, 3306);
+    }
+
+    #[test]
+    fn env_overrides_file_and_cli_overrides_env() {
+        let path = config_file("layers", "[db]\nhost = \"db.file\"\nport = 3307\nuser = \"file\"\n");
+        let env = env(&[
+            (CONFIG_FILE_ENV, path.to_str().unwrap()),
+            ("PROCESSOR_DB_HOST", "db.env"),
+            ("PROCESSOR_DB_PORT", "3308"),
+        ]);
+        let config = Config::load_with(&args(&["--db-host=db.cli"]), env).unwrap();
+        assert_eq!(config.db.host, "db.cli");
+        assert_eq!(config.db.port, 3308);
+        assert_eq!(config.db.user, "file");
+    }
+
+    #[test]
+    fn cli_config_path_wins_over_env() {
+        let env_path = config_file("env_path", "[db]\nhost = \"db.env_file\"\n");
+        let cli_path = config_file("cli_path", "[db]\nhost = \"db.cli_file\"\n");
+        let config = Config::load_with(
+            &args(&["--config", cli_path.to_str().unwrap()]),
+            env(&[(CONFIG_FILE_ENV, env_path.to_str().unwrap())])
+        ).unwrap();
+        assert_eq!(config.db.host, "db.cli_file");
+    }
+
+    #[test]
+    fn unknown_env_var_is_skipped() {
+        let config = Config::load_with(&[], env(&[("PROCESSOR_DB_SOMETHING_ELSE", "1"), ("PROCESSOR_DB_USER", "env")])).unwrap();
+        assert_eq!(config.db.user, "env");
+    }
+
+    #[test]
+    fn unknown_cli_arg_and_bad_values_fail() {
+        assert!(Config::load_with(&args(&["--db-hots=x"]), Vec::new()).is_err());
+        assert!(Config::load_with(&args(&["--db-port=abc"]), Vec::new()).is_err());
+        assert!(Config::load_with(&[], env(&[("PROCESSOR_DB_PORT", "abc")])).is_err());
+    }
+}
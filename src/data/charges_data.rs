@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use futures::Stream;
+use serde::Serialize;
+use crate::data::queries::AccountChargesData;
+use crate::data::repository::{AccountRepository, StatementRepository};
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_datatypes::{AccountIdType, AccountsChargesData, ProductIdType};
+use crate::utils::CoreResult;
+
+// Keyset pagination over accounts ordered by ID, so memory is bounded by batch_size no matter
+// how large the portfolio is and a run can restart from any `last_id`
+pub struct AccountChargesCursor {
+    last_id: AccountIdType,
+    batch_size: usize,
+    exhausted: bool,
+    filter: AccountFilter,
+}
+
+// Restricts a cursor to one product and/or one shard. Shard `index` of `count` owns the accounts
+// with ID % count == index, so shards of the same product never overlap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountFilter {
+    pub products_id: Option<ProductIdType>,
+    pub shard: Option<Shard>,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, account: &Account) -> bool {
+        self.products_id.map(|products_id| account.products_id() == products_id).unwrap_or(true)
+            && self.shard
+                .filter(|shard| shard.count > 1)
+                .map(|shard| account.id() as u64 % shard.count == shard.index)
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl AccountChargesCursor {
+    pub fn new(batch_size: usize) -> Self {
+        Self::starting_after(0, batch_size)
+    }
+
+    pub fn starting_after(last_id: AccountIdType, batch_size: usize) -> Self {
+        AccountChargesCursor { last_id, batch_size: batch_size.max(1), exhausted: false, filter: AccountFilter::default() }
+    }
+
+    pub fn with_filter(mut self, filter: AccountFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn filter(&self) -> &AccountFilter {
+        &self.filter
+    }
+
+    pub fn last_id(&self) -> AccountIdType {
+        self.last_id
+    }
+
+    // None once every account has been returned. Accounts that fail to load are skipped by the
+    // repository so one bad account doesn't stop the batch; a page can come back empty when all
+    // of them failed
+    pub async fn next_batch<R: AccountRepository + StatementRepository>(&mut self, repository: &R) -> CoreResult<Option<AccountsChargesData>> {
+        if self.exhausted { return Ok(None) }
+
+        let page = repository.get_accounts_after(self.last_id, &self.filter, self.batch_size).await?;
+        if page.scanned < self.batch_size {
+            self.exhausted = true;
+        }
+        let last_id = match page.last_id {
+            Some(last_id) => last_id,
+            None => return Ok(None),
+        };
+        self.last_id = last_id;
+
+        let ids: Vec<AccountIdType> = page.accounts.iter().map(|account| account.id()).collect();
+        let mut statements = repository.get_latest_statements(&ids).await?;
+
+        Ok(Some(
+            page.accounts.into_iter()
+                .map(|account| {
+                    let statement = statements.remove(&account.id());
+                    AccountChargesData::new(account, statement)
+                })
+                .collect()
+        ))
+    }
+}
+
+// Yields every account with its latest statement, one repository page at a time
+pub fn stream_account_charges_data<R>(repository: R, batch_size: usize) -> impl Stream<Item = CoreResult<AccountChargesData>>
+where
+    R: AccountRepository + StatementRepository,
+{
+    let state = (repository, AccountChargesCursor::new(batch_size), VecDeque::<AccountChargesData>::new());
+    futures::stream::unfold(state, |(repository, mut cursor, mut buffer)| async move {
+        loop {
+            if let Some(data) = buffer.pop_front() {
+                return Some((Ok(data), (repository, cursor, buffer)));
+            }
+            match cursor.next_batch(&repository).await {
+                Ok(Some(batch)) => buffer.extend(batch),
+                Ok(None) => return None,
+                Err(e) => {
+                    // Stop after reporting the error instead of retrying the same page forever
+                    cursor.exhausted = true;
+                    return Some((Err(e), (repository, cursor, buffer)));
+                }
+            }
+        }
+    })
+}
@@ -1,19 +1,90 @@
-use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use mysql_async::Conn;
+use mysql_async::prelude::Queryable;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::config::DbConfig;
 use crate::data;
-use crate::data::get_conn;
-use crate::utils::MyResult;
+use crate::data::pool_conn;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::{CoreError, MyResult};
 
-pub async fn init_db_conn () -> MyResult<Conn> {
-    data::init_pool().await.unwrap_or_else(|e| {
-        println!("Failed to initiate db pool: {}", e);
-        exit(0)
-    });
+// Set by init_db_conn and the health check, get_conn refuses connections while it's false
+static POOL_HEALTHY: AtomicBool = AtomicBool::new(false);
 
-    let conn = get_conn().await.unwrap_or_else(|e| {
-        println!("Failed to get db conn: {}", e);
-        exit(0)
-    });
+pub fn is_pool_healthy() -> bool {
+    POOL_HEALTHY.load(Ordering::Relaxed)
+}
 
-    Ok(conn)
-}
\ No newline at end of file
+// Builds the pool and retries the first connection with exponential backoff until
+// connect_deadline_secs. The caller decides how to exit; see SystemErrorCodes::exit_code
+pub async fn init_db_conn (config: &DbConfig) -> MyResult<Conn> {
+    data::init_pool(config).await?;
+
+    let deadline = Instant::now() + Duration::from_secs(config.connect_deadline_secs);
+    let max_backoff = Duration::from_millis(config.connect_max_backoff_ms);
+    let mut backoff = Duration::from_millis(config.connect_initial_backoff_ms);
+    let mut attempts: u32 = 0;
+
+    loop {
+        attempts += 1;
+        match pool_conn().await {
+            Ok(conn) => {
+                POOL_HEALTHY.store(true, Ordering::Relaxed);
+                return Ok(conn);
+            },
+            Err(e) => {
+                if Instant::now() + backoff > deadline {
+                    return Err(CoreError::system_error(
+                        format!("Failed to get db conn after {} attempts: {}", attempts, e),
+                        "data::db_conn::init_db_conn",
+                        SystemErrorCodes::DbNoConn(1)
+                    ));
+                }
+                println!("Failed to get db conn (attempt {}), retrying in {:?}: {}", attempts, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(max_backoff);
+            }
+        }
+    }
+}
+
+// Pings the pool every health_check_interval_secs, flags it unhealthy on failure and rebuilds it
+// after health_check_failures_before_reset consecutive failures
+pub fn spawn_health_check(config: DbConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.health_check_interval_secs.max(1)));
+        let mut failures: u32 = 0;
+        loop {
+            interval.tick().await;
+            match ping().await {
+                Ok(()) => {
+                    if !POOL_HEALTHY.swap(true, Ordering::Relaxed) {
+                        println!("Db pool recovered");
+                    }
+                    failures = 0;
+                },
+                Err(e) => {
+                    failures += 1;
+                    if POOL_HEALTHY.swap(false, Ordering::Relaxed) {
+                        println!("Db pool marked unhealthy: {}", e);
+                    }
+                    if failures >= config.health_check_failures_before_reset.max(1) {
+                        println!("Rebuilding db pool after {} failed health checks", failures);
+                        if let Err(e) = data::init_pool(&config).await {
+                            println!("Failed to rebuild db pool: {}", e);
+                        }
+                        failures = 0;
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn ping() -> MyResult<()> {
+    pool_conn().await?
+        .ping().await
+        .map_err(|e| CoreError::system_error(e, "data::db_conn::ping", SystemErrorCodes::DbNoConn(2)))
+}
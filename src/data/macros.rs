@@ -76,4 +76,149 @@ macro_rules! extract_bool_opt {
                 .map(|x| x != 0)
         }
     };
-}
\ No newline at end of file
+}
+// Fallible counterparts of the extract_* macros above. They evaluate to CoreResult<T> and carry
+// SystemErrorCodes::BadFormat instead of panicking, so they can back FromRow::from_row_opt.
+// try_extract_decimal!/try_extract_decimal_opt! still accept any value whose SQL text parses as
+// a decimal (e.g. a DOUBLE column), but unlike extract_decimal!/extract_decimal_opt! they fail on
+// values that don't (NULL for the non-Option form, non numeric strings) instead of reading them
+// as zero/None: a balance silently loaded as 0 is worse than a rejected row
+#[macro_export]
+macro_rules! try_extract_value {
+    ($row:expr, $field_name:expr, $table_name:expr) => {
+        {
+            use $crate::datatypes::{expect_sql_field, sql_field_error};
+            match $row.get_opt($field_name) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(e)) => Err(sql_field_error(format!("{}: converting {} from {}", e, $field_name, $table_name), $table_name)),
+                None => Err(sql_field_error(expect_sql_field($field_name, $table_name), $table_name)),
+            }
+        }
+    };
+    ($row:expr, $field_name:expr, $table_name:expr, $datatype:ty) => {
+        {
+            use $crate::datatypes::{expect_sql_field, sql_field_error};
+            match $row.get_opt::<$datatype, _>($field_name) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(e)) => Err(sql_field_error(format!("{}: converting {} from {}", e, $field_name, $table_name), $table_name)),
+                None => Err(sql_field_error(expect_sql_field($field_name, $table_name), $table_name)),
+            }
+        }
+    };
+}
+#[macro_export]
+macro_rules! try_extract_decimal {
+    ($row:expr, $field_name:expr, $table_name:expr) => {
+        {
+            use $crate::datatypes::{expect_sql_field, sql_field_error};
+            use mysql_common::rust_decimal::Decimal;
+            use std::str::FromStr;
+            match $row.get_opt::<Decimal, _>($field_name) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(e)) => Decimal::from_str(&e.0.as_sql(false))
+                    .map_err(|e| sql_field_error(format!("{}: converting {} from {}", e, $field_name, $table_name), $table_name)),
+                None => Err(sql_field_error(expect_sql_field($field_name, $table_name), $table_name)),
+            }
+        }
+    };
+}
+#[macro_export]
+macro_rules! try_extract_decimal_opt {
+    ($row:expr, $field_name:expr, $table_name:expr) => {
+        {
+            use $crate::datatypes::{expect_sql_field, sql_field_error};
+            use mysql_common::rust_decimal::Decimal;
+            use std::str::FromStr;
+            match $row.get_opt::<Option<Decimal>, _>($field_name) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(e)) => Decimal::from_str(&e.0.as_sql(false))
+                    .map(Some)
+                    .map_err(|e| sql_field_error(format!("{}: converting {} from {}", e, $field_name, $table_name), $table_name)),
+                None => Err(sql_field_error(expect_sql_field($field_name, $table_name), $table_name)),
+            }
+        }
+    };
+}
+#[macro_export]
+macro_rules! try_extract_char_opt {
+    ($row:expr, $field_name:expr, $table_name:expr) => {
+        $crate::try_extract_value!($row, $field_name, $table_name, Option<String>)
+            .map(|x| x.and_then(|x| x.chars().next()))
+    };
+}
+#[macro_export]
+macro_rules! try_extract_bool {
+    ($row:expr, $field_name:expr, $table_name:expr) => {
+        $crate::try_extract_value!($row, $field_name, $table_name, u8)
+            .map(|x| x != 0)
+    };
+}
+#[macro_export]
+macro_rules! try_extract_bool_opt {
+    ($row:expr, $field_name:expr, $table_name:expr) => {
+        $crate::try_extract_value!($row, $field_name, $table_name, Option<u8>)
+            .map(|x| x.map(|x| x != 0))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use mysql_common::constants::ColumnType;
+    use mysql_common::packets::Column;
+    use mysql_common::row::{new_row, Row};
+    use mysql_common::rust_decimal::Decimal;
+    use mysql_common::value::Value;
+    use crate::datatypes::system_codes::SystemErrorCodes;
+
+    fn row(value: Value) -> Row {
+        let columns = vec![Column::new(ColumnType::MYSQL_TYPE_NEWDECIMAL).with_name(b"amount")];
+        new_row(vec![value], Arc::from(columns))
+    }
+
+    #[test]
+    fn decimals_accept_anything_that_reads_as_a_number() {
+        let cases = [
+            (Value::Bytes(b"12.50".to_vec()), Decimal::new(1250, 2)),
+            (Value::Int(-3), Decimal::new(-3, 0)),
+            (Value::Double(2.5), Decimal::new(25, 1)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(crate::try_extract_decimal!(row(value.clone()), "amount", "test").unwrap(), expected, "{:?}", value);
+            assert_eq!(crate::try_extract_decimal_opt!(row(value.clone()), "amount", "test").unwrap(), Some(expected), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn null_decimals_only_pass_as_options() {
+        assert_eq!(crate::try_extract_decimal!(row(Value::NULL), "amount", "test").unwrap_err().system_error, SystemErrorCodes::BadFormat);
+        assert_eq!(crate::try_extract_decimal_opt!(row(Value::NULL), "amount", "test").unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_decimals_are_rejected() {
+        for value in [Value::Bytes(b"12,50".to_vec()), Value::Bytes(b"abc".to_vec()), Value::Date(2024, 3, 15, 0, 0, 0, 0)] {
+            assert_eq!(crate::try_extract_decimal!(row(value.clone()), "amount", "test").unwrap_err().system_error, SystemErrorCodes::BadFormat, "{:?}", value);
+            assert_eq!(crate::try_extract_decimal_opt!(row(value.clone()), "amount", "test").unwrap_err().system_error, SystemErrorCodes::BadFormat, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn missing_columns_are_reported() {
+        let error = crate::try_extract_decimal!(row(Value::Int(1)), "missing", "test").unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::BadFormat);
+        assert!(error.detail.contains("missing"), "{}", error.detail);
+
+        assert_eq!(crate::try_extract_value!(row(Value::Int(1)), "missing", "test", i64).unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_rejected() {
+        assert_eq!(crate::try_extract_value!(row(Value::Int(7)), "amount", "test", i64).unwrap(), 7);
+        assert_eq!(crate::try_extract_value!(row(Value::Bytes(b"abc".to_vec())), "amount", "test", i64).unwrap_err().system_error, SystemErrorCodes::BadFormat);
+        assert_eq!(crate::try_extract_value!(row(Value::NULL), "amount", "test", i64).unwrap_err().system_error, SystemErrorCodes::BadFormat);
+        assert_eq!(crate::try_extract_value!(row(Value::NULL), "amount", "test", Option<i64>).unwrap(), None);
+        assert!(crate::try_extract_bool!(row(Value::Int(1)), "amount", "test").unwrap());
+        assert_eq!(crate::try_extract_bool!(row(Value::Bytes(b"yes".to_vec())), "amount", "test").unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+}
@@ -1,51 +1,84 @@
+pub mod charges_data;
 pub mod db_conn;
 mod macros;
+pub mod parameters;
 pub mod queries;
+pub mod repository;
+pub mod transaction;
+pub mod wallets;
 
 use lazy_static::lazy_static;
 use mysql_async::{Conn, Pool};
 use tokio::sync::RwLock;
 use logger::ErrorTypes;
-use crate::datatypes::system_codes::MySystemError;
+use crate::config::DbConfig;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
 use crate::utils::MyResult;
 
-const MYSQL_DSN: &str = "mysql://root:@127.0.0.1:3306/processor";
-
 lazy_static! {
     static ref DB_POOL: RwLock<Option<Pool>> = RwLock::new(None);
 }
 
-pub async fn init_pool() -> MyResult<()> {
-    let pool = create_pool().await?;
-    let mut w = DB_POOL.write().await;
-    *w = Some(pool);
+pub async fn init_pool(config: &DbConfig) -> MyResult<()> {
+    let pool = create_pool(config).await?;
+    let previous = DB_POOL.write().await.replace(pool);
+    if let Some(previous) = previous {
+        // Connections still checked out keep the old pool alive until they are returned
+        tokio::spawn(async move {
+            if let Err(e) = previous.disconnect().await {
+                println!("Failed to disconnect previous db pool: {}", e);
+            }
+        });
+    }
     Ok(())
 }
 
-pub async fn create_pool() -> crate::utils::CoreResult<Pool>{
-    let opts: mysql_async::Opts = mysql_async::Opts::from_url(MYSQL_DSN)
+pub async fn create_pool(config: &DbConfig) -> crate::utils::CoreResult<Pool>{
+    let opts: mysql_async::Opts = config.opts()
             .map_err(
-                |e| crate::utils::CoreError::new(
+                |e| crate::utils::CoreError::system_error(
+                    e.detail,
                     "data::create_pool()",
-                    e.to_string(),
-                    ErrorTypes::DbPool
+                    SystemErrorCodes::DbNoConn(3)
                 )
             )?;
-    let opts = opts;
     Ok(Pool::new(opts))
 }
 
+// Fails fast while the health check has the pool flagged unhealthy, instead of every caller
+// waiting out a connection timeout
 pub async fn get_conn() -> crate::utils::CoreResult<Conn> {
+    if !db_conn::is_pool_healthy() {
+        return Err(crate::utils::CoreError::system_error(
+            "Db pool is unhealthy",
+            "data::get_conn",
+            SystemErrorCodes::DbNoConn(4)
+        ));
+    }
+    pool_conn().await
+}
+
+// Skips the health flag, for init_db_conn and the health check that decide it
+pub(crate) async fn pool_conn() -> crate::utils::CoreResult<Conn> {
     DB_POOL
         .read().await
         .as_ref()
         .ok_or_else(
             || crate::utils::CoreError::new(
-                "data::get_conn",
+                "data::pool_conn",
                 "DB_POOL disconnected".to_string(),
                 ErrorTypes::DbNoConn
             )
         )?
         .get_conn().await
-        .map_err(|e| crate::utils::CoreError::new("data::get_conn",e.to_string(),ErrorTypes::DbNoConn))
-}
\ No newline at end of file
+        .map_err(|e| crate::utils::CoreError::new("data::pool_conn",e.to_string(),ErrorTypes::DbNoConn))
+}
+// Waits for checked out connections to be returned, so in-flight work should be drained first
+pub async fn disconnect_pool() -> crate::utils::CoreResult<()> {
+    let pool = DB_POOL.write().await.take();
+    match pool {
+        Some(pool) => pool.disconnect().await
+            .map_err(|e| crate::utils::CoreError::new("data::disconnect_pool", e.to_string(), ErrorTypes::DbNoConn)),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, HashMap};
+use mysql_async::{Conn, Params, Value};
+use mysql_async::prelude::Queryable;
+use mysql_common::row::Row;
+use my_own_tests_derive::FromRow;
+use crate::datatypes::structs::{Account, ParameterData, ParameterType};
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, AccountParameterIdType};
+use crate::utils::{CoreError, CoreResult};
+
+// One row per parameter declared for the account's product; value is NULL when the
+// account has no stored value for it
+#[derive(Debug, FromRow)]
+#[from_row(table = "accounts_parameters")]
+struct AccountParameterRow {
+    #[from_row(rename = "accounts_ID")]
+    accounts_id: AccountIdType,
+    #[from_row(rename = "parameters_ID")]
+    parameters_id: AccountParameterIdType,
+    value_type: String,
+    value: Option<String>,
+}
+
+pub async fn load_account_parameters(conn: &mut Conn, account: &mut Account) -> CoreResult<()> {
+    match load_parameters(conn, std::slice::from_mut(account)).await?.into_values().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// Returns the accounts with a parameter value that doesn't parse as its declared type, so the
+// caller can drop just those; they are left with the parameters that did parse
+pub async fn load_parameters(conn: &mut Conn, accounts: &mut [Account]) -> CoreResult<HashMap<AccountIdType, CoreError>> {
+    let mut failed = HashMap::new();
+    if accounts.is_empty() { return Ok(failed) }
+
+    let placeholders = vec!["?"; accounts.len()].join(", ");
+    let params: Vec<Value> = accounts.iter().map(|account| Value::from(account.id())).collect();
+
+    let rows = conn.exec::<Row, _, _>(
+        format!(
+            "SELECT a.ID AS accounts_ID, p.ID AS parameters_ID, p.value_type, ap.value \
+            FROM accounts a \
+            JOIN parameters p ON p.products_ID = a.products_ID \
+            LEFT JOIN accounts_parameters ap ON ap.parameters_ID = p.ID AND ap.accounts_ID = a.ID \
+            WHERE a.ID IN ({})",
+            placeholders
+        ),
+        Params::Positional(params)
+    ).await.map_err(|e| CoreError::system_error(e, "data::parameters::load_parameters", SystemErrorCodes::DbQuery(3)))?;
+
+    let mut by_account: HashMap<AccountIdType, BTreeMap<AccountParameterIdType, ParameterData>> = HashMap::with_capacity(accounts.len());
+    for row in rows.iter() {
+        let parameter = AccountParameterRow::try_from_row(row)?;
+        let data = parameter.value_type.parse::<ParameterType>()
+            .and_then(|parameter_type| ParameterData::parse(parameter_type, parameter.value.as_deref()));
+        match data {
+            Ok(data) => {
+                by_account
+                    .entry(parameter.accounts_id)
+                    .or_default()
+                    .insert(parameter.parameters_id, data);
+            },
+            Err(e) => {
+                failed.entry(parameter.accounts_id).or_insert(e);
+            },
+        }
+    }
+
+    for account in accounts.iter_mut() {
+        account.set_parameters(by_account.remove(&account.id()).unwrap_or_default());
+    }
+
+    Ok(failed)
+}
@@ -2,33 +2,38 @@
 use std::process::exit;
 use mysql_async::Conn;
 use mysql_async::prelude::Queryable;
-use mysql_async::prelude::FromRow;
 use mysql_common::chrono;
-use mysql_common::row::convert::FromRowError;
 use mysql_common::row::Row;
+use my_own_tests_derive::FromRow;
 use serde::{Serialize, Deserialize};
+use crate::data::charges_data::AccountChargesCursor;
+use crate::data::repository::{AccountRepository, StatementRepository};
 use crate::datatypes::structs::{Account};
 use crate::datatypes::system_datatypes::{AccountIdType, ProductIdType};
-use crate::extract_value;
+use crate::utils::shutdown;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Pids {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pids {
     pub pid0: u64,
     pub pid1: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Processes {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Processes {
     pub process2: u64,
     pub process3: u64,
     pub process4: u64,
     pub process5: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
+#[from_row(table = "products_statements_configurations")]
 pub struct FullQuery {
+    #[from_row(rename = "products_ID")]
     products_id: ProductIdType,
+    #[from_row(rename = "pids", json)]
     pids_json: Option<Pids>,
+    #[from_row(rename = "processes", json)]
     processes_json: Option<Processes>,
     created_at: String,
     updated_at: String
@@ -36,77 +41,105 @@ pub struct FullQuery {
 
 pub async fn select_json_from_db(conn: &mut Conn) {
 
-    let json_vec = conn.query::<FullQuery,_>(
+    let rows = conn.query::<Row,_>(
         "SELECT * FROM products_statements_configurations"
     ).await.unwrap();
 
-    for json in json_vec {
-        // if json.is_none() {
-        //     println!("json was none");
-        //     exit(0)
-        // } else {
-        //     println!("{:?}", json.unwrap());
-        // }
-        println!("{:?}", json);
-    }
-}
-
-impl FromRow for FullQuery {
-    fn from_row(row: Row) -> Self where Self: Sized {
-
-        let pids_json = extract_value!(row, "pids", "products_statements_configurations", Option<String>);
-        let processes_json = extract_value!(row, "processes", "products_statements_configurations", Option<String>);
-
-        FullQuery {
-            products_id: extract_value!(row, "products_ID", "products_statements_configurations", ProductIdType),
-            pids_json: serde_json::from_str(
-                &{if let Some(..) = pids_json {pids_json.unwrap()}
-                    else {"".to_string()}}
-                ).unwrap_or(None),
-            processes_json: serde_json::from_str(
-                &{if let Some(..) = processes_json {processes_json.unwrap()}
-                    else {"".to_string()}}
-                ).unwrap_or(None),
-            created_at: extract_value!(row, "created_at", "products_statements_configurations", String),
-            updated_at: extract_value!(row, "updated_at", "products_statements_configurations", String)
+    for row in rows {
+        match FullQuery::try_from_row(&row) {
+            Ok(json) => println!("{:?}", json),
+            Err(e) => println!("Skipping products_statements_configurations row: {}", e)
         }
     }
-
-    fn from_row_opt(_row: Row) -> Result<Self, FromRowError> where Self: Sized { unimplemented!() }
 }
 
+#[derive(Debug, Clone)]
 pub struct AccountChargesData {
     accounts: Account,
     account_statement: Option<AccountStatements>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "account_statements")]
 pub struct AccountStatements {
     accounts_id: AccountIdType,
     balances_date: chrono::NaiveDate
 }
 
-pub async fn get_account_charges_data(conn: &mut Conn) {
-    let accounts = conn.query_first::<Account, _>(
-        "SELECT * FROM accounts"
-    ).await.unwrap_or(None);
+impl Processes {
+    // Process count configured for step `number` (process2..process5), None for unknown steps
+    pub fn count_for(&self, number: u8) -> Option<u64> {
+        match number {
+            2 => Some(self.process2),
+            3 => Some(self.process3),
+            4 => Some(self.process4),
+            5 => Some(self.process5),
+            _ => None,
+        }
+    }
+}
 
-    let accounts_statemens = conn.query_first::<AccountStatements, _>(
-        "SELECT * FROM account_statements"
-    ).await.unwrap_or(None);
+impl FullQuery {
+    pub fn products_id(&self) -> ProductIdType {
+        self.products_id
+    }
 
-    if accounts.is_some() { println!("{:?}", accounts.unwrap()) }
-    if accounts_statemens.is_some() { println!("{:?}", accounts_statemens.unwrap())}
+    pub fn pids(&self) -> Option<&Pids> {
+        self.pids_json.as_ref()
+    }
 
+    pub fn processes(&self) -> Option<&Processes> {
+        self.processes_json.as_ref()
+    }
 }
 
-impl FromRow for AccountStatements {
-    fn from_row(row: Row) -> Self where Self: Sized {
-        AccountStatements {
-            accounts_id: extract_value!(row, "accounts_id", "account_statements", AccountIdType),
-            balances_date: extract_value!(row, "balances_date", "account_statements", chrono::NaiveDate)
-        }
+impl AccountStatements {
+    pub fn new(accounts_id: AccountIdType, balances_date: chrono::NaiveDate) -> Self {
+        AccountStatements { accounts_id, balances_date }
     }
 
-    fn from_row_opt(_row: Row) -> Result<Self, FromRowError> where Self: Sized { unimplemented!() }
-}
\ No newline at end of file
+    pub fn accounts_id(&self) -> AccountIdType {
+        self.accounts_id
+    }
+
+    pub fn balances_date(&self) -> chrono::NaiveDate {
+        self.balances_date
+    }
+}
+
+impl AccountChargesData {
+    pub fn new(accounts: Account, account_statement: Option<AccountStatements>) -> Self {
+        AccountChargesData { accounts, account_statement }
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.accounts
+    }
+
+    pub fn account_statement(&self) -> Option<&AccountStatements> {
+        self.account_statement.as_ref()
+    }
+}
+
+pub async fn get_account_charges_data<R: AccountRepository + StatementRepository>(repository: &R, batch_size: usize) {
+    let mut cursor = AccountChargesCursor::new(batch_size);
+    loop {
+        if shutdown::is_shutting_down() {
+            println!("Stopping account charges data after account {}", cursor.last_id());
+            break;
+        }
+        match cursor.next_batch(repository).await {
+            Ok(Some(batch)) => {
+                for data in batch {
+                    println!("{:?}", data.account());
+                    if let Some(statement) = data.account_statement() { println!("{:?}", statement) }
+                }
+            },
+            Ok(None) => break,
+            Err(e) => {
+                println!("Failed to read accounts after {}: {}", cursor.last_id(), e);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::RwLock;
+use mysql_common::chrono::{NaiveDate, NaiveDateTime};
+use mysql_common::rust_decimal::Decimal;
+use crate::authorization::decision::AuthorizationDecision;
+use crate::authorization::fraud::RecentTransaction;
+use crate::authorization::idempotency::TransmissionKey;
+use crate::batch::ledger::{BatchRun, RunKey, RunStatus};
+use crate::batch::runner::BatchStep;
+use crate::billing::availability::WalletBalance;
+use crate::billing::calendar::Cycle;
+use crate::billing::statements::{Statement, StatementTransaction};
+use crate::data::charges_data::AccountFilter;
+use crate::data::queries::{AccountStatements, FullQuery};
+use crate::data::repository::{AccountPage, AccountRepository, BalanceRepository, BatchRunRepository, IdempotencyRepository, ProductConfigRepository, StatementRepository};
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, ProductIdType, WalletIdType};
+use crate::utils::{CoreError, CoreResult};
+
+// Locks are never held across an await, so std's RwLock is enough here
+#[derive(Debug, Default)]
+pub struct InMemoryRepository {
+    accounts: RwLock<BTreeMap<AccountIdType, Account>>,
+    statements: RwLock<BTreeMap<AccountIdType, Vec<AccountStatements>>>,
+    configurations: RwLock<BTreeMap<ProductIdType, FullQuery>>,
+    balances: RwLock<BTreeMap<AccountIdType, HashMap<WalletIdType, WalletBalance>>>,
+    authorizations: RwLock<BTreeMap<AccountIdType, Vec<RecentTransaction>>>,
+    decisions: RwLock<HashMap<TransmissionKey, (NaiveDateTime, AuthorizationDecision)>>,
+    transactions: RwLock<BTreeMap<AccountIdType, Vec<StatementTransaction>>>,
+    closed_statements: RwLock<BTreeMap<(AccountIdType, NaiveDate), Statement>>,
+    runs: RwLock<HashMap<RunKey, BatchRun>>,
+    completed_dates: RwLock<HashSet<(BatchStep, NaiveDate)>>,
+}
+
+impl InMemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_account(&self, account: Account) {
+        self.accounts.write().unwrap().insert(account.id(), account);
+    }
+
+    pub fn insert_statement(&self, statement: AccountStatements) {
+        self.statements.write().unwrap()
+            .entry(statement.accounts_id())
+            .or_default()
+            .push(statement);
+    }
+
+    pub fn insert_configuration(&self, configuration: FullQuery) {
+        self.configurations.write().unwrap().insert(configuration.products_id(), configuration);
+    }
+
+    pub fn insert_wallet_balance(&self, accounts_id: AccountIdType, wallets_id: WalletIdType, balance: WalletBalance) {
+        self.balances.write().unwrap()
+            .entry(accounts_id)
+            .or_default()
+            .insert(wallets_id, balance);
+    }
+
+    pub fn insert_authorization(&self, accounts_id: AccountIdType, authorization: RecentTransaction) {
+        self.authorizations.write().unwrap()
+            .entry(accounts_id)
+            .or_default()
+            .push(authorization);
+    }
+
+    pub fn insert_transaction(&self, accounts_id: AccountIdType, transaction: StatementTransaction) {
+        self.transactions.write().unwrap()
+            .entry(accounts_id)
+            .or_default()
+            .push(transaction);
+    }
+
+    pub fn closed_statement(&self, accounts_id: AccountIdType, balances_date: NaiveDate) -> Option<Statement> {
+        self.closed_statements.read().unwrap().get(&(accounts_id, balances_date)).cloned()
+    }
+}
+
+impl AccountRepository for InMemoryRepository {
+    async fn get_account(&self, accounts_id: AccountIdType) -> CoreResult<Option<Account>> {
+        Ok(self.accounts.read().unwrap().get(&accounts_id).cloned())
+    }
+
+    async fn get_account_products(&self) -> CoreResult<Vec<ProductIdType>> {
+        let mut products: Vec<ProductIdType> = self.accounts.read().unwrap().values().map(|account| account.products_id()).collect();
+        products.sort_unstable();
+        products.dedup();
+        Ok(products)
+    }
+
+    async fn get_accounts_after(&self, after_id: AccountIdType, filter: &AccountFilter, limit: usize) -> CoreResult<AccountPage> {
+        let accounts: Vec<Account> = self.accounts.read().unwrap()
+            .range((Bound::Excluded(after_id), Bound::Unbounded))
+            .map(|(_, account)| account)
+            .filter(|account| filter.matches(account))
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(AccountPage { scanned: accounts.len(), last_id: accounts.last().map(|account| account.id()), accounts })
+    }
+}
+
+impl StatementRepository for InMemoryRepository {
+    async fn get_latest_statement(&self, accounts_id: AccountIdType) -> CoreResult<Option<AccountStatements>> {
+        Ok(self.statements.read().unwrap()
+            .get(&accounts_id)
+            .and_then(|statements| statements.iter().max_by_key(|s| s.balances_date()))
+            .cloned())
+    }
+
+    async fn get_latest_statements(&self, accounts_ids: &[AccountIdType]) -> CoreResult<HashMap<AccountIdType, AccountStatements>> {
+        let statements = self.statements.read().unwrap();
+        Ok(accounts_ids.iter()
+            .filter_map(|id| statements.get(id)?.iter().max_by_key(|s| s.balances_date()))
+            .map(|statement| (statement.accounts_id(), statement.clone()))
+            .collect())
+    }
+
+    // The write lock on closed_statements is held throughout, like the MySQL transaction
+    async fn close_statement<F>(&self, accounts_id: AccountIdType, cycle: Cycle, build: F) -> CoreResult<Option<bool>>
+    where
+        F: Fn(Decimal, &[StatementTransaction]) -> CoreResult<Option<Statement>> + Send + Sync + 'static,
+    {
+        let mut closed = self.closed_statements.write().unwrap();
+        if closed.contains_key(&(accounts_id, cycle.close)) {
+            return Ok(Some(false));
+        }
+        let opening_balance = closed.range(..(accounts_id, cycle.close))
+            .next_back()
+            .filter(|((id, _), _)| *id == accounts_id)
+            .map(|(_, statement)| statement.closing_balance)
+            .unwrap_or_default();
+        let mut transactions: Vec<StatementTransaction> = self.transactions.read().unwrap()
+            .get(&accounts_id)
+            .map(|transactions| transactions.iter()
+                .filter(|t| t.posted_date >= cycle.open && t.posted_date <= cycle.close)
+                .cloned()
+                .collect())
+            .unwrap_or_default();
+        transactions.sort_by_key(|t| (t.posted_date, t.id));
+
+        match build(opening_balance, &transactions)? {
+            Some(statement) => {
+                closed.insert((accounts_id, cycle.close), statement);
+                self.insert_statement(AccountStatements::new(accounts_id, cycle.close));
+                Ok(Some(true))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl ProductConfigRepository for InMemoryRepository {
+    async fn get_product_configuration(&self, products_id: ProductIdType) -> CoreResult<Option<FullQuery>> {
+        Ok(self.configurations.read().unwrap().get(&products_id).cloned())
+    }
+
+    async fn get_product_configurations(&self) -> CoreResult<Vec<FullQuery>> {
+        Ok(self.configurations.read().unwrap().values().cloned().collect())
+    }
+}
+
+impl BalanceRepository for InMemoryRepository {
+    async fn get_wallet_balances(&self, accounts_id: AccountIdType) -> CoreResult<HashMap<WalletIdType, WalletBalance>> {
+        Ok(self.balances.read().unwrap().get(&accounts_id).cloned().unwrap_or_default())
+    }
+
+    async fn get_recent_authorizations(&self, accounts_id: AccountIdType, since: NaiveDateTime) -> CoreResult<Vec<RecentTransaction>> {
+        let mut authorizations: Vec<RecentTransaction> = self.authorizations.read().unwrap()
+            .get(&accounts_id)
+            .map(|authorizations| authorizations.iter().filter(|a| a.local_datetime >= since).copied().collect())
+            .unwrap_or_default();
+        authorizations.sort_by_key(|a| a.local_datetime);
+        Ok(authorizations)
+    }
+}
+
+impl IdempotencyRepository for InMemoryRepository {
+    async fn get_decision(&self, key: &TransmissionKey, since: NaiveDateTime) -> CoreResult<Option<(NaiveDateTime, AuthorizationDecision)>> {
+        Ok(self.decisions.read().unwrap()
+            .get(key)
+            .filter(|(at, _)| *at >= since)
+            .cloned())
+    }
+
+    async fn insert_decision(&self, key: &TransmissionKey, decision: &AuthorizationDecision, at: NaiveDateTime, since: NaiveDateTime) -> CoreResult<bool> {
+        let mut decisions = self.decisions.write().unwrap();
+        if decisions.get(key).map(|(created_at, _)| *created_at >= since).unwrap_or(false) {
+            return Ok(false);
+        }
+        decisions.insert(key.clone(), (at, decision.clone()));
+        Ok(true)
+    }
+}
+
+impl BatchRunRepository for InMemoryRepository {
+    async fn start_run(&self, key: RunKey, force: bool) -> CoreResult<BatchRun> {
+        let mut runs = self.runs.write().unwrap();
+        let id = runs.len() as u64 + 1;
+        let run = runs.entry(key).or_insert_with(|| BatchRun::new(id, key));
+        if run.is_completed() && !force {
+            return Ok(run.clone());
+        }
+        if run.is_completed() {
+            run.set_last_accounts_id(0);
+        }
+        run.set_status(RunStatus::Running);
+        Ok(run.clone())
+    }
+
+    async fn checkpoint(&self, run: &mut BatchRun, last_accounts_id: AccountIdType) -> CoreResult<()> {
+        let mut runs = self.runs.write().unwrap();
+        let stored = runs.values_mut()
+            .find(|stored| stored.id() == run.id())
+            .filter(|stored| stored.last_accounts_id() == run.last_accounts_id() && stored.status() == RunStatus::Running);
+        match stored {
+            Some(stored) => stored.set_last_accounts_id(last_accounts_id),
+            None if last_accounts_id != run.last_accounts_id() => return Err(CoreError::system_error(
+                format!("Checkpoint of batch run {} is no longer {}", run.id(), run.last_accounts_id()),
+                "data::repository::InMemoryRepository::checkpoint",
+                SystemErrorCodes::LastLogIdChanged
+            )),
+            None => {},
+        }
+        run.set_last_accounts_id(last_accounts_id);
+        Ok(())
+    }
+
+    async fn finish_run(&self, run: &mut BatchRun, status: RunStatus) -> CoreResult<()> {
+        if let Some(stored) = self.runs.write().unwrap().values_mut().find(|stored| stored.id() == run.id()) {
+            stored.set_status(status);
+        }
+        run.set_status(status);
+        Ok(())
+    }
+
+    async fn is_date_completed(&self, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<bool> {
+        Ok(self.completed_dates.read().unwrap().contains(&(run_type, business_date)))
+    }
+
+    async fn complete_date(&self, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<()> {
+        self.completed_dates.write().unwrap().insert((run_type, business_date));
+        Ok(())
+    }
+
+    async fn reopen_date(&self, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<()> {
+        self.completed_dates.write().unwrap().remove(&(run_type, business_date));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::charges_data::{AccountChargesCursor, Shard};
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn account(id: AccountIdType, products_id: ProductIdType) -> Account {
+        Account::new(id, id, products_id, 0, 0, 0, Some(15), Decimal::new(1000, 0), 0.0, 0.0, 0.0, 0.0)
+    }
+
+    fn repository_with_accounts(accounts: &[(AccountIdType, ProductIdType)]) -> InMemoryRepository {
+        let repository = InMemoryRepository::new();
+        for (id, products_id) in accounts {
+            repository.insert_account(account(*id, *products_id));
+        }
+        repository
+    }
+
+    fn statement(accounts_id: AccountIdType, cycle: Cycle, opening_balance: Decimal, transactions: &[StatementTransaction]) -> Statement {
+        let purchases: Decimal = transactions.iter().map(|t| t.amount).sum();
+        Statement {
+            accounts_id,
+            cycle_open: cycle.open,
+            balances_date: cycle.close,
+            opening_balance,
+            purchases,
+            cash_advances: Decimal::ZERO,
+            fees: Decimal::ZERO,
+            payments: Decimal::ZERO,
+            refunds: Decimal::ZERO,
+            charges: Decimal::ZERO,
+            closing_balance: opening_balance + purchases,
+            minimum_payment: Decimal::ZERO,
+            payment_due: cycle.close,
+            lines: Vec::new(),
+        }
+    }
+
+    fn purchase(id: u64, posted_date: NaiveDate, amount: i64) -> StatementTransaction {
+        StatementTransaction { id, wallets_id: 1, posted_date, kind: "purchase".to_string(), amount: Decimal::new(amount, 0) }
+    }
+
+    fn run_key(shard: Shard) -> RunKey {
+        RunKey { run_type: BatchStep::Statements, business_date: date(2024, 3, 15), products_id: 1, shard }
+    }
+
+    #[tokio::test]
+    async fn accounts_after_pages_by_id_and_applies_filter() {
+        let repository = repository_with_accounts(&[(1, 1), (2, 2), (3, 1), (4, 1), (5, 1), (6, 1)]);
+
+        let page = repository.get_accounts_after(0, &AccountFilter::default(), 4).await.unwrap();
+        assert_eq!(page.accounts.iter().map(|a| a.id()).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!((page.scanned, page.last_id), (4, Some(4)));
+
+        let filter = AccountFilter { products_id: Some(1), shard: Some(Shard { index: 1, count: 2 }) };
+        let page = repository.get_accounts_after(1, &filter, 10).await.unwrap();
+        assert_eq!(page.accounts.iter().map(|a| a.id()).collect::<Vec<_>>(), vec![3, 5]);
+        assert_eq!(page.last_id, Some(5));
+
+        let page = repository.get_accounts_after(6, &AccountFilter::default(), 10).await.unwrap();
+        assert_eq!((page.scanned, page.last_id), (0, None));
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_through_repository_with_latest_statements() {
+        let repository = repository_with_accounts(&[(1, 1), (2, 1), (3, 1)]);
+        repository.insert_statement(AccountStatements::new(2, date(2024, 1, 15)));
+        repository.insert_statement(AccountStatements::new(2, date(2024, 2, 15)));
+
+        let mut cursor = AccountChargesCursor::new(2);
+        let first = cursor.next_batch(&repository).await.unwrap().unwrap();
+        assert_eq!(first.len(), 2);
+        assert!(first[0].account_statement().is_none());
+        assert_eq!(first[1].account_statement().unwrap().balances_date(), date(2024, 2, 15));
+
+        let second = cursor.next_batch(&repository).await.unwrap().unwrap();
+        assert_eq!(second.iter().map(|d| d.account().id()).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(cursor.last_id(), 3);
+        assert!(cursor.next_batch(&repository).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn close_statement_uses_previous_balance_and_cycle_transactions_once() {
+        let repository = repository_with_accounts(&[(1, 1)]);
+        repository.insert_transaction(1, purchase(1, date(2024, 1, 10), 100));
+        repository.insert_transaction(1, purchase(2, date(2024, 2, 1), 40));
+        repository.insert_transaction(1, purchase(3, date(2024, 2, 20), 7));
+
+        let january = Cycle { open: date(2023, 12, 16), close: date(2024, 1, 15) };
+        let february = Cycle { open: date(2024, 1, 16), close: date(2024, 2, 15) };
+        let build = |cycle: Cycle| move |opening: Decimal, transactions: &[StatementTransaction]| Ok(Some(statement(1, cycle, opening, transactions)));
+
+        assert_eq!(repository.close_statement(1, january, build(january)).await.unwrap(), Some(true));
+        assert_eq!(repository.close_statement(1, february, build(february)).await.unwrap(), Some(true));
+        assert_eq!(repository.close_statement(1, february, build(february)).await.unwrap(), Some(false));
+
+        let closed = repository.closed_statement(1, february.close).unwrap();
+        assert_eq!(closed.opening_balance, Decimal::new(100, 0));
+        assert_eq!(closed.purchases, Decimal::new(40, 0));
+        assert_eq!(closed.closing_balance, Decimal::new(140, 0));
+        assert_eq!(repository.get_latest_statement(1).await.unwrap().unwrap().balances_date(), february.close);
+    }
+
+    #[tokio::test]
+    async fn close_statement_stores_nothing_when_build_declines() {
+        let repository = repository_with_accounts(&[(1, 1)]);
+        let cycle = Cycle { open: date(2024, 1, 16), close: date(2024, 2, 15) };
+
+        assert_eq!(repository.close_statement(1, cycle, |_, _| Ok(None)).await.unwrap(), None);
+        assert!(repository.closed_statement(1, cycle.close).is_none());
+        assert!(repository.get_latest_statement(1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn checkpoint_from_stale_run_is_rejected() {
+        let repository = InMemoryRepository::new();
+        let key = run_key(Shard { index: 0, count: 1 });
+        let mut first = repository.start_run(key, false).await.unwrap();
+        let mut second = repository.start_run(key, false).await.unwrap();
+        assert_eq!(first.id(), second.id());
+
+        repository.checkpoint(&mut first, 10).await.unwrap();
+        let error = repository.checkpoint(&mut second, 20).await.unwrap_err();
+        assert_eq!(error.system_error, SystemErrorCodes::LastLogIdChanged);
+        assert_eq!(repository.start_run(key, false).await.unwrap().last_accounts_id(), 10);
+    }
+
+    #[tokio::test]
+    async fn completed_run_is_kept_unless_forced() {
+        let repository = InMemoryRepository::new();
+        let key = run_key(Shard { index: 0, count: 2 });
+        let mut run = repository.start_run(key, false).await.unwrap();
+        repository.checkpoint(&mut run, 42).await.unwrap();
+        repository.finish_run(&mut run, RunStatus::Completed).await.unwrap();
+
+        let again = repository.start_run(key, false).await.unwrap();
+        assert!(again.is_completed());
+        assert_eq!(again.last_accounts_id(), 42);
+
+        let forced = repository.start_run(key, true).await.unwrap();
+        assert_eq!(forced.status(), RunStatus::Running);
+        assert_eq!(forced.last_accounts_id(), 0);
+    }
+
+    #[tokio::test]
+    async fn completed_dates_are_keyed_on_run_type_and_date() {
+        let repository = InMemoryRepository::new();
+        let business_date = date(2024, 3, 15);
+        repository.complete_date(BatchStep::Statements, business_date).await.unwrap();
+
+        assert!(repository.is_date_completed(BatchStep::Statements, business_date).await.unwrap());
+        assert!(!repository.is_date_completed(BatchStep::Charges, business_date).await.unwrap());
+
+        repository.reopen_date(BatchStep::Statements, business_date).await.unwrap();
+        assert!(!repository.is_date_completed(BatchStep::Statements, business_date).await.unwrap());
+    }
+}
@@ -0,0 +1,100 @@
+pub mod memory;
+pub mod mysql;
+
+use std::collections::HashMap;
+use std::future::Future;
+use mysql_common::chrono::{NaiveDate, NaiveDateTime};
+use mysql_common::rust_decimal::Decimal;
+use crate::authorization::decision::AuthorizationDecision;
+use crate::authorization::fraud::RecentTransaction;
+use crate::authorization::idempotency::TransmissionKey;
+use crate::batch::ledger::{BatchRun, RunKey, RunStatus};
+use crate::batch::runner::BatchStep;
+use crate::billing::availability::WalletBalance;
+use crate::billing::calendar::Cycle;
+use crate::billing::statements::{Statement, StatementTransaction};
+use crate::data::charges_data::AccountFilter;
+use crate::data::queries::{AccountStatements, FullQuery};
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_datatypes::{AccountIdType, ProductIdType, WalletIdType};
+use crate::utils::CoreResult;
+
+pub use memory::InMemoryRepository;
+pub use mysql::MySqlRepository;
+
+// Business logic should depend on these traits rather than on mysql_async::Conn so it can run
+// against InMemoryRepository in unit tests and local demos
+
+pub trait AccountRepository {
+    // Account with its wallets and parameters hydrated
+    fn get_account(&self, accounts_id: AccountIdType) -> impl Future<Output = CoreResult<Option<Account>>> + Send;
+
+    // Every product that has at least one account
+    fn get_account_products(&self) -> impl Future<Output = CoreResult<Vec<ProductIdType>>> + Send;
+
+    // Keyset page of up to `limit` accounts with ID > after_id matching `filter`, ordered by ID
+    fn get_accounts_after(&self, after_id: AccountIdType, filter: &AccountFilter, limit: usize) -> impl Future<Output = CoreResult<AccountPage>> + Send;
+}
+
+// Accounts that fail to load are left out of `accounts` but still count in `scanned` and
+// `last_id`, so paging moves past them
+#[derive(Debug, Clone, Default)]
+pub struct AccountPage {
+    pub accounts: Vec<Account>,
+    pub scanned: usize,
+    pub last_id: Option<AccountIdType>,
+}
+
+pub trait StatementRepository {
+    fn get_latest_statement(&self, accounts_id: AccountIdType) -> impl Future<Output = CoreResult<Option<AccountStatements>>> + Send;
+
+    // Latest statement of each account that has one
+    fn get_latest_statements(&self, accounts_ids: &[AccountIdType]) -> impl Future<Output = CoreResult<HashMap<AccountIdType, AccountStatements>>> + Send;
+
+    // Closes `cycle` atomically: `build` gets the previous closing balance and the cycle's
+    // transactions, and the statement it returns is stored. Some(false) when a statement for
+    // cycle.close already exists, None when `build` returns None
+    fn close_statement<F>(&self, accounts_id: AccountIdType, cycle: Cycle, build: F) -> impl Future<Output = CoreResult<Option<bool>>> + Send
+    where
+        F: Fn(Decimal, &[StatementTransaction]) -> CoreResult<Option<Statement>> + Send + Sync + 'static;
+}
+
+pub trait ProductConfigRepository {
+    fn get_product_configuration(&self, products_id: ProductIdType) -> impl Future<Output = CoreResult<Option<FullQuery>>> + Send;
+
+    fn get_product_configurations(&self) -> impl Future<Output = CoreResult<Vec<FullQuery>>> + Send;
+}
+
+pub trait BalanceRepository {
+    // Wallets without a balance row are left out, callers treat them as empty
+    fn get_wallet_balances(&self, accounts_id: AccountIdType) -> impl Future<Output = CoreResult<HashMap<WalletIdType, WalletBalance>>> + Send;
+
+    // Approved authorizations at or after `since`, oldest first
+    fn get_recent_authorizations(&self, accounts_id: AccountIdType, since: NaiveDateTime) -> impl Future<Output = CoreResult<Vec<RecentTransaction>>> + Send;
+}
+
+pub trait IdempotencyRepository {
+    // Decision recorded for `key` at or after `since`, with the time it was recorded
+    fn get_decision(&self, key: &TransmissionKey, since: NaiveDateTime) -> impl Future<Output = CoreResult<Option<(NaiveDateTime, AuthorizationDecision)>>> + Send;
+
+    // Records older than `since` are replaced. False when a live one already exists, the
+    // stored decision wins then
+    fn insert_decision(&self, key: &TransmissionKey, decision: &AuthorizationDecision, at: NaiveDateTime, since: NaiveDateTime) -> impl Future<Output = CoreResult<bool>> + Send;
+}
+
+pub trait BatchRunRepository {
+    // Claims the ledger row for `key` and marks it running. An unfinished run keeps its checkpoint,
+    // a completed one is returned untouched unless `force`, which restarts it from the first account
+    fn start_run(&self, key: RunKey, force: bool) -> impl Future<Output = CoreResult<BatchRun>> + Send;
+
+    // Fails with LastLogIdChanged when the stored checkpoint is no longer the one `run` holds
+    fn checkpoint(&self, run: &mut BatchRun, last_accounts_id: AccountIdType) -> impl Future<Output = CoreResult<()>> + Send;
+
+    fn finish_run(&self, run: &mut BatchRun, status: RunStatus) -> impl Future<Output = CoreResult<()>> + Send;
+
+    fn is_date_completed(&self, run_type: BatchStep, business_date: NaiveDate) -> impl Future<Output = CoreResult<bool>> + Send;
+
+    fn complete_date(&self, run_type: BatchStep, business_date: NaiveDate) -> impl Future<Output = CoreResult<()>> + Send;
+
+    fn reopen_date(&self, run_type: BatchStep, business_date: NaiveDate) -> impl Future<Output = CoreResult<()>> + Send;
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use mysql_async::{Params, Value};
+use mysql_async::prelude::Queryable;
+use mysql_common::chrono::{NaiveDate, NaiveDateTime};
+use mysql_common::row::Row;
+use mysql_common::rust_decimal::Decimal;
+use my_own_tests_derive::FromRow;
+use crate::authorization::blocks::Operation;
+use crate::authorization::decision::AuthorizationDecision;
+use crate::authorization::fraud::RecentTransaction;
+use crate::authorization::idempotency::TransmissionKey;
+use crate::batch::ledger::{self, BatchRun, RunKey, RunStatus};
+use crate::batch::runner::BatchStep;
+use crate::billing::availability::WalletBalance;
+use crate::billing::calendar::Cycle;
+use crate::billing::statements::{close_cycle, Statement, StatementTransaction};
+use crate::data::charges_data::AccountFilter;
+use crate::data::get_conn;
+use crate::data::parameters::load_parameters;
+use crate::data::queries::{AccountStatements, FullQuery};
+use crate::data::transaction::{is_duplicate_key, tx_query_error_as, with_transaction, TransactionOptions};
+use crate::data::repository::{AccountPage, AccountRepository, BalanceRepository, BatchRunRepository, IdempotencyRepository, ProductConfigRepository, StatementRepository};
+use crate::data::wallets::{get_account_with_wallets, load_wallets};
+use crate::datatypes::response_codes::ResponseCodes;
+use crate::datatypes::structs::Account;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, ProductIdType, WalletIdType};
+use crate::try_extract_value;
+use crate::utils::{CoreError, CoreResult};
+
+// Takes a connection from DB_POOL per call, so init_pool must have run first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MySqlRepository;
+
+impl AccountRepository for MySqlRepository {
+    async fn get_account(&self, accounts_id: AccountIdType) -> CoreResult<Option<Account>> {
+        let mut conn = get_conn().await?;
+        let mut account = match get_account_with_wallets(&mut conn, accounts_id).await? {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+        if let Some(e) = load_parameters(&mut conn, std::slice::from_mut(&mut account)).await?.into_values().next() {
+            return Err(e);
+        }
+        Ok(Some(account))
+    }
+
+    async fn get_account_products(&self) -> CoreResult<Vec<ProductIdType>> {
+        let mut conn = get_conn().await?;
+        conn.query::<ProductIdType, _>("SELECT DISTINCT products_ID FROM accounts ORDER BY products_ID")
+            .await
+            .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_account_products", SystemErrorCodes::DbQuery(29)))
+    }
+
+    async fn get_accounts_after(&self, after_id: AccountIdType, filter: &AccountFilter, limit: usize) -> CoreResult<AccountPage> {
+        let mut query = String::from("SELECT * FROM accounts WHERE ID > ?");
+        let mut params = vec![Value::from(after_id)];
+        if let Some(products_id) = filter.products_id {
+            query.push_str(" AND products_ID = ?");
+            params.push(Value::from(products_id));
+        }
+        if let Some(shard) = filter.shard.filter(|shard| shard.count > 1) {
+            query.push_str(" AND MOD(ID, ?) = ?");
+            params.push(Value::from(shard.count));
+            params.push(Value::from(shard.index));
+        }
+        query.push_str(" ORDER BY ID LIMIT ?");
+        params.push(Value::from(limit as u64));
+
+        let mut conn = get_conn().await?;
+        let rows = conn.exec::<Row, _, _>(query, Params::Positional(params))
+            .await
+            .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_accounts_after", SystemErrorCodes::DbQuery(4)))?;
+
+        let mut page = AccountPage { accounts: Vec::with_capacity(rows.len()), scanned: rows.len(), last_id: None };
+        for row in rows.iter() {
+            let id = try_extract_value!(row, "ID", "accounts", AccountIdType)?;
+            page.last_id = Some(id);
+            match Account::try_from_row(row) {
+                Ok(account) => page.accounts.push(account),
+                Err(e) => println!("Skipping account {}: {}", id, e),
+            }
+        }
+
+        let mut failed = load_wallets(&mut conn, &mut page.accounts).await?;
+        failed.extend(load_parameters(&mut conn, &mut page.accounts).await?);
+        page.accounts.retain(|account| match failed.get(&account.id()) {
+            Some(e) => {
+                println!("Skipping account {}: {}", account.id(), e);
+                false
+            },
+            None => true,
+        });
+        Ok(page)
+    }
+}
+
+impl StatementRepository for MySqlRepository {
+    async fn get_latest_statement(&self, accounts_id: AccountIdType) -> CoreResult<Option<AccountStatements>> {
+        let mut conn = get_conn().await?;
+        conn.exec_first::<Row, _, _>(
+            "SELECT * FROM account_statements WHERE accounts_id = ? ORDER BY balances_date DESC, ID DESC LIMIT 1",
+            (accounts_id,)
+        ).await
+            .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_latest_statement", SystemErrorCodes::DbQuery(5)))?
+            .map(|row| AccountStatements::try_from_row(&row))
+            .transpose()
+    }
+
+    // Ties on balances_date are broken by the highest ID
+    async fn get_latest_statements(&self, accounts_ids: &[AccountIdType]) -> CoreResult<HashMap<AccountIdType, AccountStatements>> {
+        if accounts_ids.is_empty() { return Ok(HashMap::new()) }
+
+        let placeholders = vec!["?"; accounts_ids.len()].join(", ");
+        let params: Vec<Value> = accounts_ids.iter().map(|id| Value::from(*id)).collect();
+
+        let mut conn = get_conn().await?;
+        let rows = conn.exec::<Row, _, _>(
+            format!(
+                "SELECT s.* FROM account_statements s \
+                JOIN (\
+                    SELECT MAX(d.ID) AS ID FROM account_statements d \
+                    JOIN (\
+                        SELECT accounts_id, MAX(balances_date) AS balances_date FROM account_statements \
+                        WHERE accounts_id IN ({}) GROUP BY accounts_id\
+                    ) latest ON latest.accounts_id = d.accounts_id AND latest.balances_date = d.balances_date \
+                    GROUP BY d.accounts_id\
+                ) picked ON picked.ID = s.ID",
+                placeholders
+            ),
+            Params::Positional(params)
+        ).await.map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_latest_statements", SystemErrorCodes::DbQuery(10)))?;
+
+        rows.iter()
+            .map(|row| AccountStatements::try_from_row(row).map(|statement| (statement.accounts_id(), statement)))
+            .collect()
+    }
+
+    async fn close_statement<F>(&self, accounts_id: AccountIdType, cycle: Cycle, build: F) -> CoreResult<Option<bool>>
+    where
+        F: Fn(Decimal, &[StatementTransaction]) -> CoreResult<Option<Statement>> + Send + Sync + 'static,
+    {
+        close_cycle(accounts_id, cycle, build).await
+    }
+}
+
+impl ProductConfigRepository for MySqlRepository {
+    async fn get_product_configuration(&self, products_id: ProductIdType) -> CoreResult<Option<FullQuery>> {
+        let mut conn = get_conn().await?;
+        conn.exec_first::<Row, _, _>(
+            "SELECT * FROM products_statements_configurations WHERE products_ID = ?",
+            (products_id,)
+        ).await
+            .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_product_configuration", SystemErrorCodes::DbQuery(6)))?
+            .map(|row| FullQuery::try_from_row(&row))
+            .transpose()
+    }
+
+    async fn get_product_configurations(&self) -> CoreResult<Vec<FullQuery>> {
+        let mut conn = get_conn().await?;
+        conn.query::<Row, _>("SELECT * FROM products_statements_configurations")
+            .await
+            .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_product_configurations", SystemErrorCodes::DbQuery(7)))?
+            .iter()
+            .map(FullQuery::try_from_row)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "wallet_balances")]
+struct WalletBalanceRow {
+    #[from_row(rename = "wallets_ID")]
+    wallets_id: WalletIdType,
+    #[from_row(decimal, default)]
+    posted: Decimal,
+    #[from_row(decimal, default)]
+    pending: Decimal,
+    #[from_row(decimal, default)]
+    cash_posted: Decimal,
+    #[from_row(decimal, default)]
+    cash_pending: Decimal,
+}
+
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "authorizations")]
+struct AuthorizationRow {
+    local_datetime: NaiveDateTime,
+    #[from_row(decimal)]
+    amount: Decimal,
+    operation: String,
+}
+
+impl BalanceRepository for MySqlRepository {
+    async fn get_wallet_balances(&self, accounts_id: AccountIdType) -> CoreResult<HashMap<WalletIdType, WalletBalance>> {
+        let mut conn = get_conn().await?;
+        conn.exec::<Row, _, _>(
+            "SELECT b.wallets_ID, b.posted, b.pending, b.cash_posted, b.cash_pending FROM wallet_balances b \
+            JOIN wallets w ON w.ID = b.wallets_ID WHERE w.accounts_ID = ?",
+            (accounts_id,)
+        ).await
+            .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_wallet_balances", SystemErrorCodes::DbQuery(20)))?
+            .iter()
+            .map(|row| WalletBalanceRow::try_from_row(row).map(|b| (b.wallets_id, WalletBalance {
+                posted: b.posted,
+                pending: b.pending,
+                cash_posted: b.cash_posted,
+                cash_pending: b.cash_pending,
+            })))
+            .collect()
+    }
+
+    async fn get_recent_authorizations(&self, accounts_id: AccountIdType, since: NaiveDateTime) -> CoreResult<Vec<RecentTransaction>> {
+        let mut conn = get_conn().await?;
+        let rows = conn.exec::<Row, _, _>(
+            "SELECT local_datetime, amount, operation FROM authorizations \
+            WHERE accounts_ID = ? AND local_datetime >= ? AND response_code IN (?, ?) ORDER BY local_datetime",
+            (accounts_id, since, ResponseCodes::Approved as u8, ResponseCodes::PartialApproval as u8)
+        ).await.map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_recent_authorizations", SystemErrorCodes::DbQuery(21)))?;
+
+        let mut authorizations = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let authorization = AuthorizationRow::try_from_row(row)?;
+            let operation = Operation::from_code(&authorization.operation)
+                .ok_or_else(|| CoreError::system_error(
+                    format!("Unknown authorization operation {}", authorization.operation),
+                    "data::repository::MySqlRepository::get_recent_authorizations",
+                    SystemErrorCodes::UnknownOperation
+                ))?;
+            authorizations.push(RecentTransaction { local_datetime: authorization.local_datetime, amount: authorization.amount, operation });
+        }
+        Ok(authorizations)
+    }
+}
+
+// authorization_idempotency has a unique key on (acquirer_id, stan, transmission_date_time, terminal_id)
+impl IdempotencyRepository for MySqlRepository {
+    async fn get_decision(&self, key: &TransmissionKey, since: NaiveDateTime) -> CoreResult<Option<(NaiveDateTime, AuthorizationDecision)>> {
+        let mut conn = get_conn().await?;
+        let decision = conn.exec_first::<(NaiveDateTime, String), _, _>(
+            "SELECT created_at, decision FROM authorization_idempotency \
+            WHERE acquirer_id = ? AND stan = ? AND transmission_date_time = ? AND terminal_id = ? AND created_at >= ?",
+            (&key.acquirer_id, &key.stan, &key.transmission_date_time, &key.terminal_id, since)
+        ).await.map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_decision", SystemErrorCodes::DbQuery(22)))?;
+
+        decision
+            .map(|(created_at, json)| serde_json::from_str::<AuthorizationDecision>(&json)
+                .map(|decision| (created_at, decision))
+                .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::get_decision", SystemErrorCodes::JsonParse(3))))
+            .transpose()
+    }
+
+    async fn insert_decision(&self, key: &TransmissionKey, decision: &AuthorizationDecision, at: NaiveDateTime, since: NaiveDateTime) -> CoreResult<bool> {
+        let json = serde_json::to_string(decision)
+            .map_err(|e| CoreError::system_error(e, "data::repository::MySqlRepository::insert_decision", SystemErrorCodes::JsonParse(4)))?;
+        let key = key.clone();
+        with_transaction(&TransactionOptions::default(), move |tx| {
+            let (key, json) = (key.clone(), json.clone());
+            Box::pin(async move {
+                let key_params = (&key.acquirer_id, &key.stan, &key.transmission_date_time, &key.terminal_id);
+                let created_at = tx.exec_first::<NaiveDateTime, _, _>(
+                    "SELECT created_at FROM authorization_idempotency \
+                    WHERE acquirer_id = ? AND stan = ? AND transmission_date_time = ? AND terminal_id = ? FOR UPDATE",
+                    key_params
+                ).await.map_err(|e| tx_query_error_as(e, "data::repository::MySqlRepository::insert_decision", SystemErrorCodes::DbQuery(30)))?;
+
+                match created_at {
+                    Some(created_at) if created_at >= since => Ok(false),
+                    // Expired entry of a reused key
+                    Some(_) => {
+                        tx.exec_drop(
+                            "UPDATE authorization_idempotency SET decision = ?, created_at = ? \
+                            WHERE acquirer_id = ? AND stan = ? AND transmission_date_time = ? AND terminal_id = ?",
+                            (&json, at, key_params.0, key_params.1, key_params.2, key_params.3)
+                        ).await.map_err(|e| tx_query_error_as(e, "data::repository::MySqlRepository::insert_decision", SystemErrorCodes::DbQuery(31)))?;
+                        Ok(true)
+                    },
+                    None => match tx.exec_drop(
+                        "INSERT INTO authorization_idempotency \
+                        (acquirer_id, stan, transmission_date_time, terminal_id, decision, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+                        (key_params.0, key_params.1, key_params.2, key_params.3, &json, at)
+                    ).await {
+                        Ok(()) => Ok(true),
+                        // A concurrent transmission of the key inserted it first
+                        Err(e) if is_duplicate_key(&e) => Ok(false),
+                        Err(e) => Err(tx_query_error_as(e, "data::repository::MySqlRepository::insert_decision", SystemErrorCodes::DbQuery(23))),
+                    },
+                }
+            })
+        }).await
+    }
+}
+
+impl BatchRunRepository for MySqlRepository {
+    async fn start_run(&self, key: RunKey, force: bool) -> CoreResult<BatchRun> {
+        ledger::start_run(key, force).await
+    }
+
+    async fn checkpoint(&self, run: &mut BatchRun, last_accounts_id: AccountIdType) -> CoreResult<()> {
+        ledger::checkpoint(&mut get_conn().await?, run, last_accounts_id).await
+    }
+
+    async fn finish_run(&self, run: &mut BatchRun, status: RunStatus) -> CoreResult<()> {
+        ledger::finish_run(&mut get_conn().await?, run, status).await
+    }
+
+    async fn is_date_completed(&self, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<bool> {
+        ledger::is_date_completed(&mut get_conn().await?, run_type, business_date).await
+    }
+
+    async fn complete_date(&self, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<()> {
+        ledger::complete_date(&mut get_conn().await?, run_type, business_date).await
+    }
+
+    async fn reopen_date(&self, run_type: BatchStep, business_date: NaiveDate) -> CoreResult<()> {
+        ledger::reopen_date(&mut get_conn().await?, run_type, business_date).await
+    }
+}
@@ -0,0 +1,215 @@
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::time::Duration;
+use futures::FutureExt;
+use mysql_async::{IsolationLevel, Transaction, TxOpts};
+use crate::data::get_conn;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::{CoreError, CoreResult};
+
+// MySQL server errors that abort the transaction but are safe to retry from scratch
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+const ER_LOCK_DEADLOCK: u16 = 1213;
+const ER_DUP_ENTRY: u16 = 1062;
+
+// Sub-codes reported through SystemErrorCodes::DbTransaction/DbCommit/DbRollback
+const TX_BEGIN: u8 = 1;
+const TX_RETRIES_EXHAUSTED: u8 = 2;
+const TX_DEADLOCK: u8 = 4;
+const COMMIT_FAILED: u8 = 1;
+const ROLLBACK_FAILED: u8 = 1;
+
+pub type TransactionFuture<'a, T> = Pin<Box<dyn Future<Output = CoreResult<T>> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionOptions {
+    pub isolation_level: Option<IsolationLevel>,
+    pub max_deadlock_retries: u8,
+    pub retry_backoff: Duration,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        TransactionOptions {
+            isolation_level: None,
+            max_deadlock_retries: 3,
+            retry_backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+impl TransactionOptions {
+    pub fn with_isolation_level(mut self, isolation_level: IsolationLevel) -> Self {
+        self.isolation_level = Some(isolation_level);
+        self
+    }
+}
+
+// Runs `f` inside a transaction on a DB_POOL connection: commits on Ok, rolls back on Err, rolls
+// back and re-raises on panic, and starts over (up to max_deadlock_retries) when the server reports a deadlock or lock wait timeout.
+// Queries inside `f` should map their errors with `tx_query_error` so deadlocks can be recognised.
+//
+//     with_transaction(&TransactionOptions::default(), |tx| Box::pin(async move {
+//         tx.exec_drop("UPDATE ...", params).await.map_err(|e| tx_query_error(e, "my::location"))?;
+//         Ok(())
+//     })).await?;
+pub async fn with_transaction<T, F>(options: &TransactionOptions, mut f: F) -> CoreResult<T>
+    where
+        F: for<'a, 't> FnMut(&'a mut Transaction<'t>) -> TransactionFuture<'a, T>,
+{
+    let mut attempt: u8 = 0;
+    loop {
+        let mut conn = get_conn().await?;
+        let mut tx_opts = TxOpts::default();
+        tx_opts.with_isolation_level(options.isolation_level);
+
+        let mut tx = conn.start_transaction(tx_opts).await
+            .map_err(|e| CoreError::system_error(e, "data::transaction::with_transaction", SystemErrorCodes::DbTransaction(TX_BEGIN)))?;
+
+        let result = AssertUnwindSafe(f(&mut tx)).catch_unwind().await;
+
+        let error = match result {
+            Ok(Ok(value)) => match tx.commit().await {
+                Ok(()) => return Ok(value),
+                Err(e) => {
+                    let code = commit_error_code(server_code(&e));
+                    CoreError::system_error(e, "data::transaction::with_transaction", code)
+                },
+            },
+            Ok(Err(error)) => {
+                tx.rollback().await
+                    .map_err(|e| CoreError::system_error(
+                        format!("{} (rolling back after: {})", e, error),
+                        "data::transaction::with_transaction",
+                        SystemErrorCodes::DbRollback(ROLLBACK_FAILED)
+                    ))?;
+                error
+            },
+            Err(panic) => {
+                // The panic is the caller's bug, not a DB error: roll back so the connection goes
+                // back to the pool clean, then let it unwind
+                if let Err(e) = tx.rollback().await {
+                    println!("Rollback after panic failed: {} (panic: {})", e, panic_message(panic.as_ref()));
+                }
+                std::panic::resume_unwind(panic);
+            },
+        };
+
+        match retry_decision(&error.system_error, attempt, options) {
+            RetryDecision::Fail => return Err(error),
+            RetryDecision::Exhausted => return Err(CoreError::system_error(
+                format!("Gave up after {} retries: {}", attempt, error),
+                "data::transaction::with_transaction",
+                SystemErrorCodes::DbTransaction(TX_RETRIES_EXHAUSTED)
+            )),
+            RetryDecision::Retry(backoff) => {
+                attempt += 1;
+                tokio::time::sleep(backoff).await;
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RetryDecision {
+    // Start over after waiting
+    Retry(Duration),
+    // Return the error as is
+    Fail,
+    // Deadlocked on every attempt
+    Exhausted,
+}
+
+// `attempt` is the number of retries already made; the backoff doubles on each one
+fn retry_decision(error: &SystemErrorCodes, attempt: u8, options: &TransactionOptions) -> RetryDecision {
+    if *error != SystemErrorCodes::DbTransaction(TX_DEADLOCK) {
+        RetryDecision::Fail
+    } else if attempt >= options.max_deadlock_retries {
+        RetryDecision::Exhausted
+    } else {
+        RetryDecision::Retry(options.retry_backoff * 2u32.pow(attempt as u32))
+    }
+}
+
+// Maps a query error raised inside a transaction; deadlocks get a dedicated code so
+// with_transaction retries them
+pub fn tx_query_error(e: mysql_async::Error, at: &str) -> CoreError {
+    tx_query_error_as(e, at, SystemErrorCodes::DbQuery(8))
+}
+
+// Same as tx_query_error, with the caller's own code for errors that aren't retried
+pub fn tx_query_error_as(e: mysql_async::Error, at: &str, code: SystemErrorCodes) -> CoreError {
+    let code = query_error_code(server_code(&e), code);
+    CoreError::system_error(e, at, code)
+}
+
+// The row hit a unique key. The statement is rolled back but the transaction stays usable
+pub fn is_duplicate_key(e: &mysql_async::Error) -> bool {
+    server_code(e) == Some(ER_DUP_ENTRY)
+}
+
+// MySQL error number for server errors, None for driver and connection errors
+fn server_code(e: &mysql_async::Error) -> Option<u16> {
+    match e {
+        mysql_async::Error::Server(server) => Some(server.code),
+        _ => None,
+    }
+}
+
+fn is_retryable(server_code: Option<u16>) -> bool {
+    matches!(server_code, Some(ER_LOCK_DEADLOCK) | Some(ER_LOCK_WAIT_TIMEOUT))
+}
+
+fn query_error_code(server_code: Option<u16>, code: SystemErrorCodes) -> SystemErrorCodes {
+    if is_retryable(server_code) { SystemErrorCodes::DbTransaction(TX_DEADLOCK) } else { code }
+}
+
+fn commit_error_code(server_code: Option<u16>) -> SystemErrorCodes {
+    query_error_code(server_code, SystemErrorCodes::DbCommit(COMMIT_FAILED))
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadlocks_and_lock_wait_timeouts_are_retried() {
+        assert_eq!(query_error_code(Some(ER_LOCK_DEADLOCK), SystemErrorCodes::DbQuery(8)), SystemErrorCodes::DbTransaction(TX_DEADLOCK));
+        assert_eq!(query_error_code(Some(ER_LOCK_WAIT_TIMEOUT), SystemErrorCodes::DbQuery(8)), SystemErrorCodes::DbTransaction(TX_DEADLOCK));
+        assert_eq!(query_error_code(Some(ER_DUP_ENTRY), SystemErrorCodes::DbQuery(8)), SystemErrorCodes::DbQuery(8));
+        assert_eq!(query_error_code(None, SystemErrorCodes::DbQuery(23)), SystemErrorCodes::DbQuery(23));
+
+        assert_eq!(commit_error_code(Some(ER_LOCK_DEADLOCK)), SystemErrorCodes::DbTransaction(TX_DEADLOCK));
+        assert_eq!(commit_error_code(Some(ER_DUP_ENTRY)), SystemErrorCodes::DbCommit(COMMIT_FAILED));
+        assert_eq!(commit_error_code(None), SystemErrorCodes::DbCommit(COMMIT_FAILED));
+    }
+
+    #[test]
+    fn retries_back_off_until_exhausted() {
+        let options = TransactionOptions::default();
+        let deadlock = SystemErrorCodes::DbTransaction(TX_DEADLOCK);
+
+        assert_eq!(retry_decision(&deadlock, 0, &options), RetryDecision::Retry(Duration::from_millis(50)));
+        assert_eq!(retry_decision(&deadlock, 1, &options), RetryDecision::Retry(Duration::from_millis(100)));
+        assert_eq!(retry_decision(&deadlock, 2, &options), RetryDecision::Retry(Duration::from_millis(200)));
+        assert_eq!(retry_decision(&deadlock, 3, &options), RetryDecision::Exhausted);
+
+        let no_retries = TransactionOptions { max_deadlock_retries: 0, ..options };
+        assert_eq!(retry_decision(&deadlock, 0, &no_retries), RetryDecision::Exhausted);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let options = TransactionOptions::default();
+        for error in [SystemErrorCodes::DbQuery(8), SystemErrorCodes::DbCommit(COMMIT_FAILED), SystemErrorCodes::DbTransaction(TX_BEGIN)] {
+            assert_eq!(retry_decision(&error, 0, &options), RetryDecision::Fail, "{:?}", error);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use mysql_async::{Conn, Params, Value};
+use mysql_async::prelude::Queryable;
+use mysql_common::row::Row;
+use my_own_tests_derive::FromRow;
+use crate::datatypes::structs::{Account, Wallet};
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, CurrenciesIdType, WalletIdType};
+use crate::utils::{CoreError, CoreResult};
+
+// Wallet columns are aliased so they can ride along with `accounts.*` in a single join.
+// currencies_ID is only NULL when the wallet points at a currency that no longer exists
+const WALLET_COLUMNS: &str = "w.ID AS wallets_ID, w.accounts_ID AS wallets_accounts_ID, \
+    w.currencies_ID AS wallets_currencies_ID, w.charge_priority AS wallets_charge_priority, \
+    c.ID AS currencies_ID";
+
+#[derive(Debug, FromRow)]
+#[from_row(table = "wallets")]
+struct WalletRow {
+    #[from_row(rename = "wallets_ID")]
+    id: Option<WalletIdType>,
+    #[from_row(rename = "wallets_accounts_ID")]
+    accounts_id: Option<AccountIdType>,
+    #[from_row(rename = "wallets_currencies_ID")]
+    currencies_id: Option<CurrenciesIdType>,
+    #[from_row(rename = "wallets_charge_priority")]
+    charge_priority: Option<i16>,
+    #[from_row(rename = "currencies_ID")]
+    known_currency: Option<CurrenciesIdType>,
+}
+
+impl WalletRow {
+    // None when the LEFT JOIN found no wallet at all for the account
+    fn into_wallet(self) -> CoreResult<Option<(AccountIdType, Wallet)>> {
+        let id = match self.id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let (accounts_id, currencies_id) = match (self.accounts_id, self.currencies_id, self.known_currency) {
+            (Some(accounts_id), Some(currencies_id), Some(_)) => (accounts_id, currencies_id),
+            _ => return Err(CoreError::system_error(
+                format!("Wallet {} references account {:?} and currency {:?} which do not exist", id, self.accounts_id, self.currencies_id),
+                "data::wallets::WalletRow::into_wallet",
+                SystemErrorCodes::UnknownWalletsId
+            )),
+        };
+        // A default would put the wallet first in the waterfall
+        let charge_priority = self.charge_priority.ok_or_else(|| CoreError::system_error(
+            format!("Wallet {} has no charge_priority", id),
+            "data::wallets::WalletRow::into_wallet",
+            SystemErrorCodes::BadFormat
+        ))?;
+
+        Ok(Some((accounts_id, Wallet::new(id, currencies_id, charge_priority))))
+    }
+}
+
+// Loads an account and all its wallets in one round trip
+pub async fn get_account_with_wallets(conn: &mut Conn, accounts_id: AccountIdType) -> CoreResult<Option<Account>> {
+    let rows = conn.exec::<Row, _, _>(
+        format!(
+            "SELECT a.*, {} FROM accounts a \
+            LEFT JOIN wallets w ON w.accounts_ID = a.ID \
+            LEFT JOIN currencies c ON c.ID = w.currencies_ID \
+            WHERE a.ID = ?",
+            WALLET_COLUMNS
+        ),
+        (accounts_id,)
+    ).await.map_err(|e| CoreError::system_error(e, "data::wallets::get_account_with_wallets", SystemErrorCodes::DbQuery(1)))?;
+
+    let mut account = match rows.first() {
+        Some(row) => Account::try_from_row(row)?,
+        None => return Ok(None),
+    };
+
+    for row in rows.iter() {
+        if let Some((_, wallet)) = WalletRow::try_from_row(row)?.into_wallet()? {
+            account.wallets_mut().insert(wallet.id(), wallet);
+        }
+    }
+
+    Ok(Some(account))
+}
+
+// Batched second query for callers that already hold a page of accounts. Returns the accounts
+// whose wallets couldn't be loaded, e.g. a wallet pointing at a deleted currency, so the caller
+// can drop just those; their wallet maps are incomplete
+pub async fn load_wallets(conn: &mut Conn, accounts: &mut [Account]) -> CoreResult<HashMap<AccountIdType, CoreError>> {
+    let mut failed = HashMap::new();
+    if accounts.is_empty() { return Ok(failed) }
+
+    let mut by_id: HashMap<AccountIdType, &mut Account> = accounts
+        .iter_mut()
+        .map(|account| (account.id(), account))
+        .collect();
+
+    let placeholders = vec!["?"; by_id.len()].join(", ");
+    let params: Vec<Value> = by_id.keys().map(|id| Value::from(*id)).collect();
+
+    let rows = conn.exec::<Row, _, _>(
+        format!(
+            "SELECT {} FROM wallets w \
+            LEFT JOIN currencies c ON c.ID = w.currencies_ID \
+            WHERE w.accounts_ID IN ({})",
+            WALLET_COLUMNS,
+            placeholders
+        ),
+        Params::Positional(params)
+    ).await.map_err(|e| CoreError::system_error(e, "data::wallets::load_wallets", SystemErrorCodes::DbQuery(2)))?;
+
+    for row in rows.iter() {
+        let wallet_row = WalletRow::try_from_row(row)?;
+        let owner = wallet_row.accounts_id;
+        let (accounts_id, wallet) = match (wallet_row.into_wallet(), owner) {
+            (Ok(Some(wallet)), _) => wallet,
+            (Ok(None), _) => continue,
+            (Err(e), Some(accounts_id)) => {
+                failed.entry(accounts_id).or_insert(e);
+                continue;
+            },
+            (Err(e), None) => return Err(e),
+        };
+        let account = by_id.get_mut(&accounts_id).ok_or_else(|| CoreError::system_error(
+            format!("Wallet {} belongs to account {} which was not requested", wallet.id(), accounts_id),
+            "data::wallets::load_wallets",
+            SystemErrorCodes::UnknownWalletsId
+        ))?;
+        account.wallets_mut().insert(wallet.id(), wallet);
+    }
+
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(accounts_id: Option<AccountIdType>, currencies_id: Option<CurrenciesIdType>, charge_priority: Option<i16>, known_currency: Option<CurrenciesIdType>) -> WalletRow {
+        WalletRow { id: Some(10), accounts_id, currencies_id, charge_priority, known_currency }
+    }
+
+    #[test]
+    fn complete_rows_become_wallets() {
+        let (accounts_id, wallet) = row(Some(1), Some(978), Some(2), Some(978)).into_wallet().unwrap().unwrap();
+        assert_eq!((accounts_id, wallet.id(), wallet.currencies_id(), wallet.charge_priority()), (1, 10, 978, 2));
+    }
+
+    #[test]
+    fn accounts_without_wallets_yield_nothing() {
+        let empty = WalletRow { id: None, accounts_id: None, currencies_id: None, charge_priority: None, known_currency: None };
+        assert!(empty.into_wallet().unwrap().is_none());
+    }
+
+    #[test]
+    fn orphan_wallets_are_reported() {
+        // Deleted currency, missing account, NULL currency
+        for orphan in [row(Some(1), Some(978), Some(1), None), row(None, Some(978), Some(1), Some(978)), row(Some(1), None, Some(1), None)] {
+            assert_eq!(orphan.into_wallet().unwrap_err().system_error, SystemErrorCodes::UnknownWalletsId);
+        }
+    }
+
+    #[test]
+    fn missing_charge_priority_is_rejected() {
+        assert_eq!(row(Some(1), Some(978), None, Some(978)).into_wallet().unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+}
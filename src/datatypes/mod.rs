@@ -1,8 +1,29 @@
 pub mod system_codes;
 pub mod response_codes;
+pub mod response_mapping;
 pub mod system_datatypes;
 pub mod structs;
 
+use std::fmt::Display;
+use serde::de::DeserializeOwned;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::{CoreError, CoreResult};
+
 pub fn expect_sql_field(field_name: &'static str, struct_name: &'static str) -> String {
     format!("Expected field {} AT {}", field_name, struct_name)
-}
\ No newline at end of file
+}
+
+pub fn sql_field_error<T: Display>(detail: T, struct_name: &'static str) -> CoreError {
+    CoreError::system_error(detail, struct_name, SystemErrorCodes::BadFormat)
+}
+
+pub fn parse_json_field<T: DeserializeOwned>(value: Option<String>, field_name: &'static str, struct_name: &'static str) -> CoreResult<Option<T>> {
+    value
+        .map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|e| CoreError::system_error(
+            format!("{}: parsing {} from {}", e, field_name, struct_name),
+            struct_name,
+            SystemErrorCodes::JsonParse(1)
+        ))
+}
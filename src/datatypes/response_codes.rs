@@ -2,6 +2,16 @@ use std::fmt;
 use std::fmt::Formatter;
 use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResponseCategory {
+    Approved,
+    SoftDecline,
+    HardDecline,
+    PickUp,
+    Retryable,
+    System,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResponseCodes {
     Approved = 0,
@@ -63,6 +73,110 @@ impl Default for ResponseCodes {
 }
 
 impl ResponseCodes {
+    pub fn description(&self) -> &'static str {
+        match self {
+            ResponseCodes::Approved => "Approved",
+            ResponseCodes::ReferToCardIssuer => "Refer to card issuer",
+            ResponseCodes::InvalidMerchant => "Invalid merchant",
+            ResponseCodes::CaptureCard => "Capture card",
+            ResponseCodes::DoNotHonor => "Do not honor",
+            ResponseCodes::HonorWithId => "Honor with identification",
+            ResponseCodes::PartialApproval => "Partial approval",
+            ResponseCodes::InvalidTransaction => "Invalid transaction",
+            ResponseCodes::InvalidAmount => "Invalid amount",
+            ResponseCodes::InvalidCardNumber => "Invalid card number",
+            ResponseCodes::InvalidIssuer => "Invalid issuer",
+            ResponseCodes::FormatError => "Format error",
+            ResponseCodes::LostCard => "Lost card, pick up",
+            ResponseCodes::StolenCard => "Stolen card, pick up",
+            ResponseCodes::InsufficientFunds => "Insufficient funds",
+            ResponseCodes::ExpiredCard => "Expired card",
+            ResponseCodes::InvalidPin => "Incorrect PIN",
+            ResponseCodes::TransactionNotPermittedToCardholder => "Transaction not permitted to cardholder",
+            ResponseCodes::TransactionNotPermittedToTerminal => "Transaction not permitted to terminal",
+            ResponseCodes::ExceedsWithdrawalAmountLimit => "Exceeds withdrawal amount limit",
+            ResponseCodes::RestrictedCard => "Restricted card",
+            ResponseCodes::ExceedsWithdrawalCountLimit => "Exceeds withdrawal count limit",
+            ResponseCodes::AllowableNumberOfPinTriesExceeded => "Allowable number of PIN tries exceeded",
+            ResponseCodes::DeactivatedCard => "Deactivated card",
+            ResponseCodes::InvalidNonexistentAccountSpecified => "Invalid or nonexistent account specified",
+            ResponseCodes::DoNotHonorSwitch => "Do not honor, switch decision",
+            ResponseCodes::CancelledCard => "Cancelled card",
+            ResponseCodes::AuthorizationPlatformOrIssuerSystemInoperative => "Authorization platform or issuer system inoperative",
+            ResponseCodes::NotDeclined => "Not declined",
+            ResponseCodes::PinValidationNotPossible => "PIN validation not possible",
+            ResponseCodes::ApprovedPurchaseAmountOnlyNoCashBackAllowed => "Approved for purchase amount only, no cash back allowed",
+            ResponseCodes::CryptographicFailure => "Cryptographic failure",
+            ResponseCodes::InvalidCryptogram => "Invalid cryptogram",
+            ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative => "Authorization system or issuer system inoperative",
+            ResponseCodes::DuplicateTransmissionDetected => "Duplicate transmission detected",
+            ResponseCodes::SystemError => "System error",
+        }
+    }
+
+    pub fn category(&self) -> ResponseCategory {
+        match self {
+            ResponseCodes::Approved => ResponseCategory::Approved,
+            ResponseCodes::ReferToCardIssuer => ResponseCategory::SoftDecline,
+            ResponseCodes::InvalidMerchant => ResponseCategory::HardDecline,
+            ResponseCodes::CaptureCard => ResponseCategory::PickUp,
+            ResponseCodes::DoNotHonor => ResponseCategory::SoftDecline,
+            ResponseCodes::HonorWithId => ResponseCategory::Approved,
+            ResponseCodes::PartialApproval => ResponseCategory::Approved,
+            ResponseCodes::InvalidTransaction => ResponseCategory::HardDecline,
+            ResponseCodes::InvalidAmount => ResponseCategory::SoftDecline,
+            ResponseCodes::InvalidCardNumber => ResponseCategory::HardDecline,
+            ResponseCodes::InvalidIssuer => ResponseCategory::HardDecline,
+            ResponseCodes::FormatError => ResponseCategory::HardDecline,
+            ResponseCodes::LostCard => ResponseCategory::PickUp,
+            ResponseCodes::StolenCard => ResponseCategory::PickUp,
+            ResponseCodes::InsufficientFunds => ResponseCategory::SoftDecline,
+            ResponseCodes::ExpiredCard => ResponseCategory::HardDecline,
+            ResponseCodes::InvalidPin => ResponseCategory::SoftDecline,
+            ResponseCodes::TransactionNotPermittedToCardholder => ResponseCategory::HardDecline,
+            ResponseCodes::TransactionNotPermittedToTerminal => ResponseCategory::HardDecline,
+            ResponseCodes::ExceedsWithdrawalAmountLimit => ResponseCategory::SoftDecline,
+            ResponseCodes::RestrictedCard => ResponseCategory::HardDecline,
+            ResponseCodes::ExceedsWithdrawalCountLimit => ResponseCategory::SoftDecline,
+            ResponseCodes::AllowableNumberOfPinTriesExceeded => ResponseCategory::HardDecline,
+            ResponseCodes::DeactivatedCard => ResponseCategory::HardDecline,
+            ResponseCodes::InvalidNonexistentAccountSpecified => ResponseCategory::HardDecline,
+            ResponseCodes::DoNotHonorSwitch => ResponseCategory::SoftDecline,
+            ResponseCodes::CancelledCard => ResponseCategory::HardDecline,
+            ResponseCodes::AuthorizationPlatformOrIssuerSystemInoperative => ResponseCategory::Retryable,
+            ResponseCodes::NotDeclined => ResponseCategory::Approved,
+            ResponseCodes::PinValidationNotPossible => ResponseCategory::Retryable,
+            ResponseCodes::ApprovedPurchaseAmountOnlyNoCashBackAllowed => ResponseCategory::Approved,
+            ResponseCodes::CryptographicFailure => ResponseCategory::Retryable,
+            ResponseCodes::InvalidCryptogram => ResponseCategory::HardDecline,
+            ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative => ResponseCategory::Retryable,
+            ResponseCodes::DuplicateTransmissionDetected => ResponseCategory::HardDecline,
+            ResponseCodes::SystemError => ResponseCategory::System,
+        }
+    }
+
+    // Whether the cardholder can try the same transaction again and possibly succeed: soft
+    // declines (e.g. after paying down the balance), temporary issuer problems and system errors
+    pub fn cardholder_may_retry(&self) -> bool {
+        match self.category() {
+            ResponseCategory::SoftDecline | ResponseCategory::Retryable | ResponseCategory::System => true,
+            ResponseCategory::Approved | ResponseCategory::HardDecline | ResponseCategory::PickUp => false,
+        }
+    }
+
+    // Zero padded wire representation, "05", "51"
+    pub fn as_two_digits(&self) -> String {
+        format!("{:02}", *self as u8)
+    }
+
+    // Exactly two ASCII digits of a known code
+    pub fn from_two_digits(value: &str) -> Option<Self> {
+        if value.len() != 2 || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        value.parse::<u8>().ok().and_then(Self::from_code)
+    }
+
     pub fn from_code(code: u8) -> Option<Self> {
         match code {
             x if x == ResponseCodes::Approved as u8 => Some(ResponseCodes::Approved),
@@ -95,6 +209,7 @@ impl ResponseCodes {
             x if x == ResponseCodes::AuthorizationPlatformOrIssuerSystemInoperative as u8 => Some(ResponseCodes::AuthorizationPlatformOrIssuerSystemInoperative),
             x if x == ResponseCodes::NotDeclined as u8 => Some(ResponseCodes::NotDeclined),
             x if x == ResponseCodes::PinValidationNotPossible as u8 => Some(ResponseCodes::PinValidationNotPossible),
+            x if x == ResponseCodes::ApprovedPurchaseAmountOnlyNoCashBackAllowed as u8 => Some(ResponseCodes::ApprovedPurchaseAmountOnlyNoCashBackAllowed),
             x if x == ResponseCodes::CryptographicFailure as u8 => Some(ResponseCodes::CryptographicFailure),
             x if x == ResponseCodes::InvalidCryptogram as u8 => Some(ResponseCodes::InvalidCryptogram),
             x if x == ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative as u8 => Some(ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative),
@@ -111,3 +226,130 @@ impl<'de> Deserialize<'de> for ResponseCodes {
         Ok(ResponseCodes::from_code(u8::deserialize(deserializer)?).unwrap_or_default())
     }
 }
+
+// Two character string representation for #[serde(with = "...")]. The default impls above keep
+// using the bare u8
+//
+//     #[serde(with = "crate::datatypes::response_codes::two_digit")]
+//     response_code: ResponseCodes,
+pub mod two_digit {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use crate::datatypes::response_codes::ResponseCodes;
+
+    pub fn serialize<S: Serializer>(code: &ResponseCodes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&code.as_two_digits())
+    }
+
+    // Strict: only "00".."99" strings naming a known code are accepted
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ResponseCodes, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        ResponseCodes::from_two_digits(&value)
+            .ok_or_else(|| de::Error::custom(format!("invalid response code {:?}", value)))
+    }
+
+    // Lenient: also accepts numbers, one digit strings and surrounding whitespace, and maps
+    // unknown codes to ResponseCodes::default() like the u8 Deserialize impl does
+    pub mod lenient {
+        use std::fmt;
+        use serde::{de, Deserializer, Serializer};
+        use crate::datatypes::response_codes::ResponseCodes;
+
+        pub fn serialize<S: Serializer>(code: &ResponseCodes, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(code, serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ResponseCodes, D::Error> {
+            deserializer.deserialize_any(LenientVisitor)
+        }
+
+        struct LenientVisitor;
+
+        impl<'de> de::Visitor<'de> for LenientVisitor {
+            type Value = ResponseCodes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a response code as a number or a numeric string")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<ResponseCodes, E> {
+                Ok(u8::try_from(value).ok().and_then(ResponseCodes::from_code).unwrap_or_default())
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<ResponseCodes, E> {
+                Ok(u8::try_from(value).ok().and_then(ResponseCodes::from_code).unwrap_or_default())
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<ResponseCodes, E> {
+                let value = value.trim();
+                if value.is_empty() || value.len() > 2 || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(E::custom(format!("invalid response code {:?}", value)));
+                }
+                self.visit_u64(value.parse::<u64>().map_err(E::custom)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Strict {
+        #[serde(with = "two_digit")]
+        code: ResponseCodes,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Lenient {
+        #[serde(with = "two_digit::lenient")]
+        code: ResponseCodes,
+    }
+
+    fn strict(value: serde_json::Value) -> Option<ResponseCodes> {
+        serde_json::from_value::<Strict>(json!({ "code": value })).ok().map(|s| s.code)
+    }
+
+    fn lenient(value: serde_json::Value) -> Option<ResponseCodes> {
+        serde_json::from_value::<Lenient>(json!({ "code": value })).ok().map(|l| l.code)
+    }
+
+    #[test]
+    fn two_digit_round_trips_every_code() {
+        for code in (0..=99).filter_map(ResponseCodes::from_code) {
+            let wire = serde_json::to_value(Strict { code }).unwrap();
+            assert_eq!(wire, json!({ "code": format!("{:02}", code as u8) }));
+            assert_eq!(serde_json::from_value::<Strict>(wire.clone()).unwrap().code, code);
+            assert_eq!(serde_json::from_value::<Lenient>(wire).unwrap().code, code);
+        }
+    }
+
+    #[test]
+    fn strict_two_digit_rejects_anything_but_two_known_digits() {
+        assert_eq!(strict(json!("51")), Some(ResponseCodes::InsufficientFunds));
+        for value in [json!("5"), json!(5), json!(" 51 "), json!("123"), json!("99"), json!(""), json!("5a")] {
+            assert_eq!(strict(value.clone()), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn lenient_two_digit_accepts_numbers_and_padding() {
+        assert_eq!(lenient(json!("5")), Some(ResponseCodes::DoNotHonor));
+        assert_eq!(lenient(json!(5)), Some(ResponseCodes::DoNotHonor));
+        assert_eq!(lenient(json!(" 51 ")), Some(ResponseCodes::InsufficientFunds));
+        // Unknown codes fall back to the default like the u8 impl
+        assert_eq!(lenient(json!("99")), Some(ResponseCodes::SystemError));
+        assert_eq!(lenient(json!(300)), Some(ResponseCodes::SystemError));
+        for value in [json!("123"), json!(""), json!("-1"), json!("5a"), json!(true)] {
+            assert_eq!(lenient(value.clone()), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn format_error_is_a_hard_decline() {
+        assert_eq!(ResponseCodes::FormatError.category(), ResponseCategory::HardDecline);
+        assert!(!ResponseCodes::FormatError.cardholder_may_retry());
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+use serde::{de, Deserialize, Deserializer};
+use crate::datatypes::response_codes::ResponseCodes;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::{CoreError, CoreResult};
+
+lazy_static! {
+    static ref RESPONSE_CODE_MAPPING: RwLock<ResponseCodeMapping> = RwLock::new(ResponseCodeMapping::standard());
+}
+
+// Keys are either a whole family by variant name ("DbQuery", "BadFormat") or one sub-code in
+// its Debug form ("DbQuery(8)"); values are known response codes, as numbers or two digit
+// strings. Unknown codes fail the config load instead of becoming the default 96
+//
+//     [response_codes]
+//     default = 96
+//     [response_codes.errors]
+//     DbQuery = 91
+//     "DbTransaction(4)" = 91
+//     [response_codes.networks.visa]
+//     BadFormat = 30
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ResponseCodesConfig {
+    #[serde(deserialize_with = "strict_default")]
+    pub default: Option<ResponseCodes>,
+    #[serde(deserialize_with = "strict_codes")]
+    pub errors: BTreeMap<String, ResponseCodes>,
+    #[serde(deserialize_with = "strict_networks")]
+    pub networks: BTreeMap<String, BTreeMap<String, ResponseCodes>>,
+}
+
+// ResponseCodes' own Deserialize maps unknown codes to SystemError, config values must not
+struct StrictCode(ResponseCodes);
+
+impl<'de> Deserialize<'de> for StrictCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(StrictCodeVisitor)
+    }
+}
+
+struct StrictCodeVisitor;
+
+impl<'de> de::Visitor<'de> for StrictCodeVisitor {
+    type Value = StrictCode;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a known response code as a number or a two digit string")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<StrictCode, E> {
+        u8::try_from(value).ok()
+            .and_then(ResponseCodes::from_code)
+            .map(StrictCode)
+            .ok_or_else(|| E::custom(format!("unknown response code {}", value)))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<StrictCode, E> {
+        let value = u64::try_from(value).map_err(|_| E::custom(format!("unknown response code {}", value)))?;
+        self.visit_u64(value)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<StrictCode, E> {
+        ResponseCodes::from_two_digits(value)
+            .map(StrictCode)
+            .ok_or_else(|| E::custom(format!("invalid response code {:?}", value)))
+    }
+}
+
+fn strict_default<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ResponseCodes>, D::Error> {
+    Ok(Option::<StrictCode>::deserialize(deserializer)?.map(|code| code.0))
+}
+
+fn strict_codes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, ResponseCodes>, D::Error> {
+    let codes = BTreeMap::<String, StrictCode>::deserialize(deserializer)?;
+    Ok(codes.into_iter().map(|(key, code)| (key, code.0)).collect())
+}
+
+fn strict_networks<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, BTreeMap<String, ResponseCodes>>, D::Error> {
+    let networks = BTreeMap::<String, BTreeMap<String, StrictCode>>::deserialize(deserializer)?;
+    Ok(networks.into_iter()
+        .map(|(network, codes)| (network, codes.into_iter().map(|(key, code)| (key, code.0)).collect()))
+        .collect())
+}
+
+// Family is SystemErrorCodes::code() / 100, exact entries use the full code
+#[derive(Debug, Clone, Default)]
+struct MappingTable {
+    families: HashMap<u16, ResponseCodes>,
+    codes: HashMap<u16, ResponseCodes>,
+}
+
+impl MappingTable {
+    fn get(&self, error: &SystemErrorCodes) -> Option<ResponseCodes> {
+        self.codes.get(&error.code())
+            .or_else(|| self.families.get(&(error.code() / 100)))
+            .copied()
+    }
+
+    fn set(&mut self, key: &str, response_code: ResponseCodes) -> CoreResult<()> {
+        match parse_key(key)? {
+            (family, None) => self.families.insert(family, response_code),
+            (family, Some(sub_code)) => self.codes.insert(family * 100 + sub_code as u16, response_code),
+        };
+        Ok(())
+    }
+}
+
+// Lookup order: network sub-code, network family, sub-code, family, default
+#[derive(Debug, Clone)]
+pub struct ResponseCodeMapping {
+    default: ResponseCodes,
+    base: MappingTable,
+    networks: HashMap<String, MappingTable>,
+}
+
+impl ResponseCodeMapping {
+    pub fn standard() -> Self {
+        let mut families = HashMap::new();
+        let mut map = |codes: &[SystemErrorCodes], response_code: ResponseCodes| {
+            for code in codes {
+                families.insert(code.code() / 100, response_code);
+            }
+        };
+        use SystemErrorCodes::*;
+        map(
+            &[UnReachable(0), ClosedChannel(0), BufferFull, TcpConn, DbNoConn(0), DbQuery(0), DbStmt(0),
+                DbTransaction(0), DbRollback(0), DbCommit(0), NoInsertId(0), NoBalancesLock(0), LastLogIdChanged],
+            ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative
+        );
+        map(&[BadFormat, StringParse(0), JsonParse(0), RequestError], ResponseCodes::FormatError);
+        map(&[InvalidCipherResponse, CipherError, MissingKey(0), Encryption], ResponseCodes::CryptographicFailure);
+        map(&[UnknownWalletsId], ResponseCodes::InvalidNonexistentAccountSpecified);
+        map(&[MissingCreditCurrency(0), MissingDebitCurrency(0)], ResponseCodes::TransactionNotPermittedToCardholder);
+        map(
+            &[UnknownOperation, TransactionGroupNotFound(0), TransactionLogNotFound, InconsistentTransactionGroup(0)],
+            ResponseCodes::InvalidTransaction
+        );
+
+        ResponseCodeMapping {
+            default: ResponseCodes::SystemError,
+            base: MappingTable { families, codes: HashMap::new() },
+            networks: HashMap::new(),
+        }
+    }
+
+    // Entries from the config are applied on top of the standard table
+    pub fn from_config(config: &ResponseCodesConfig) -> CoreResult<Self> {
+        let mut mapping = Self::standard();
+        if let Some(default) = config.default {
+            mapping.default = default;
+        }
+        for (key, response_code) in config.errors.iter() {
+            mapping.base.set(key, *response_code)?;
+        }
+        for (network, overrides) in config.networks.iter() {
+            let table = mapping.networks.entry(network.clone()).or_default();
+            for (key, response_code) in overrides.iter() {
+                table.set(key, *response_code)?;
+            }
+        }
+        Ok(mapping)
+    }
+
+    pub fn lookup(&self, error: &SystemErrorCodes, network: Option<&str>) -> ResponseCodes {
+        network
+            .and_then(|network| self.networks.get(network))
+            .and_then(|table| table.get(error))
+            .or_else(|| self.base.get(error))
+            .unwrap_or(self.default)
+    }
+}
+
+// Replaces the table used by SystemErrorCodes::as_response_code, meant to be called at startup
+pub fn set_response_code_mapping(mapping: ResponseCodeMapping) {
+    *RESPONSE_CODE_MAPPING.write().unwrap_or_else(|e| e.into_inner()) = mapping;
+}
+
+pub fn response_code_for(error: &SystemErrorCodes, network: Option<&str>) -> ResponseCodes {
+    RESPONSE_CODE_MAPPING.read().unwrap_or_else(|e| e.into_inner()).lookup(error, network)
+}
+
+// "DbQuery" -> (21, None), "DbQuery(8)" -> (21, Some(8)). Sub-codes are only accepted for
+// variants that carry one, "BadFormat(3)" is rejected
+fn parse_key(key: &str) -> CoreResult<(u16, Option<u8>)> {
+    let (name, sub_code) = match key.split_once('(') {
+        Some((name, rest)) => {
+            let sub_code = rest.strip_suffix(')')
+                .and_then(|sub_code| sub_code.parse::<u8>().ok())
+                .filter(|sub_code| *sub_code < 100)
+                .ok_or_else(|| key_error(key))?;
+            (name, Some(sub_code))
+        },
+        None => (key, None),
+    };
+    let (family, has_sub_codes) = (10..100u16)
+        .find_map(|family| {
+            let debug = format!("{:?}", SystemErrorCodes::from_u16(family * 100)?);
+            match debug.split_once('(') {
+                Some((variant, _)) if variant == name => Some((family, true)),
+                None if debug == name => Some((family, false)),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| key_error(key))?;
+    if sub_code.is_some() && !has_sub_codes {
+        return Err(key_error(key));
+    }
+    Ok((family, sub_code))
+}
+
+fn key_error(key: &str) -> CoreError {
+    CoreError::system_error(
+        format!("Unknown system error code {} in response code mapping", key),
+        "datatypes::response_mapping::parse_key",
+        SystemErrorCodes::BadFormat
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Result<ResponseCodesConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn lookup_prefers_network_then_sub_code_then_family() {
+        let mapping = ResponseCodeMapping::from_config(&config(r#"
+            default = 5
+            [errors]
+            DbQuery = 91
+            "DbQuery(8)" = 84
+            [networks.visa]
+            DbQuery = 80
+            "DbQuery(8)" = 1
+        "#).unwrap()).unwrap();
+
+        assert_eq!(mapping.lookup(&SystemErrorCodes::DbQuery(8), Some("visa")), ResponseCodes::ReferToCardIssuer);
+        assert_eq!(mapping.lookup(&SystemErrorCodes::DbQuery(3), Some("visa")), ResponseCodes::DoNotHonorSwitch);
+        assert_eq!(mapping.lookup(&SystemErrorCodes::DbQuery(8), Some("mastercard")), ResponseCodes::AuthorizationPlatformOrIssuerSystemInoperative);
+        assert_eq!(mapping.lookup(&SystemErrorCodes::DbQuery(3), None), ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative);
+        // Networks fall through to the standard table and then to the default
+        assert_eq!(mapping.lookup(&SystemErrorCodes::BadFormat, Some("visa")), ResponseCodes::FormatError);
+        assert_eq!(mapping.lookup(&SystemErrorCodes::Unknown, Some("visa")), ResponseCodes::DoNotHonor);
+    }
+
+    #[test]
+    fn config_values_must_be_known_codes() {
+        let parsed = config("default = \"96\"\n[errors]\nBadFormat = 30\n").unwrap();
+        assert_eq!(parsed.default, Some(ResponseCodes::SystemError));
+        assert_eq!(parsed.errors["BadFormat"], ResponseCodes::FormatError);
+
+        for toml in ["[errors]\nBadFormat = 31", "[errors]\nBadFormat = \"5\"", "default = 300", "[networks.visa]\nDbQuery = -1"] {
+            assert!(config(toml).is_err(), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn keys_only_take_sub_codes_for_variants_that_have_them() {
+        assert_eq!(parse_key("BadFormat").unwrap(), (30, None));
+        assert_eq!(parse_key("DbQuery").unwrap(), (21, None));
+        assert_eq!(parse_key("DbQuery(8)").unwrap(), (21, Some(8)));
+        for key in ["BadFormat(3)", "DbQuery(100)", "DbQuery(x)", "DbQuery(8", "Db", "NotAnError"] {
+            assert!(parse_key(key).is_err(), "{}", key);
+        }
+    }
+}
@@ -1,30 +1,46 @@
 use std::collections::{BTreeMap, HashMap};
-use mysql_common::row::convert::{FromRow, FromRowError};
-use mysql_common::row::Row;
+use std::str::FromStr;
 use mysql_common::rust_decimal::Decimal;
+use my_own_tests_derive::FromRow;
 use crate::datatypes::system_datatypes::{AccountIdType, AccountParameterIdType, AffinityGroupIdType, BlockIdType, CurrenciesIdType, FraudGroupsId, ParameterValueDate, ParameterValueDateTime, ParameterValueDecimal, ParameterValueInteger, ParameterValueRange, ProductIdType, WalletIdType};
 use serde::{Deserialize, Serialize};
-use crate::extract_value;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::{CoreError, CoreResult};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, FromRow)]
+#[from_row(table = "Account")]
 pub struct Account {
     #[serde(skip_serializing)]
+    #[from_row(rename = "ID")]
     id: AccountIdType,
     number: AccountIdType,
+    #[from_row(rename = "products_ID")]
     products_id: ProductIdType,
+    #[from_row(rename = "blocks_ID")]
     blocks_id: BlockIdType,
+    #[from_row(rename = "fraud_groups_ID")]
     fraud_groups_id: FraudGroupsId,
+    #[from_row(rename = "affinity_groups_ID")]
     affinity_groups_id: AffinityGroupIdType,
     #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[from_row(skip = "new_wallets")]
     wallets: HashMap<WalletIdType, Wallet>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[from_row(skip)]
     parameters: Option<BTreeMap<AccountParameterIdType, ParameterData>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     statement_day: Option<u8>, // this determines if the account has a credit line (if is_some())
     credit_amount: Decimal,
     future_balance_coefficient: f32,
     grace_period_coefficient: f32,
+    // Share of credit_amount that can be withdrawn as cash
     withdrawal_coefficient: f32,
+    // Per-cycle interest rate on cash advances
+    cash_advance_coefficient: f32,
+}
+
+fn new_wallets() -> HashMap<WalletIdType, Wallet> {
+    HashMap::with_capacity(12)
 }
 
 #[derive(Serialize, Debug, Copy, Clone, Default)]
@@ -34,6 +50,16 @@ pub struct Wallet {
     charge_priority: i16,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+    Integer,
+    Decimal,
+    Date,
+    Datetime,
+    Range,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ParameterData {
     Integer(ParameterValueInteger),
@@ -44,32 +70,225 @@ pub enum ParameterData {
     Unset,
 }
 
-impl FromRow for Account {
-    fn from_row(row: Row) -> Self
-        where
-            Self: Sized,
-    {
+impl Account {
+    pub fn new(
+        id: AccountIdType,
+        number: AccountIdType,
+        products_id: ProductIdType,
+        blocks_id: BlockIdType,
+        fraud_groups_id: FraudGroupsId,
+        affinity_groups_id: AffinityGroupIdType,
+        statement_day: Option<u8>,
+        credit_amount: Decimal,
+        future_balance_coefficient: f32,
+        grace_period_coefficient: f32,
+        withdrawal_coefficient: f32,
+        cash_advance_coefficient: f32,
+    ) -> Self {
         Account {
-            id: extract_value!(row, "ID", "Account"),
-            number: extract_value!(row, "number", "Account"),
-            products_id: extract_value!(row, "products_ID", "Account"),
-            blocks_id: extract_value!(row, "blocks_ID", "Account"),
-            fraud_groups_id: extract_value!(row, "fraud_groups_ID", "Account"),
-            affinity_groups_id: extract_value!(row, "affinity_groups_ID", "Account"),
-            wallets: HashMap::with_capacity(12),
+            id,
+            number,
+            products_id,
+            blocks_id,
+            fraud_groups_id,
+            affinity_groups_id,
+            wallets: new_wallets(),
             parameters: None,
-            statement_day: extract_value!(row, "statement_day", "Account"),
-            credit_amount: extract_value!(row, "credit_amount", "Account"),
-            future_balance_coefficient: extract_value!(row, "future_balance_coefficient", "Account"),
-            grace_period_coefficient: extract_value!(row, "grace_period_coefficient", "Account"),
-            withdrawal_coefficient: extract_value!(row, "withdrawal_coefficient", "Account"),
+            statement_day,
+            credit_amount,
+            future_balance_coefficient,
+            grace_period_coefficient,
+            withdrawal_coefficient,
+            cash_advance_coefficient,
         }
     }
 
-    fn from_row_opt(_row: Row) -> Result<Self, FromRowError>
-        where
-            Self: Sized,
-    {
-        unimplemented!()
+    pub fn id(&self) -> AccountIdType {
+        self.id
+    }
+
+    pub fn wallets(&self) -> &HashMap<WalletIdType, Wallet> {
+        &self.wallets
+    }
+
+    pub fn wallets_mut(&mut self) -> &mut HashMap<WalletIdType, Wallet> {
+        &mut self.wallets
+    }
+
+    pub fn products_id(&self) -> ProductIdType {
+        self.products_id
+    }
+
+    pub fn blocks_id(&self) -> BlockIdType {
+        self.blocks_id
+    }
+
+    pub fn fraud_groups_id(&self) -> FraudGroupsId {
+        self.fraud_groups_id
+    }
+
+    pub fn statement_day(&self) -> Option<u8> {
+        self.statement_day
+    }
+
+    pub fn credit_amount(&self) -> Decimal {
+        self.credit_amount
+    }
+
+    pub fn future_balance_coefficient(&self) -> f32 {
+        self.future_balance_coefficient
+    }
+
+    pub fn grace_period_coefficient(&self) -> f32 {
+        self.grace_period_coefficient
+    }
+
+    pub fn withdrawal_coefficient(&self) -> f32 {
+        self.withdrawal_coefficient
+    }
+
+    pub fn cash_advance_coefficient(&self) -> f32 {
+        self.cash_advance_coefficient
     }
-}
\ No newline at end of file
+
+    pub fn parameters(&self) -> Option<&BTreeMap<AccountParameterIdType, ParameterData>> {
+        self.parameters.as_ref()
+    }
+
+    pub fn set_parameters(&mut self, parameters: BTreeMap<AccountParameterIdType, ParameterData>) {
+        self.parameters = Some(parameters);
+    }
+}
+
+impl FromStr for ParameterType {
+    type Err = CoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "integer" => Ok(ParameterType::Integer),
+            "decimal" => Ok(ParameterType::Decimal),
+            "date" => Ok(ParameterType::Date),
+            "datetime" => Ok(ParameterType::Datetime),
+            "range" => Ok(ParameterType::Range),
+            _ => Err(CoreError::system_error(
+                format!("Unknown parameter type {}", s),
+                "ParameterType::from_str",
+                SystemErrorCodes::BadFormat
+            )),
+        }
+    }
+}
+
+impl ParameterData {
+    // Values are stored as text, ranges as the JSON representation of Ranger<f64>.
+    // A declared parameter without a stored value is Unset
+    pub fn parse(parameter_type: ParameterType, value: Option<&str>) -> CoreResult<Self> {
+        let value = match value {
+            Some(value) => value.trim(),
+            None => return Ok(ParameterData::Unset),
+        };
+
+        let parse_error = |e: String| CoreError::system_error(
+            format!("{}: parsing {:?} parameter value {}", e, parameter_type, value),
+            "ParameterData::parse",
+            SystemErrorCodes::StringParse(3)
+        );
+
+        match parameter_type {
+            ParameterType::Integer => value.parse::<ParameterValueInteger>()
+                .map(ParameterData::Integer)
+                .map_err(|e| parse_error(e.to_string())),
+            ParameterType::Decimal => ParameterValueDecimal::from_str(value)
+                .map(ParameterData::Decimal)
+                .map_err(|e| parse_error(e.to_string())),
+            ParameterType::Date => ParameterValueDate::parse_from_str(value, "%Y-%m-%d")
+                .map(ParameterData::Date)
+                .map_err(|e| parse_error(e.to_string())),
+            ParameterType::Datetime => ParameterValueDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+                .map(ParameterData::Datetime)
+                .map_err(|e| parse_error(e.to_string())),
+            ParameterType::Range => serde_json::from_str::<ParameterValueRange>(value)
+                .map(ParameterData::Range)
+                .map_err(|e| CoreError::system_error(
+                    format!("{}: parsing range parameter value {}", e, value),
+                    "ParameterData::parse",
+                    SystemErrorCodes::JsonParse(2)
+                )),
+        }
+    }
+}
+
+impl Wallet {
+    pub fn new(id: WalletIdType, currencies_id: CurrenciesIdType, charge_priority: i16) -> Self {
+        Wallet { id, currencies_id, charge_priority }
+    }
+
+    pub fn id(&self) -> WalletIdType {
+        self.id
+    }
+
+    pub fn currencies_id(&self) -> CurrenciesIdType {
+        self.currencies_id
+    }
+
+    pub fn charge_priority(&self) -> i16 {
+        self.charge_priority
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(parameter_type: ParameterType, value: &str) -> SystemErrorCodes {
+        ParameterData::parse(parameter_type, Some(value)).unwrap_err().system_error
+    }
+
+    #[test]
+    fn missing_values_are_unset() {
+        for parameter_type in [ParameterType::Integer, ParameterType::Decimal, ParameterType::Date, ParameterType::Datetime, ParameterType::Range] {
+            assert!(matches!(ParameterData::parse(parameter_type, None).unwrap(), ParameterData::Unset), "{:?}", parameter_type);
+        }
+    }
+
+    #[test]
+    fn values_parse_for_their_type() {
+        assert!(matches!(ParameterData::parse(ParameterType::Integer, Some(" -42 ")).unwrap(), ParameterData::Integer(-42)));
+        assert!(matches!(ParameterData::parse(ParameterType::Decimal, Some("12.50")).unwrap(), ParameterData::Decimal(d) if d == Decimal::new(1250, 2)));
+        assert!(matches!(
+            ParameterData::parse(ParameterType::Date, Some("2024-02-29")).unwrap(),
+            ParameterData::Date(d) if d == ParameterValueDate::from_ymd_opt(2024, 2, 29).unwrap()
+        ));
+        assert!(matches!(
+            ParameterData::parse(ParameterType::Datetime, Some("2024-03-15 18:00:05")).unwrap(),
+            ParameterData::Datetime(d) if d == ParameterValueDate::from_ymd_opt(2024, 3, 15).unwrap().and_hms_opt(18, 0, 5).unwrap()
+        ));
+    }
+
+    #[test]
+    fn values_that_fail_for_their_type_are_rejected() {
+        let cases = [
+            (ParameterType::Integer, "12.5"),
+            (ParameterType::Integer, "abc"),
+            (ParameterType::Decimal, "1,5"),
+            (ParameterType::Date, "2023-02-29"),
+            (ParameterType::Date, "15/03/2024"),
+            (ParameterType::Datetime, "2024-03-15"),
+        ];
+        for (parameter_type, value) in cases {
+            assert_eq!(parse_error(parameter_type, value), SystemErrorCodes::StringParse(3), "{:?} {}", parameter_type, value);
+        }
+    }
+
+    #[test]
+    fn ranges_are_parsed_from_json() {
+        let range = ParameterData::parse(ParameterType::Range, Some(r#"{"min": 1.5, "max": 10.0}"#)).unwrap();
+        let round_trip = serde_json::to_string(&range).unwrap();
+        assert!(matches!(range, ParameterData::Range(_)));
+        assert!(round_trip.contains("1.5") && round_trip.contains("10.0"), "{}", round_trip);
+
+        for value in ["", "1.5-10", r#"{"min": 1.5, "max": 10.0"#, r#"{"min": "low", "max": "high"}"#] {
+            assert_eq!(parse_error(ParameterType::Range, value), SystemErrorCodes::JsonParse(2), "{}", value);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::Display;
-use serde::{Serialize, Serializer};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 use crate::datatypes::response_codes::ResponseCodes;
+use crate::datatypes::response_mapping::response_code_for;
 use logger::{ErrorTypes, Locations, MyError};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -98,8 +99,16 @@ impl SystemErrorCodes {
             Self::NoCollectingBalances => 7300
         }
     }
+    // Process exit status for fatal errors: the code family (e.g. 20 for DbNoConn), never 0
+    pub fn exit_code(&self) -> i32 {
+        (self.code() / 100) as i32
+    }
+    // Uses the table installed with response_mapping::set_response_code_mapping
     pub fn as_response_code(&self) -> ResponseCodes {
-        ResponseCodes::SystemError
+        response_code_for(self, None)
+    }
+    pub fn as_network_response_code(&self, network: &str) -> ResponseCodes {
+        response_code_for(self, Some(network))
     }
     pub fn from_u16(code: u16) -> Option<Self> {
         match code / 100 {
@@ -155,6 +164,13 @@ impl Serialize for SystemErrorCodes {
     }
 }
 
+impl<'de> Deserialize<'de> for SystemErrorCodes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error> where
+        D: Deserializer<'de> {
+        Ok(SystemErrorCodes::from_u16(u16::deserialize(deserializer)?).unwrap_or(SystemErrorCodes::Unknown))
+    }
+}
+
 impl From<&SystemErrorCodes> for ErrorTypes {
     fn from(s: &SystemErrorCodes) -> Self {
         match s {
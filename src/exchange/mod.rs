@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+use mysql_async::Conn;
+use mysql_async::prelude::Queryable;
+use mysql_common::chrono::NaiveDate;
+use mysql_common::row::Row;
+use mysql_common::rust_decimal::{Decimal, RoundingStrategy};
+use my_own_tests_derive::FromRow;
+use crate::billing::waterfall::CurrencyConverter;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::CurrenciesIdType;
+use crate::utils::{CoreError, CoreResult};
+
+// Used for currencies missing from the currencies table or the CSV
+const DEFAULT_DECIMALS: u32 = 2;
+
+// `rate` is how many `to` units one `from` unit buys. `markup` is a fraction added on top of the
+// converted amount (0.02 = 2%)
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "exchange_rates")]
+pub struct ExchangeRate {
+    #[from_row(rename = "from_currencies_ID")]
+    pub from: CurrenciesIdType,
+    #[from_row(rename = "to_currencies_ID")]
+    pub to: CurrenciesIdType,
+    pub effective_date: NaiveDate,
+    #[from_row(decimal)]
+    pub rate: Decimal,
+    #[from_row(decimal, default)]
+    pub markup: Decimal,
+}
+
+#[derive(Debug, Clone, FromRow)]
+#[from_row(table = "currencies")]
+struct CurrencyRow {
+    #[from_row(rename = "ID")]
+    id: CurrenciesIdType,
+    decimals: u32,
+}
+
+// Rates per (from, to) pair, each pair keeping its history by effective date. A conversion uses
+// the latest rate effective on or before the requested date
+#[derive(Debug, Clone, Default)]
+pub struct ExchangeStore {
+    rates: HashMap<(CurrenciesIdType, CurrenciesIdType), BTreeMap<NaiveDate, ExchangeRate>>,
+    decimals: HashMap<CurrenciesIdType, u32>,
+}
+
+impl ExchangeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_rate(&mut self, rate: ExchangeRate) -> CoreResult<()> {
+        if rate.rate <= Decimal::ZERO || rate.markup < Decimal::ZERO {
+            return Err(CoreError::system_error(
+                format!("Invalid rate {} markup {} from {} to {} at {}", rate.rate, rate.markup, rate.from, rate.to, rate.effective_date),
+                "exchange::ExchangeStore::insert_rate",
+                SystemErrorCodes::ExchangeConfiguration
+            ));
+        }
+        self.rates.entry((rate.from, rate.to)).or_default().insert(rate.effective_date, rate);
+        Ok(())
+    }
+
+    pub fn set_decimals(&mut self, currencies_id: CurrenciesIdType, decimals: u32) {
+        self.decimals.insert(currencies_id, decimals);
+    }
+
+    pub fn decimals(&self, currencies_id: CurrenciesIdType) -> u32 {
+        self.decimals.get(&currencies_id).copied().unwrap_or(DEFAULT_DECIMALS)
+    }
+
+    pub fn rate_on(&self, from: CurrenciesIdType, to: CurrenciesIdType, date: NaiveDate) -> CoreResult<&ExchangeRate> {
+        match self.rates.get(&(from, to)) {
+            Some(history) => history.range(..=date).next_back().map(|(_, rate)| rate)
+                .ok_or_else(|| CoreError::system_error(
+                    format!("No rate from {} to {} effective at {}", from, to, date),
+                    "exchange::ExchangeStore::rate_on",
+                    SystemErrorCodes::ExchangeConfiguration
+                )),
+            None if !self.rates.keys().any(|(f, _)| *f == from) => Err(CoreError::system_error(
+                format!("No rates from currency {}", from),
+                "exchange::ExchangeStore::rate_on",
+                SystemErrorCodes::MissingFromCurrency(1)
+            )),
+            None => Err(CoreError::system_error(
+                format!("No rates from currency {} to currency {}", from, to),
+                "exchange::ExchangeStore::rate_on",
+                SystemErrorCodes::MissingToCurrency(1)
+            )),
+        }
+    }
+
+    // amount * rate * (1 + markup), rounded half away from zero to the decimals of `to`
+    pub fn convert_on(&self, amount: Decimal, from: CurrenciesIdType, to: CurrenciesIdType, date: NaiveDate) -> CoreResult<Decimal> {
+        let converted = if from == to {
+            amount
+        } else {
+            let rate = self.rate_on(from, to, date)?;
+            amount.checked_mul(rate.rate)
+                .and_then(|a| a.checked_mul(Decimal::ONE + rate.markup))
+                .ok_or_else(|| CoreError::system_error(
+                    format!("Overflow converting {} from {} to {}", amount, from, to),
+                    "exchange::ExchangeStore::convert_on",
+                    SystemErrorCodes::ExchangeConfiguration
+                ))?
+        };
+        Ok(converted.round_dp_with_strategy(self.decimals(to), RoundingStrategy::MidpointAwayFromZero))
+    }
+
+    pub async fn load_from_db(conn: &mut Conn) -> CoreResult<Self> {
+        let mut store = Self::new();
+
+        let currencies = conn.query::<Row, _>("SELECT ID, decimals FROM currencies")
+            .await
+            .map_err(|e| CoreError::system_error(e, "exchange::ExchangeStore::load_from_db", SystemErrorCodes::DbQuery(15)))?;
+        for row in currencies.iter() {
+            let currency = CurrencyRow::try_from_row(row)?;
+            store.set_decimals(currency.id, currency.decimals);
+        }
+
+        let rates = conn.query::<Row, _>(
+            "SELECT from_currencies_ID, to_currencies_ID, effective_date, rate, markup FROM exchange_rates"
+        ).await.map_err(|e| CoreError::system_error(e, "exchange::ExchangeStore::load_from_db", SystemErrorCodes::DbQuery(16)))?;
+        for row in rates.iter() {
+            store.insert_rate(ExchangeRate::try_from_row(row)?)?;
+        }
+
+        Ok(store)
+    }
+
+    // One rate per line: from,to,effective_date(YYYY-MM-DD),rate[,markup]. Blank lines, lines
+    // starting with # and a header line starting with "from" are ignored
+    pub fn load_from_csv(path: &Path) -> CoreResult<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| csv_error(format!("{}: reading {}", e, path.display())))?;
+        let mut store = Self::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("from") { continue }
+
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != 4 && fields.len() != 5 {
+                return Err(csv_error(format!("{} line {}: expected 4 or 5 fields", path.display(), number + 1)));
+            }
+            let rate = ExchangeRate {
+                from: parse_csv_field(fields[0], "from", path, number)?,
+                to: parse_csv_field(fields[1], "to", path, number)?,
+                effective_date: NaiveDate::parse_from_str(fields[2], "%Y-%m-%d")
+                    .map_err(|e| csv_error(format!("{} line {}: effective_date {}", path.display(), number + 1, e)))?,
+                rate: parse_csv_field(fields[3], "rate", path, number)?,
+                markup: match fields.get(4) {
+                    Some(markup) => parse_csv_field(markup, "markup", path, number)?,
+                    None => Decimal::ZERO,
+                },
+            };
+            store.insert_rate(rate)?;
+        }
+
+        Ok(store)
+    }
+}
+
+impl CurrencyConverter for ExchangeStore {
+    fn convert(&self, amount: Decimal, from: CurrenciesIdType, to: CurrenciesIdType, date: NaiveDate) -> CoreResult<Decimal> {
+        self.convert_on(amount, from, to, date)
+    }
+
+    fn decimals(&self, currencies_id: CurrenciesIdType) -> u32 {
+        ExchangeStore::decimals(self, currencies_id)
+    }
+}
+
+fn parse_csv_field<T: FromStr>(value: &str, field: &str, path: &Path, number: usize) -> CoreResult<T> {
+    value.parse::<T>()
+        .map_err(|_| csv_error(format!("{} line {}: invalid {} {}", path.display(), number + 1, field, value)))
+}
+
+fn csv_error(detail: String) -> CoreError {
+    CoreError::system_error(detail, "exchange::ExchangeStore::load_from_csv", SystemErrorCodes::ExchangeConfiguration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn converter_uses_the_rate_effective_on_the_given_date() {
+        let mut store = ExchangeStore::new();
+        for (effective_date, rate) in [(date(2024, 1, 1), 2), (date(2024, 3, 1), 3)] {
+            store.insert_rate(ExchangeRate { from: 2, to: 1, effective_date, rate: Decimal::new(rate, 0), markup: Decimal::ZERO }).unwrap();
+        }
+
+        assert_eq!(store.convert(Decimal::TEN, 2, 1, date(2024, 2, 28)).unwrap(), Decimal::new(20, 0));
+        assert_eq!(store.convert(Decimal::TEN, 2, 1, date(2024, 3, 1)).unwrap(), Decimal::new(30, 0));
+        assert_eq!(store.convert(Decimal::TEN, 2, 1, date(2023, 12, 31)).unwrap_err().system_error, SystemErrorCodes::ExchangeConfiguration);
+    }
+
+    fn rate(from: CurrenciesIdType, to: CurrenciesIdType, rate: Decimal, markup: Decimal) -> ExchangeRate {
+        ExchangeRate { from, to, effective_date: date(2024, 1, 1), rate, markup }
+    }
+
+    // Written to the temp dir, named after the test so parallel tests don't collide
+    fn csv(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("exchange_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn markup_is_added_on_top_of_the_rate() {
+        let mut store = ExchangeStore::new();
+        store.insert_rate(rate(2, 1, Decimal::new(11, 1), Decimal::new(2, 2))).unwrap();
+
+        // 100 * 1.1 * 1.02
+        assert_eq!(store.convert(Decimal::new(100, 0), 2, 1, date(2024, 3, 1)).unwrap(), Decimal::new(11220, 2));
+    }
+
+    #[test]
+    fn conversions_round_to_the_target_currency_decimals() {
+        let mut store = ExchangeStore::new();
+        store.set_decimals(392, 0);
+        store.set_decimals(48, 3);
+        for to in [978, 392, 48] {
+            store.insert_rate(rate(840, to, Decimal::new(123455, 5), Decimal::ZERO)).unwrap();
+        }
+
+        // 10 * 1.23455 = 12.3455, half away from zero
+        assert_eq!(store.convert(Decimal::TEN, 840, 978, date(2024, 3, 1)).unwrap(), Decimal::new(1235, 2));
+        assert_eq!(store.convert(Decimal::TEN, 840, 392, date(2024, 3, 1)).unwrap(), Decimal::new(12, 0));
+        assert_eq!(store.convert(Decimal::TEN, 840, 48, date(2024, 3, 1)).unwrap(), Decimal::new(12346, 3));
+        assert_eq!(store.convert(-Decimal::TEN, 840, 48, date(2024, 3, 1)).unwrap(), Decimal::new(-12346, 3));
+        // Same currency amounts are only rounded
+        assert_eq!(store.convert(Decimal::new(12345, 3), 978, 978, date(2024, 3, 1)).unwrap(), Decimal::new(1235, 2));
+    }
+
+    #[test]
+    fn missing_currencies_have_their_own_codes() {
+        let mut store = ExchangeStore::new();
+        store.insert_rate(rate(2, 1, Decimal::TWO, Decimal::ZERO)).unwrap();
+
+        assert_eq!(store.rate_on(3, 1, date(2024, 3, 1)).unwrap_err().system_error, SystemErrorCodes::MissingFromCurrency(1));
+        assert_eq!(store.rate_on(2, 3, date(2024, 3, 1)).unwrap_err().system_error, SystemErrorCodes::MissingToCurrency(1));
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        let mut store = ExchangeStore::new();
+        for invalid in [rate(2, 1, Decimal::ZERO, Decimal::ZERO), rate(2, 1, -Decimal::ONE, Decimal::ZERO), rate(2, 1, Decimal::ONE, -Decimal::ONE)] {
+            assert_eq!(store.insert_rate(invalid).unwrap_err().system_error, SystemErrorCodes::ExchangeConfiguration);
+        }
+    }
+
+    #[test]
+    fn csv_rates_are_loaded_with_optional_markup() {
+        let path = csv("loaded", "from,to,effective_date,rate,markup\n# comment\n\n840,978,2024-01-01,0.9\n840,978,2024-03-01,0.8,0.01\n");
+        let store = ExchangeStore::load_from_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(store.rate_on(840, 978, date(2024, 2, 1)).unwrap().markup, Decimal::ZERO);
+        // 100 * 0.8 * 1.01
+        assert_eq!(store.convert(Decimal::new(100, 0), 840, 978, date(2024, 3, 1)).unwrap(), Decimal::new(8080, 2));
+    }
+
+    #[test]
+    fn malformed_csv_rows_are_rejected() {
+        let rows = [
+            ("fields", "840,978,2024-01-01\n"),
+            ("currency", "usd,978,2024-01-01,0.9\n"),
+            ("date", "840,978,01/01/2024,0.9\n"),
+            ("rate", "840,978,2024-01-01,abc\n"),
+            ("markup", "840,978,2024-01-01,0.9,x\n"),
+            ("negative", "840,978,2024-01-01,-0.9\n"),
+        ];
+        for (name, content) in rows {
+            let path = csv(name, content);
+            let result = ExchangeStore::load_from_csv(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(result.unwrap_err().system_error, SystemErrorCodes::ExchangeConfiguration, "{}", name);
+        }
+
+        let missing = std::env::temp_dir().join("exchange_missing_file.csv");
+        assert_eq!(ExchangeStore::load_from_csv(&missing).unwrap_err().system_error, SystemErrorCodes::ExchangeConfiguration);
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::iso8583::dictionary::{Dictionary, Encoding, FieldSpec, LengthType};
+use crate::utils::{CoreError, CoreResult};
+
+// Field values are kept decoded: Ascii and Bcd fields as their ASCII characters/digits, Binary
+// fields as raw bytes. Field 1 (secondary bitmap) is never stored, the codec derives it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IsoMessage {
+    mti: String,
+    fields: BTreeMap<u8, Vec<u8>>,
+}
+
+impl IsoMessage {
+    pub fn new(mti: &str) -> Self {
+        IsoMessage { mti: mti.to_string(), fields: BTreeMap::new() }
+    }
+
+    pub fn mti(&self) -> &str {
+        &self.mti
+    }
+
+    pub fn set_mti(&mut self, mti: &str) {
+        self.mti = mti.to_string();
+    }
+
+    pub fn has(&self, number: u8) -> bool {
+        self.fields.contains_key(&number)
+    }
+
+    pub fn get(&self, number: u8) -> Option<&[u8]> {
+        self.fields.get(&number).map(Vec::as_slice)
+    }
+
+    // None when the field is missing or not valid UTF-8 (binary fields)
+    pub fn get_str(&self, number: u8) -> Option<&str> {
+        self.get(number).and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub fn set<T: Into<Vec<u8>>>(&mut self, number: u8, value: T) {
+        self.fields.insert(number, value.into());
+    }
+
+    pub fn remove(&mut self, number: u8) -> Option<Vec<u8>> {
+        self.fields.remove(&number)
+    }
+
+    pub fn field_numbers(&self) -> impl Iterator<Item = u8> + '_ {
+        self.fields.keys().copied()
+    }
+}
+
+pub fn decode(dictionary: &Dictionary, data: &[u8]) -> CoreResult<IsoMessage> {
+    let mut reader = Reader { data, position: 0 };
+
+    let mti = match dictionary.mti_encoding() {
+        Encoding::Bcd => unpack_bcd(reader.take(2)?, 4)?,
+        _ => ascii(reader.take(4)?, "MTI")?,
+    };
+    if mti.len() != 4 || !mti.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(codec_error(format!("Invalid MTI {}", mti)));
+    }
+
+    let mut bitmap = read_bitmap(dictionary, &mut reader)?;
+    if bitmap[0] & 0x80 != 0 {
+        bitmap.extend(read_bitmap(dictionary, &mut reader)?);
+    }
+
+    let mut message = IsoMessage::new(&mti);
+    for number in 2..=(bitmap.len() * 8) as u8 {
+        if !is_set(&bitmap, number) { continue }
+        let spec = dictionary.field(number)
+            .ok_or_else(|| codec_error(format!("Field {} is present but not in the dictionary", number)))?;
+        message.set(number, read_field(spec, &mut reader)?);
+    }
+
+    if reader.position != data.len() {
+        return Err(codec_error(format!("{} trailing bytes after the last field", data.len() - reader.position)));
+    }
+    Ok(message)
+}
+
+pub fn encode(dictionary: &Dictionary, message: &IsoMessage) -> CoreResult<Vec<u8>> {
+    let mut out = Vec::with_capacity(256);
+
+    if message.mti.len() != 4 || !message.mti.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(codec_error(format!("Invalid MTI {}", message.mti)));
+    }
+    match dictionary.mti_encoding() {
+        Encoding::Bcd => out.extend(pack_bcd(&message.mti)?),
+        _ => out.extend(message.mti.as_bytes()),
+    }
+
+    let secondary = message.fields.keys().any(|n| *n > 64);
+    let mut bitmap = vec![0u8; if secondary { 16 } else { 8 }];
+    if secondary { bitmap[0] |= 0x80 }
+    for number in message.fields.keys() {
+        if *number < 2 || *number > 128 {
+            return Err(codec_error(format!("Field number {} out of range", number)));
+        }
+        bitmap[(*number as usize - 1) / 8] |= 0x80 >> ((*number as usize - 1) % 8);
+    }
+    match dictionary.bitmap_encoding() {
+        Encoding::Ascii => out.extend(bitmap.iter().map(|b| format!("{:02X}", b)).collect::<String>().as_bytes()),
+        _ => out.extend(&bitmap),
+    }
+
+    for (number, value) in message.fields.iter() {
+        let spec = dictionary.field(*number)
+            .ok_or_else(|| codec_error(format!("Field {} is not in the dictionary", number)))?;
+        write_field(spec, value, &mut out)?;
+    }
+    Ok(out)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> CoreResult<&'a [u8]> {
+        let end = self.position + length;
+        if end > self.data.len() {
+            return Err(codec_error(format!("Message truncated reading {} bytes at offset {}", length, self.position)));
+        }
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+}
+
+fn read_bitmap(dictionary: &Dictionary, reader: &mut Reader) -> CoreResult<Vec<u8>> {
+    match dictionary.bitmap_encoding() {
+        Encoding::Ascii => {
+            let bytes = reader.take(16)?;
+            // Checked up front so slicing below stays on char boundaries
+            if !bytes.iter().all(u8::is_ascii_hexdigit) {
+                return Err(codec_error(format!("Invalid hex bitmap {}", String::from_utf8_lossy(bytes))));
+            }
+            let hex = ascii(bytes, "bitmap")?;
+            (0..8).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| codec_error(format!("Invalid hex bitmap {}", hex))))
+                .collect()
+        },
+        _ => Ok(reader.take(8)?.to_vec()),
+    }
+}
+
+fn is_set(bitmap: &[u8], number: u8) -> bool {
+    let index = number as usize - 1;
+    bitmap[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+// Bytes needed on the wire for `length` characters/digits/bytes
+fn wire_length(encoding: Encoding, length: usize) -> usize {
+    match encoding {
+        Encoding::Bcd => length.div_ceil(2),
+        _ => length,
+    }
+}
+
+fn read_field(spec: &FieldSpec, reader: &mut Reader) -> CoreResult<Vec<u8>> {
+    let length = match spec.length_type {
+        LengthType::Fixed => spec.length,
+        length_type => {
+            let digits = length_type.prefix_digits();
+            let prefix = match spec.prefix_encoding {
+                Encoding::Bcd => unpack_bcd(reader.take(digits.div_ceil(2))?, digits)?,
+                _ => ascii(reader.take(digits)?, "length prefix")?,
+            };
+            let length = prefix.parse::<usize>()
+                .map_err(|_| codec_error(format!("Invalid length prefix {} for field {}", prefix, spec.number)))?;
+            if length > spec.length {
+                return Err(codec_error(format!("Field {} length {} exceeds max {}", spec.number, length, spec.length)));
+            }
+            length
+        },
+    };
+
+    let raw = reader.take(wire_length(spec.encoding, length))?;
+    match spec.encoding {
+        Encoding::Bcd => Ok(unpack_bcd(raw, length)?.into_bytes()),
+        _ => Ok(raw.to_vec()),
+    }
+}
+
+fn write_field(spec: &FieldSpec, value: &[u8], out: &mut Vec<u8>) -> CoreResult<()> {
+    let length = value.len();
+    match spec.length_type {
+        LengthType::Fixed if length != spec.length => {
+            return Err(codec_error(format!("Field {} must be {} long, got {}", spec.number, spec.length, length)));
+        },
+        LengthType::Fixed => {},
+        length_type => {
+            if length > spec.length {
+                return Err(codec_error(format!("Field {} length {} exceeds max {}", spec.number, length, spec.length)));
+            }
+            let prefix = format!("{:0width$}", length, width = length_type.prefix_digits());
+            match spec.prefix_encoding {
+                Encoding::Bcd => out.extend(pack_bcd(&prefix)?),
+                _ => out.extend(prefix.as_bytes()),
+            }
+        },
+    }
+
+    match spec.encoding {
+        Encoding::Bcd => {
+            let digits = std::str::from_utf8(value)
+                .map_err(|_| codec_error(format!("Field {} is not numeric", spec.number)))?;
+            out.extend(pack_bcd(digits)?);
+        },
+        _ => out.extend(value),
+    }
+    Ok(())
+}
+
+// Odd lengths are left padded with a 0 nibble
+fn pack_bcd(digits: &str) -> CoreResult<Vec<u8>> {
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(codec_error(format!("{} is not numeric", digits)));
+    }
+    let padded = if digits.len() % 2 == 1 { format!("0{}", digits) } else { digits.to_string() };
+    Ok(padded.as_bytes()
+        .chunks(2)
+        .map(|pair| ((pair[0] - b'0') << 4) | (pair[1] - b'0'))
+        .collect())
+}
+
+fn unpack_bcd(bytes: &[u8], digits: usize) -> CoreResult<String> {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        for nibble in [byte >> 4, byte & 0x0F] {
+            if nibble > 9 {
+                return Err(codec_error(format!("Invalid BCD byte {:02X}", byte)));
+            }
+            out.push((b'0' + nibble) as char);
+        }
+    }
+    Ok(out.split_off(out.len() - digits))
+}
+
+fn ascii(bytes: &[u8], what: &str) -> CoreResult<String> {
+    std::str::from_utf8(bytes)
+        .map(str::to_string)
+        .map_err(|_| codec_error(format!("Invalid ASCII in {}", what)))
+}
+
+fn codec_error(detail: String) -> CoreError {
+    CoreError::system_error(detail, "iso8583::codec", SystemErrorCodes::BadFormat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iso8583::dictionary::{DictionaryConfig, Version};
+
+    // BCD MTI and hex bitmap, with BCD fields both fixed and variable
+    fn packed_dictionary() -> Dictionary {
+        use Encoding::{Ascii, Bcd, Binary};
+        use LengthType::{Fixed, LlVar, LllVar};
+        Dictionary::from_config(DictionaryConfig {
+            version: Version::V1987,
+            mti_encoding: Bcd,
+            bitmap_encoding: Ascii,
+            fields: vec![
+                FieldSpec::new(2, "pan", LlVar, 19, Bcd).with_prefix_encoding(Bcd),
+                FieldSpec::new(3, "processing_code", Fixed, 5, Bcd),
+                FieldSpec::new(43, "name", LlVar, 40, Ascii),
+                FieldSpec::new(55, "icc_data", LllVar, 999, Binary),
+                FieldSpec::new(70, "network_code", Fixed, 3, Ascii),
+            ],
+        }).unwrap()
+    }
+
+    fn assert_bad_format(result: CoreResult<IsoMessage>) {
+        assert_eq!(result.unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+
+    #[test]
+    fn binary_bitmap_round_trip_with_secondary_bitmap() {
+        let dictionary = Dictionary::iso8583_1987();
+        let mut message = IsoMessage::new("0400");
+        message.set(2, "4111111111111111");
+        message.set(11, "000123");
+        message.set(90, "0".repeat(42));
+
+        let data = encode(&dictionary, &message).unwrap();
+        assert_eq!(&data[0..4], b"0400");
+        assert_eq!(&data[4..20], &[0xC0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x40, 0, 0, 0, 0]);
+        assert_eq!(decode(&dictionary, &data).unwrap(), message);
+    }
+
+    #[test]
+    fn ascii_bitmap_round_trip_with_secondary_bitmap() {
+        let dictionary = packed_dictionary();
+        let mut message = IsoMessage::new("0100");
+        message.set(3, "00000");
+        message.set(70, "301");
+
+        let data = encode(&dictionary, &message).unwrap();
+        assert_eq!(&data[0..2], &[0x01, 0x00]);
+        assert_eq!(&data[2..34], b"A0000000000000000400000000000000");
+        assert_eq!(decode(&dictionary, &data).unwrap(), message);
+    }
+
+    #[test]
+    fn llvar_and_lllvar_prefixes() {
+        let dictionary = packed_dictionary();
+        let mut message = IsoMessage::new("0100");
+        message.set(43, "SHOP");
+        message.set(55, vec![0x9F, 0x02]);
+
+        let data = encode(&dictionary, &message).unwrap();
+        assert_eq!(&data[18..], b"04SHOP002\x9F\x02");
+        assert_eq!(decode(&dictionary, &data).unwrap(), message);
+
+        let mut too_long = data.clone();
+        too_long[18..20].copy_from_slice(b"41");
+        assert_bad_format(decode(&dictionary, &too_long));
+    }
+
+    #[test]
+    fn odd_length_bcd_is_left_padded() {
+        let dictionary = packed_dictionary();
+        let mut message = IsoMessage::new("0100");
+        message.set(2, "12345");
+        message.set(3, "01000");
+
+        let data = encode(&dictionary, &message).unwrap();
+        assert_eq!(&data[18..], &[0x05, 0x01, 0x23, 0x45, 0x00, 0x10, 0x00]);
+        assert_eq!(decode(&dictionary, &data).unwrap(), message);
+
+        let mut bad_nibble = data.clone();
+        bad_nibble[20] = 0x2A;
+        assert_bad_format(decode(&dictionary, &bad_nibble));
+    }
+
+    #[test]
+    fn truncated_messages_are_bad_format() {
+        let mut standard = IsoMessage::new("0100");
+        standard.set(2, "4111111111111111");
+        standard.set(55, vec![1, 2, 3]);
+        standard.set(90, "0".repeat(42));
+        let mut packed = IsoMessage::new("0100");
+        packed.set(2, "4111111111111111");
+        packed.set(3, "00000");
+        packed.set(70, "301");
+
+        for (dictionary, message) in [(Dictionary::iso8583_1987(), standard), (packed_dictionary(), packed)] {
+            let data = encode(&dictionary, &message).unwrap();
+            for end in 0..data.len() {
+                assert_bad_format(decode(&dictionary, &data[..end]));
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_bad_format_without_panicking() {
+        let dictionary = packed_dictionary();
+        // Multi-byte UTF-8 inside the hex bitmap
+        let mut data = vec![0x01, 0x00];
+        data.extend("0é0000000000000".as_bytes());
+        assert_eq!(data.len(), 18);
+        assert_bad_format(decode(&dictionary, &data));
+        assert_bad_format(decode(&dictionary, b"\x01\x00ZZ00000000000000"));
+        assert_bad_format(decode(&Dictionary::iso8583_1987(), b"01X0\x40\0\0\0\0\0\0\0"));
+
+        // Bitmap bits for fields the dictionary doesn't define
+        assert_bad_format(decode(&dictionary, b"\x01\x000100000000000000"));
+
+        let mut seed: u32 = 7;
+        for _ in 0..2000 {
+            let data: Vec<u8> = (0..40).map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            }).collect();
+            let _ = decode(&dictionary, &data);
+            let _ = decode(&Dictionary::iso8583_1987(), &data);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::utils::{CoreError, CoreResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Version {
+    #[serde(rename = "1987")]
+    V1987,
+    #[serde(rename = "1993")]
+    V1993,
+}
+
+impl Version {
+    // First MTI digit
+    pub fn mti_digit(&self) -> char {
+        match self {
+            Version::V1987 => '0',
+            Version::V1993 => '1',
+        }
+    }
+}
+
+// Ascii: one byte per character. Bcd: two digits per byte, left padded with 0 when odd.
+// Binary: raw bytes, only valid for field data and bitmaps (sent as raw bytes, Ascii bitmaps are hex)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Ascii,
+    Bcd,
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LengthType {
+    Fixed,
+    LlVar,
+    LllVar,
+}
+
+impl LengthType {
+    // Digits in the length prefix
+    pub fn prefix_digits(&self) -> usize {
+        match self {
+            LengthType::Fixed => 0,
+            LengthType::LlVar => 2,
+            LengthType::LllVar => 3,
+        }
+    }
+}
+
+// `length` is the exact length of fixed fields and the maximum of variable ones, counted in
+// characters/digits for Ascii and Bcd and in bytes for Binary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldSpec {
+    pub number: u8,
+    #[serde(default)]
+    pub name: String,
+    pub length_type: LengthType,
+    pub length: usize,
+    pub encoding: Encoding,
+    #[serde(default = "default_prefix_encoding")]
+    pub prefix_encoding: Encoding,
+}
+
+fn default_prefix_encoding() -> Encoding {
+    Encoding::Ascii
+}
+
+impl FieldSpec {
+    pub fn new(number: u8, name: &str, length_type: LengthType, length: usize, encoding: Encoding) -> Self {
+        FieldSpec { number, name: name.to_string(), length_type, length, encoding, prefix_encoding: Encoding::Ascii }
+    }
+
+    pub fn with_prefix_encoding(mut self, prefix_encoding: Encoding) -> Self {
+        self.prefix_encoding = prefix_encoding;
+        self
+    }
+}
+
+// Shape of a dictionary file (TOML), see Dictionary::from_file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DictionaryConfig {
+    pub version: Version,
+    pub mti_encoding: Encoding,
+    pub bitmap_encoding: Encoding,
+    pub fields: Vec<FieldSpec>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dictionary {
+    version: Version,
+    mti_encoding: Encoding,
+    bitmap_encoding: Encoding,
+    fields: BTreeMap<u8, FieldSpec>,
+}
+
+impl Dictionary {
+    pub fn from_config(config: DictionaryConfig) -> CoreResult<Self> {
+        if config.mti_encoding == Encoding::Binary {
+            return Err(dictionary_error("mti_encoding must be ascii or bcd".to_string()));
+        }
+        if config.bitmap_encoding == Encoding::Bcd {
+            return Err(dictionary_error("bitmap_encoding must be binary or ascii".to_string()));
+        }
+        let mut dictionary = Dictionary {
+            version: config.version,
+            mti_encoding: config.mti_encoding,
+            bitmap_encoding: config.bitmap_encoding,
+            fields: BTreeMap::new(),
+        };
+        for field in config.fields {
+            dictionary = dictionary.with_field(field)?;
+        }
+        Ok(dictionary)
+    }
+
+    pub fn from_file(path: &Path) -> CoreResult<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| dictionary_error(format!("{}: reading {}", e, path.display())))?;
+        let config: DictionaryConfig = toml::from_str(&content)
+            .map_err(|e| dictionary_error(format!("{}: parsing {}", e, path.display())))?;
+        Self::from_config(config)
+    }
+
+    // Adds or replaces a field definition
+    pub fn with_field(mut self, field: FieldSpec) -> CoreResult<Self> {
+        if !(2..=128).contains(&field.number) {
+            return Err(dictionary_error(format!("Field number {} out of range 2..=128", field.number)));
+        }
+        if field.length == 0 || (field.length_type != LengthType::Fixed && field.prefix_encoding == Encoding::Binary) {
+            return Err(dictionary_error(format!("Invalid length definition for field {}", field.number)));
+        }
+        let max = 10usize.pow(field.length_type.prefix_digits() as u32);
+        if field.length_type != LengthType::Fixed && field.length >= max {
+            return Err(dictionary_error(format!("Field {} max length {} doesn't fit its prefix", field.number, field.length)));
+        }
+        self.fields.insert(field.number, field);
+        Ok(self)
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn mti_encoding(&self) -> Encoding {
+        self.mti_encoding
+    }
+
+    pub fn bitmap_encoding(&self) -> Encoding {
+        self.bitmap_encoding
+    }
+
+    pub fn field(&self, number: u8) -> Option<&FieldSpec> {
+        self.fields.get(&number)
+    }
+
+    // Fields used by 0100/0200/0400 traffic, ASCII MTI and fields with a binary bitmap
+    pub fn iso8583_1987() -> Self {
+        Self::base(Version::V1987, 6, 2)
+    }
+
+    // 1993 carries the full local date and time in field 12 and three digit action codes in 39
+    pub fn iso8583_1993() -> Self {
+        Self::base(Version::V1993, 12, 3)
+    }
+
+    fn base(version: Version, local_time_length: usize, response_code_length: usize) -> Self {
+        use Encoding::{Ascii, Binary};
+        use LengthType::{Fixed, LlVar, LllVar};
+        let fields = vec![
+            FieldSpec::new(2, "primary_account_number", LlVar, 19, Ascii),
+            FieldSpec::new(3, "processing_code", Fixed, 6, Ascii),
+            FieldSpec::new(4, "amount_transaction", Fixed, 12, Ascii),
+            FieldSpec::new(7, "transmission_date_time", Fixed, 10, Ascii),
+            FieldSpec::new(11, "system_trace_audit_number", Fixed, 6, Ascii),
+            FieldSpec::new(12, "local_transaction_time", Fixed, local_time_length, Ascii),
+            FieldSpec::new(13, "local_transaction_date", Fixed, 4, Ascii),
+            FieldSpec::new(14, "expiration_date", Fixed, 4, Ascii),
+            FieldSpec::new(18, "merchant_category_code", Fixed, 4, Ascii),
+            FieldSpec::new(19, "acquiring_institution_country_code", Fixed, 3, Ascii),
+            FieldSpec::new(22, "pos_entry_mode", Fixed, 3, Ascii),
+            FieldSpec::new(25, "pos_condition_code", Fixed, 2, Ascii),
+            FieldSpec::new(32, "acquiring_institution_id", LlVar, 11, Ascii),
+            FieldSpec::new(37, "retrieval_reference_number", Fixed, 12, Ascii),
+            FieldSpec::new(38, "authorization_id_response", Fixed, 6, Ascii),
+            FieldSpec::new(39, "response_code", Fixed, response_code_length, Ascii),
+            FieldSpec::new(41, "card_acceptor_terminal_id", Fixed, 8, Ascii),
+            FieldSpec::new(42, "card_acceptor_id", Fixed, 15, Ascii),
+            FieldSpec::new(43, "card_acceptor_name_location", Fixed, 40, Ascii),
+            FieldSpec::new(49, "currency_code_transaction", Fixed, 3, Ascii),
+            FieldSpec::new(52, "pin_data", Fixed, 8, Binary),
+            FieldSpec::new(55, "icc_data", LllVar, 999, Binary),
+            FieldSpec::new(90, "original_data_elements", Fixed, 42, Ascii),
+        ];
+        Dictionary {
+            version,
+            mti_encoding: Ascii,
+            bitmap_encoding: Binary,
+            fields: fields.into_iter().map(|field| (field.number, field)).collect(),
+        }
+    }
+}
+
+fn dictionary_error(detail: String) -> CoreError {
+    CoreError::system_error(detail, "iso8583::dictionary::Dictionary", SystemErrorCodes::StringParse(4))
+}
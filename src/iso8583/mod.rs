@@ -0,0 +1,4 @@
+pub mod codec;
+pub mod countries;
+pub mod dictionary;
+pub mod requests;
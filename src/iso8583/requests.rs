@@ -0,0 +1,409 @@
+use mysql_common::chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime};
+use mysql_common::rust_decimal::Decimal;
+use serde::Serialize;
+use crate::authorization::blocks::Operation;
+use crate::authorization::decision::AuthorizationRequest;
+use crate::authorization::fraud::MccType;
+use crate::datatypes::response_codes::ResponseCodes;
+use crate::datatypes::system_codes::{MySystemError, SystemErrorCodes};
+use crate::datatypes::system_datatypes::{AccountIdType, CurrenciesIdType};
+use crate::iso8583::codec::IsoMessage;
+use crate::iso8583::countries::alpha3_from_numeric;
+use crate::iso8583::dictionary::{Dictionary, Version};
+use crate::utils::{CoreError, CoreResult};
+
+// Echoed from the request into every response
+const ECHOED_FIELDS: [u8; 12] = [2, 3, 4, 7, 11, 12, 13, 32, 37, 41, 42, 49];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MessageClass {
+    Authorization,
+    Financial,
+    Reversal,
+}
+
+impl MessageClass {
+    // Digits 2 and 3 of the MTI, the same for 1987 (0100) and 1993 (1100) messages
+    pub fn from_mti(mti: &str) -> Option<Self> {
+        match mti.get(1..3)? {
+            "10" => Some(MessageClass::Authorization),
+            "20" => Some(MessageClass::Financial),
+            "40" => Some(MessageClass::Reversal),
+            _ => None,
+        }
+    }
+}
+
+// 0100/0200/0400 request with the fields the authorization engine uses. Amounts stay in minor
+// units until the currency exponent is known. Field 49 is expected to be the ISO 4217 numeric
+// code, which is what currencies.ID stores. Field 19 (ISO 3166 numeric) is kept as alpha-3, the
+// form fraud rules use
+#[derive(Debug, Clone, Serialize)]
+pub struct IsoRequest {
+    pub mti: String,
+    pub class: MessageClass,
+    pub pan: String,
+    pub processing_code: String,
+    pub operation: Operation,
+    pub amount_minor: u64,
+    pub currencies_id: CurrenciesIdType,
+    pub transmission_date_time: String,
+    pub stan: String,
+    pub local_time: Option<String>,
+    pub local_date: Option<String>,
+    pub card_expiry: Option<String>,
+    pub mcc: MccType,
+    pub country: Option<String>,
+    pub online: bool,
+    pub acquirer_id: Option<String>,
+    pub retrieval_reference_number: Option<String>,
+    pub terminal_id: Option<String>,
+    pub merchant_id: Option<String>,
+    pub original_data_elements: Option<String>,
+}
+
+impl IsoRequest {
+    pub fn parse(message: &IsoMessage) -> CoreResult<Self> {
+        let class = MessageClass::from_mti(message.mti())
+            .ok_or_else(|| request_error(format!("Unsupported MTI {}", message.mti())))?;
+        let processing_code = required(message, 3)?.to_string();
+        let operation = match processing_code.get(0..2) {
+            Some("00") => Operation::Purchase,
+            Some("01") => Operation::Cash,
+            _ => return Err(CoreError::system_error(
+                format!("Unsupported processing code {}", processing_code),
+                "iso8583::requests::IsoRequest::parse",
+                SystemErrorCodes::UnknownOperation
+            )),
+        };
+        let original_data_elements = optional(message, 90);
+        if class == MessageClass::Reversal && original_data_elements.is_none() {
+            return Err(request_error("Reversal without original data elements (field 90)".to_string()));
+        }
+
+        Ok(IsoRequest {
+            mti: message.mti().to_string(),
+            class,
+            pan: required(message, 2)?.to_string(),
+            processing_code,
+            operation,
+            amount_minor: parse_number(message, 4)?,
+            currencies_id: parse_number(message, 49)?,
+            transmission_date_time: required(message, 7)?.to_string(),
+            stan: required(message, 11)?.to_string(),
+            local_time: optional(message, 12),
+            local_date: optional(message, 13),
+            card_expiry: optional(message, 14),
+            mcc: match message.get_str(18) {
+                Some(_) => parse_number(message, 18)?,
+                None => 0,
+            },
+            country: match message.get_str(19) {
+                Some(numeric) => Some(alpha3_from_numeric(numeric)
+                    .ok_or_else(|| request_error(format!("Unknown country code {} in field 19", numeric)))?
+                    .to_string()),
+                None => None,
+            },
+            // POS condition 59 is e-commerce, entry mode 81 is PAN entry via e-commerce
+            online: message.get_str(25) == Some("59") || message.get_str(22).map(|m| m.starts_with("81")).unwrap_or(false),
+            acquirer_id: optional(message, 32),
+            retrieval_reference_number: optional(message, 37),
+            terminal_id: optional(message, 41),
+            merchant_id: optional(message, 42),
+            original_data_elements,
+        })
+    }
+
+    pub fn amount(&self, decimals: u32) -> Decimal {
+        Decimal::from_i128_with_scale(self.amount_minor as i128, decimals)
+    }
+
+    // Field 12 is hhmmss (1987, date in field 13 as MMDD) or YYMMDDhhmmss (1993). 1987 messages
+    // carry no year, so it's the one that puts the local date closest to `now` (the switch's
+    // current time): a 1231 message received on January 1st belongs to the previous year
+    pub fn local_datetime(&self, now: NaiveDateTime) -> CoreResult<NaiveDateTime> {
+        let time = self.local_time.as_deref()
+            .ok_or_else(|| request_error("Missing local transaction time (field 12)".to_string()))?;
+        if time.len() == 12 {
+            return NaiveDateTime::parse_from_str(time, "%y%m%d%H%M%S")
+                .map_err(|e| request_error(format!("Invalid local date time {}: {}", time, e)));
+        }
+        let date = self.local_date.as_deref()
+            .ok_or_else(|| request_error("Missing local transaction date (field 13)".to_string()))?;
+        [now.year() - 1, now.year(), now.year() + 1].into_iter()
+            .filter_map(|year| NaiveDateTime::parse_from_str(&format!("{}{}{}", year, date, time), "%Y%m%d%H%M%S").ok())
+            .min_by_key(|candidate| (*candidate - now).num_seconds().abs())
+            .ok_or_else(|| request_error(format!("Invalid local date time {} {}", date, time)))
+    }
+
+    // Field 14 is YYMM, returned as the first day of that month
+    pub fn card_expiry_date(&self) -> CoreResult<Option<NaiveDate>> {
+        match self.card_expiry.as_deref() {
+            Some(expiry) => NaiveDate::parse_from_str(&format!("{}01", expiry), "%y%m%d")
+                .map(Some)
+                .map_err(|e| request_error(format!("Invalid expiration date {}: {}", expiry, e))),
+            None => Ok(None),
+        }
+    }
+
+    // The PAN to account lookup is left to the caller
+    pub fn to_authorization_request(&self, accounts_id: AccountIdType, decimals: u32, now: NaiveDateTime) -> CoreResult<AuthorizationRequest> {
+        Ok(AuthorizationRequest {
+            accounts_id,
+            amount: self.amount(decimals),
+            currencies_id: self.currencies_id,
+            operation: self.operation,
+            online: self.online,
+            mcc: self.mcc,
+            country: self.country.clone().unwrap_or_default(),
+            local_datetime: self.local_datetime(now)?,
+            card_expiry: self.card_expiry_date()?,
+            partial_approval_allowed: false,
+        })
+    }
+}
+
+// Response MTI (0100 -> 0110), the request's echoed fields and field 39: the zero padded
+// numeric value of `response_code` for 1987, its action code for 1993 (see action_code_1993).
+// `approved_amount` replaces field 4 for partial approvals
+pub fn build_response(dictionary: &Dictionary, request: &IsoMessage, response_code: ResponseCodes, approved_amount_minor: Option<u64>) -> CoreResult<IsoMessage> {
+    let mut mti: Vec<char> = request.mti().chars().collect();
+    match mti.get_mut(2) {
+        Some(function) if *function == '0' => *function = '1',
+        _ => return Err(request_error(format!("MTI {} is not a request", request.mti()))),
+    }
+
+    let mut response = IsoMessage::new(&mti.into_iter().collect::<String>());
+    for number in ECHOED_FIELDS {
+        if let Some(value) = request.get(number) {
+            response.set(number, value.to_vec());
+        }
+    }
+    if let Some(amount) = approved_amount_minor {
+        let width = dictionary.field(4).map(|spec| spec.length).unwrap_or(12);
+        response.set(4, format!("{:0width$}", amount, width = width));
+    }
+
+    let width = dictionary.field(39)
+        .map(|spec| spec.length)
+        .ok_or_else(|| request_error("Field 39 is not in the dictionary".to_string()))?;
+    let code = match dictionary.version() {
+        Version::V1987 => response_code as u16,
+        Version::V1993 => action_code_1993(response_code),
+    };
+    response.set(39, format!("{:0width$}", code, width = width));
+    Ok(response)
+}
+
+// 1993 field 39 action codes: 0xx approved, 1xx declined, 2xx declined with pick up, 9xx
+// system/format errors. Codes without a direct equivalent use the generic one of their class
+pub fn action_code_1993(response_code: ResponseCodes) -> u16 {
+    match response_code {
+        ResponseCodes::Approved => 0,
+        ResponseCodes::HonorWithId => 1,
+        ResponseCodes::PartialApproval => 2,
+        ResponseCodes::NotDeclined => 0,
+        ResponseCodes::ApprovedPurchaseAmountOnlyNoCashBackAllowed => 0,
+        ResponseCodes::DoNotHonor => 100,
+        ResponseCodes::DoNotHonorSwitch => 100,
+        ResponseCodes::ExpiredCard => 101,
+        ResponseCodes::RestrictedCard => 104,
+        ResponseCodes::AllowableNumberOfPinTriesExceeded => 106,
+        ResponseCodes::ReferToCardIssuer => 107,
+        ResponseCodes::InvalidMerchant => 109,
+        ResponseCodes::InvalidAmount => 110,
+        ResponseCodes::InvalidCardNumber => 111,
+        ResponseCodes::InvalidNonexistentAccountSpecified => 114,
+        ResponseCodes::InsufficientFunds => 116,
+        ResponseCodes::InvalidPin => 117,
+        ResponseCodes::TransactionNotPermittedToCardholder => 119,
+        ResponseCodes::TransactionNotPermittedToTerminal => 120,
+        ResponseCodes::ExceedsWithdrawalAmountLimit => 121,
+        ResponseCodes::ExceedsWithdrawalCountLimit => 123,
+        ResponseCodes::DeactivatedCard => 125,
+        ResponseCodes::InvalidCryptogram => 100,
+        ResponseCodes::CancelledCard => 100,
+        ResponseCodes::CaptureCard => 200,
+        ResponseCodes::LostCard => 208,
+        ResponseCodes::StolenCard => 209,
+        ResponseCodes::InvalidTransaction => 902,
+        ResponseCodes::FormatError => 904,
+        ResponseCodes::InvalidIssuer => 908,
+        ResponseCodes::SystemError => 909,
+        ResponseCodes::PinValidationNotPossible => 909,
+        ResponseCodes::CryptographicFailure => 909,
+        ResponseCodes::AuthorizationPlatformOrIssuerSystemInoperative => 911,
+        ResponseCodes::AuthorizationSystemOrIssuerSystemInoperative => 912,
+        ResponseCodes::DuplicateTransmissionDetected => 913,
+    }
+}
+
+fn required(message: &IsoMessage, number: u8) -> CoreResult<&str> {
+    message.get_str(number)
+        .ok_or_else(|| request_error(format!("Missing field {}", number)))
+}
+
+fn optional(message: &IsoMessage, number: u8) -> Option<String> {
+    message.get_str(number).map(str::to_string)
+}
+
+fn parse_number<T: std::str::FromStr>(message: &IsoMessage, number: u8) -> CoreResult<T> {
+    let value = required(message, number)?;
+    value.parse::<T>()
+        .map_err(|_| request_error(format!("Field {} is not numeric: {}", number, value)))
+}
+
+fn request_error(detail: String) -> CoreError {
+    CoreError::system_error(detail, "iso8583::requests", SystemErrorCodes::BadFormat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> IsoMessage {
+        let mut message = IsoMessage::new("1100");
+        message.set(2, "4111111111111111");
+        message.set(3, "000000");
+        message.set(4, "000000001000");
+        message.set(7, "0315120000");
+        message.set(11, "000001");
+        message.set(19, "724");
+        message.set(49, "978");
+        message
+    }
+
+    fn at(datetime: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn mti_gives_the_message_class() {
+        for (mti, class) in [("0100", MessageClass::Authorization), ("0200", MessageClass::Financial), ("1100", MessageClass::Authorization)] {
+            let mut message = request();
+            message.set_mti(mti);
+            assert_eq!(IsoRequest::parse(&message).unwrap().class, class, "{}", mti);
+        }
+
+        let mut reversal = request();
+        reversal.set_mti("0400");
+        reversal.set(90, "010000000100315120000000001234500000000000");
+        assert_eq!(IsoRequest::parse(&reversal).unwrap().class, MessageClass::Reversal);
+
+        let mut advice = request();
+        advice.set_mti("0120");
+        assert_eq!(IsoRequest::parse(&advice).unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+
+    #[test]
+    fn reversals_need_original_data_elements() {
+        let mut reversal = request();
+        reversal.set_mti("0400");
+        assert_eq!(IsoRequest::parse(&reversal).unwrap_err().system_error, SystemErrorCodes::BadFormat);
+
+        // Field 90 only matters for reversals
+        assert_eq!(IsoRequest::parse(&request()).unwrap().original_data_elements, None);
+    }
+
+    #[test]
+    fn processing_code_gives_the_operation() {
+        let mut message = request();
+        assert_eq!(IsoRequest::parse(&message).unwrap().operation, Operation::Purchase);
+
+        message.set(3, "011000");
+        assert_eq!(IsoRequest::parse(&message).unwrap().operation, Operation::Cash);
+
+        message.set(3, "200000");
+        assert_eq!(IsoRequest::parse(&message).unwrap_err().system_error, SystemErrorCodes::UnknownOperation);
+    }
+
+    #[test]
+    fn amount_uses_the_currency_decimals() {
+        let parsed = IsoRequest::parse(&request()).unwrap();
+        assert_eq!(parsed.amount(2), Decimal::new(1000, 2));
+        assert_eq!(parsed.amount(0), Decimal::new(1000, 0));
+        assert_eq!(parsed.amount(3), Decimal::new(1000, 3));
+    }
+
+    #[test]
+    fn card_expiry_is_the_first_day_of_its_month() {
+        let mut message = request();
+        assert_eq!(IsoRequest::parse(&message).unwrap().card_expiry_date().unwrap(), None);
+
+        message.set(14, "2512");
+        assert_eq!(IsoRequest::parse(&message).unwrap().card_expiry_date().unwrap(), NaiveDate::from_ymd_opt(2025, 12, 1));
+
+        message.set(14, "2513");
+        assert_eq!(IsoRequest::parse(&message).unwrap().card_expiry_date().unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+
+    #[test]
+    fn online_comes_from_pos_condition_or_entry_mode() {
+        // (field 22, field 25, online)
+        let cases = [(None, None, false), (None, Some("59"), true), (None, Some("00"), false), (Some("812"), None, true), (Some("051"), Some("00"), false)];
+        for (entry_mode, condition, online) in cases {
+            let mut message = request();
+            if let Some(entry_mode) = entry_mode { message.set(22, entry_mode) }
+            if let Some(condition) = condition { message.set(25, condition) }
+            assert_eq!(IsoRequest::parse(&message).unwrap().online, online, "{:?} {:?}", entry_mode, condition);
+        }
+    }
+
+    #[test]
+    fn local_date_1987_takes_the_year_closest_to_now() {
+        let mut message = request();
+        message.set_mti("0100");
+        message.set(12, "235900");
+        // (field 13, now, expected)
+        let cases = [
+            ("1231", "2025-01-01 00:10", "2024-12-31 23:59"),
+            ("0101", "2024-12-31 23:50", "2025-01-01 23:59"),
+            ("0315", "2024-03-15 12:00", "2024-03-15 23:59"),
+            ("0229", "2025-03-01 00:00", "2024-02-29 23:59"),
+        ];
+        for (date, now, expected) in cases {
+            message.set(13, date);
+            assert_eq!(IsoRequest::parse(&message).unwrap().local_datetime(at(now)).unwrap(), at(expected), "{} at {}", date, now);
+        }
+
+        message.set(13, "1301");
+        assert_eq!(IsoRequest::parse(&message).unwrap().local_datetime(at("2024-03-15 12:00")).unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+
+    #[test]
+    fn local_date_1993_carries_its_own_year() {
+        let mut message = request();
+        message.set(12, "231231235900");
+        assert_eq!(IsoRequest::parse(&message).unwrap().local_datetime(at("2025-06-01 00:00")).unwrap(), at("2023-12-31 23:59"));
+    }
+
+    #[test]
+    fn field_19_is_converted_to_alpha3() {
+        assert_eq!(IsoRequest::parse(&request()).unwrap().country.as_deref(), Some("ESP"));
+
+        let mut unknown = request();
+        unknown.set(19, "999");
+        assert_eq!(IsoRequest::parse(&unknown).unwrap_err().system_error, SystemErrorCodes::BadFormat);
+    }
+
+    #[test]
+    fn field_39_uses_action_codes_for_1993() {
+        let cases = [
+            (ResponseCodes::Approved, "000"),
+            (ResponseCodes::DoNotHonor, "100"),
+            (ResponseCodes::InsufficientFunds, "116"),
+            (ResponseCodes::StolenCard, "209"),
+            (ResponseCodes::SystemError, "909"),
+        ];
+        for (code, expected) in cases {
+            let response = build_response(&Dictionary::iso8583_1993(), &request(), code, None).unwrap();
+            assert_eq!(response.mti(), "1110");
+            assert_eq!(response.get_str(39), Some(expected));
+        }
+
+        let mut request_1987 = request();
+        request_1987.set_mti("0100");
+        let response = build_response(&Dictionary::iso8583_1987(), &request_1987, ResponseCodes::InsufficientFunds, None).unwrap();
+        assert_eq!(response.get_str(39), Some("51"));
+    }
+}
@@ -1,21 +1,80 @@
 #![allow(unused_imports)]
 #![allow(dead_code)]
-use crate::data::db_conn::init_db_conn;
+use std::process::exit;
+use std::time::Duration;
+use crate::config::Config;
+use crate::data::disconnect_pool;
+use crate::data::db_conn::{init_db_conn, spawn_health_check};
+use crate::data::repository::MySqlRepository;
 use crate::data::queries::{
     select_json_from_db,
     get_account_charges_data
 };
+use crate::datatypes::response_mapping::{set_response_code_mapping, ResponseCodeMapping};
+use crate::utils::shutdown;
 
+mod authorization;
+mod batch;
+mod billing;
+mod config;
 mod data;
 mod utils;
 mod datatypes;
+mod exchange;
+mod iso8583;
 
 #[actix_rt::main]
 async fn main() {
 
-    let mut conn = init_db_conn().await.unwrap();
+    let config = Config::load().unwrap_or_else(|e| {
+        println!("Failed to load config: {}", e);
+        exit(e.system_error.exit_code())
+    });
 
-    //select_json_from_db(&mut conn).await;
-    get_account_charges_data(&mut conn).await;
+    let mapping = ResponseCodeMapping::from_config(&config.response_codes).unwrap_or_else(|e| {
+        println!("Failed to load response code mapping: {}", e);
+        exit(e.system_error.exit_code())
+    });
+    set_response_code_mapping(mapping);
+
+    // Only checks the pool can serve connections, the work below takes its own per query
+    init_db_conn(&config.db).await.unwrap_or_else(|e| {
+        println!("Failed to initiate db conn: {}", e);
+        exit(e.system_error.exit_code())
+    });
+    let health_check = spawn_health_check(config.db.clone());
+
+    let page_size = config.batch.page_size;
+    let mut work = tokio::spawn(async move {
+        let _guard = match shutdown::begin_work("get_account_charges_data") {
+            Some(guard) => guard,
+            None => return,
+        };
+        //select_json_from_db(&mut conn).await;
+        get_account_charges_data(&MySqlRepository, page_size).await;
+    });
+
+    let finished = tokio::select! {
+        _ = &mut work => true,
+        signal = shutdown::wait_for_signal() => {
+            println!("{} received, draining in-flight work", signal);
+            false
+        }
+    };
+
+    let drain_timeout = Duration::from_secs(config.shutdown.drain_timeout_secs);
+    let interrupted = shutdown::drain(drain_timeout).await;
+    if !finished {
+        if !interrupted.is_empty() {
+            println!("Interrupted after {:?}: {}", drain_timeout, interrupted.join(", "));
+            work.abort();
+        }
+        let _ = work.await;
+    }
+
+    health_check.abort();
+    if let Err(e) = disconnect_pool().await {
+        println!("Failed to disconnect db pool: {}", e);
+    }
 
 }
@@ -1,6 +1,8 @@
+pub mod shutdown;
+
 use crate::datatypes::system_codes::SystemErrorCodes;
 use logger::MyError;
 
 pub type CoreError = MyError<SystemErrorCodes>;
 pub type CoreResult<T> = Result<T, CoreError>;
-pub type MyResult<T> = Result<T, CoreError>;
\ No newline at end of file
+pub type MyResult<T> = Result<T, CoreError>;
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use lazy_static::lazy_static;
+use tokio::sync::{watch, Notify};
+
+lazy_static! {
+    static ref SHUTDOWN: Shutdown = Shutdown::new();
+}
+
+// Tracks in-flight units of work (a query, a batch step, ...) so shutdown can wait for them
+struct Shutdown {
+    accepting: AtomicBool,
+    next_id: AtomicU64,
+    in_flight: Mutex<BTreeMap<u64, String>>,
+    idle: Notify,
+    requested: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Shutdown {
+            accepting: AtomicBool::new(true),
+            next_id: AtomicU64::new(0),
+            in_flight: Mutex::new(BTreeMap::new()),
+            idle: Notify::new(),
+            requested: watch::channel(false).0,
+        }
+    }
+}
+
+// Held for the duration of a unit of work; dropping it marks the work as finished
+#[must_use]
+pub struct WorkGuard {
+    id: u64,
+}
+
+impl Drop for WorkGuard {
+    fn drop(&mut self) {
+        let mut in_flight = SHUTDOWN.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(&self.id);
+        if in_flight.is_empty() {
+            SHUTDOWN.idle.notify_waiters();
+        }
+    }
+}
+
+// None once shutdown has started, callers must not start new work then
+pub fn begin_work<T: Into<String>>(label: T) -> Option<WorkGuard> {
+    if !SHUTDOWN.accepting.load(Ordering::SeqCst) {
+        return None;
+    }
+    let id = SHUTDOWN.next_id.fetch_add(1, Ordering::SeqCst);
+    SHUTDOWN.in_flight.lock().unwrap_or_else(|e| e.into_inner()).insert(id, label.into());
+    Some(WorkGuard { id })
+}
+
+pub fn is_shutting_down() -> bool {
+    !SHUTDOWN.accepting.load(Ordering::SeqCst)
+}
+
+// Long running loops can select on this to stop between steps
+pub fn subscribe() -> watch::Receiver<bool> {
+    SHUTDOWN.requested.subscribe()
+}
+
+#[cfg(unix)]
+pub async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+        },
+        Err(e) => {
+            println!("Failed to install SIGTERM handler, only SIGINT will be handled: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            "SIGINT"
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+// Stops accepting new work and waits up to `timeout` for in-flight work to finish.
+// Returns the labels of the work that was still running when the timeout expired
+pub async fn drain(timeout: Duration) -> Vec<String> {
+    SHUTDOWN.accepting.store(false, Ordering::SeqCst);
+    SHUTDOWN.requested.send_replace(true);
+
+    let wait_idle = async {
+        loop {
+            let idle = SHUTDOWN.idle.notified();
+            if SHUTDOWN.in_flight.lock().unwrap_or_else(|e| e.into_inner()).is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    };
+
+    if tokio::time::timeout(timeout, wait_idle).await.is_ok() {
+        return Vec::new();
+    }
+
+    SHUTDOWN.in_flight.lock().unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect()
+}